@@ -1,7 +1,16 @@
 // native/spfresh_c_api.cc
 #include <cstdint>
 #include <cstddef>
+#include <cstdio>
+#include <cstring>
+#include <cmath>
 #include <new>        // ✅ ต้องมี เพื่อใช้ std::nothrow
+#include <string>
+#include <vector>
+#include <algorithm>
+#include <unordered_set>
+#include <utility>
+#include <unistd.h>   // fsync, truncate
 
 // ---------- Types shared with Rust ----------
 struct SPFreshStatus {
@@ -12,15 +21,169 @@ struct SPFreshStatus {
 using SPFreshIndex = void*;
 
 // ---------- Internal C++ types / helpers (นอก extern "C") ----------
+
+// ไฟล์เก็บเวกเตอร์ใน index_dir (append-only แบบเดียวกับ VectorLog ฝั่ง Rust):
+//   "SPFV" | u32 version | u32 dim | records [int64 id][dim * float] ...
+// id ที่ถูกลบต่อท้ายเป็น int64 ใน "spfresh_vectors.bin.del".
+static const char kFileMagic[4] = {'S', 'P', 'F', 'V'};
+static const uint32_t kFileVersion = 1;
+static const uint64_t kHeaderLen = 12; // magic + version + dim
+static const char* kVectorsFile = "spfresh_vectors.bin";
+
 struct SPFreshHandle {
-    // ใส่ของจริงภายหลังได้ เช่น pointer ไปยัง index engine
     int32_t dim = 0;
-    // TODO: เก็บ path/index params ถ้าต้องการ
+    std::string index_dir;
+    std::string params;
+    std::vector<int64_t> ids;
+    std::vector<float> vectors; // ids.size() * dim
+    // ยังไม่ได้เขียนลงไฟล์: spfresh_save ต่อท้ายเฉพาะส่วนนี้ (ไม่เขียนทั้ง index ใหม่)
+    std::vector<int64_t> pending_ids;
+    std::vector<float> pending_vectors;
+    std::vector<int64_t> pending_deletes;
+    // ความยาวที่อ่านได้ครบของแต่ละไฟล์ — เศษ record ท้ายไฟล์ (crash กลาง append)
+    // ถูกตัดตอน save ครั้งถัดไป ไม่ใช่ตอนเปิด
+    uint64_t vectors_len = 0;
+    uint64_t deletes_len = 0;
 };
 
 // มาโครปิด warning "unused"
 #define UNUSED(x) (void)(x)
 
+static std::string vectors_path(const SPFreshHandle* h) {
+    return h->index_dir + "/" + kVectorsFile;
+}
+
+static std::string deletes_path(const SPFreshHandle* h) {
+    return vectors_path(h) + ".del";
+}
+
+// ตัด ids / vectors ที่อยู่ใน gone ออก (คงลำดับเดิม); คืนจำนวนที่ถูกตัด
+static size_t remove_ids(SPFreshHandle* h, const std::unordered_set<int64_t>& gone) {
+    size_t keep = 0;
+    for (size_t i = 0; i < h->ids.size(); ++i) {
+        if (gone.count(h->ids[i])) continue;
+        if (keep != i) {
+            h->ids[keep] = h->ids[i];
+            std::copy(h->vectors.begin() + i * h->dim,
+                      h->vectors.begin() + (i + 1) * h->dim,
+                      h->vectors.begin() + keep * h->dim);
+        }
+        ++keep;
+    }
+    const size_t removed = h->ids.size() - keep;
+    h->ids.resize(keep);
+    h->vectors.resize(keep * h->dim);
+    return removed;
+}
+
+// อ่าน tombstone (ข้าม id ท้ายไฟล์ที่เขียนไม่ครบ) แล้วตัดออกจากหน่วยความจำ
+static void load_deletes(SPFreshHandle* h) {
+    FILE* f = std::fopen(deletes_path(h).c_str(), "rb");
+    if (!f) {
+        return;
+    }
+    std::unordered_set<int64_t> gone;
+    int64_t id = 0;
+    while (std::fread(&id, sizeof(id), 1, f) == 1) {
+        gone.insert(id);
+        h->deletes_len += sizeof(id);
+    }
+    std::fclose(f);
+    remove_ids(h, gone);
+}
+
+// โหลดไฟล์เวกเตอร์ถ้ามี; ไม่มีไฟล์ หรือ header ไม่ครบ (crash ระหว่าง save ครั้งแรก) = index ว่าง
+// — save ครั้งถัดไปเขียน header ใหม่. record ท้ายที่ไม่ครบถูกข้าม.
+// ไม่เขียนไฟล์ใด ๆ (เปิดแบบ read-only ได้)
+static SPFreshStatus load_vectors(SPFreshHandle* h) {
+    const std::string path = vectors_path(h);
+    FILE* f = std::fopen(path.c_str(), "rb");
+    if (!f) {
+        return {0, nullptr};
+    }
+
+    char magic[4];
+    uint32_t version = 0, dim = 0;
+    bool ok = std::fread(magic, 1, 4, f) == 4 &&
+              std::fread(&version, sizeof(version), 1, f) == 1 &&
+              std::fread(&dim, sizeof(dim), 1, f) == 1;
+    if (!ok) {
+        std::fclose(f);
+        load_deletes(h);
+        return {0, nullptr};
+    }
+    if (std::memcmp(magic, kFileMagic, 4) != 0 || version != kFileVersion) {
+        std::fclose(f);
+        static const char* kMsg = "spfresh_vectors.bin: bad header";
+        return {4, kMsg};
+    }
+    if (static_cast<int32_t>(dim) != h->dim) {
+        std::fclose(f);
+        static const char* kMsg = "spfresh_vectors.bin: stored dim != requested dim";
+        return {5, kMsg};
+    }
+
+    h->vectors_len = kHeaderLen;
+    int64_t id = 0;
+    std::vector<float> v(dim);
+    while (std::fread(&id, sizeof(id), 1, f) == 1 &&
+           std::fread(v.data(), sizeof(float), dim, f) == dim) {
+        h->ids.push_back(id);
+        h->vectors.insert(h->vectors.end(), v.begin(), v.end());
+        h->vectors_len += sizeof(id) + sizeof(float) * static_cast<uint64_t>(dim);
+    }
+    std::fclose(f);
+    load_deletes(h);
+    return {0, nullptr};
+}
+
+// เขียน bytes ต่อท้าย path หลังตัดไฟล์ให้เหลือ good_len (ทิ้งเศษ record) แล้ว fsync
+static bool append_file(const std::string& path, uint64_t good_len,
+                        const void* header, size_t header_len,
+                        const void* data, size_t data_len) {
+    if (good_len > 0 && truncate(path.c_str(), static_cast<off_t>(good_len)) != 0) {
+        return false;
+    }
+    FILE* f = std::fopen(path.c_str(), good_len > 0 ? "ab" : "wb");
+    if (!f) {
+        return false;
+    }
+    bool ok = (header_len == 0 || std::fwrite(header, 1, header_len, f) == header_len) &&
+              (data_len == 0 || std::fwrite(data, 1, data_len, f) == data_len);
+    ok = ok && std::fflush(f) == 0 && fsync(fileno(f)) == 0;
+    return (std::fclose(f) == 0) && ok;
+}
+
+// header ของไฟล์
+static std::vector<char> file_header(const SPFreshHandle* h) {
+    std::vector<char> out(kHeaderLen);
+    const uint32_t dim = static_cast<uint32_t>(h->dim);
+    std::memcpy(out.data(), kFileMagic, 4);
+    std::memcpy(out.data() + 4, &kFileVersion, sizeof(kFileVersion));
+    std::memcpy(out.data() + 8, &dim, sizeof(dim));
+    return out;
+}
+
+// records [id][vector] ติดกัน
+static std::vector<char> encode_records(const std::vector<int64_t>& ids,
+                                        const std::vector<float>& vectors,
+                                        int32_t dim) {
+    const size_t rec = sizeof(int64_t) + sizeof(float) * dim;
+    std::vector<char> out(ids.size() * rec);
+    for (size_t i = 0; i < ids.size(); ++i) {
+        std::memcpy(out.data() + i * rec, &ids[i], sizeof(int64_t));
+        std::memcpy(out.data() + i * rec + sizeof(int64_t),
+                    vectors.data() + i * dim, sizeof(float) * dim);
+    }
+    return out;
+}
+
+static float norm_of(const float* v, int32_t dim) {
+    float s = 0.0f;
+    for (int32_t i = 0; i < dim; ++i) s += v[i] * v[i];
+    return std::sqrt(s);
+}
+
 extern "C" {
 
 // เปิด/สร้าง index
@@ -28,14 +191,14 @@ SPFreshStatus spfresh_open(const char* index_dir,
                            int32_t dim,
                            const char* params,
                            void** out_handle) {
-    // TODO: ต่อกับ SPFresh จริง ๆ ตรงนี้
-    UNUSED(index_dir);
-    UNUSED(params);
-
     if (!out_handle) {
         static const char* kMsg = "out_handle is null";
         return {1, kMsg};
     }
+    if (!index_dir) {
+        static const char* kMsg = "index_dir is null";
+        return {1, kMsg};
+    }
 
     // ✅ ใช้ std::nothrow ได้แล้วเพราะ include <new> แล้ว
     SPFreshHandle* h = new (std::nothrow) SPFreshHandle();
@@ -44,6 +207,14 @@ SPFreshStatus spfresh_open(const char* index_dir,
         return {2, kMsg};
     }
     h->dim = dim;
+    h->index_dir = index_dir;
+    h->params = params ? params : "";
+
+    SPFreshStatus st = load_vectors(h);
+    if (st.code != 0) {
+        delete h;
+        return st;
+    }
     *out_handle = reinterpret_cast<void*>(h);
     return {0, nullptr};
 }
@@ -60,37 +231,142 @@ SPFreshStatus spfresh_add(SPFreshIndex handle,
                           const float* vectors,
                           size_t n,
                           const int64_t* ids) {
-    // TODO: ใส่ลง index จริง
-    UNUSED(handle);
-    UNUSED(vectors);
-    UNUSED(n);
-    UNUSED(ids);
+    if (!handle) {
+        static const char* kMsg = "handle is null";
+        return {1, kMsg};
+    }
+    if (n == 0) {
+        return {0, nullptr};
+    }
+    if (!vectors) {
+        static const char* kMsg = "vectors is null";
+        return {1, kMsg};
+    }
+    auto* h = reinterpret_cast<SPFreshHandle*>(handle);
+
+    // ids == nullptr => รันนิ่งต่อจาก id มากสุด
+    int64_t next_id = 0;
+    if (!ids) {
+        for (int64_t id : h->ids) next_id = std::max(next_id, id + 1);
+    }
+    for (size_t i = 0; i < n; ++i) {
+        h->ids.push_back(ids ? ids[i] : next_id++);
+    }
+    h->vectors.insert(h->vectors.end(), vectors, vectors + n * h->dim);
+    h->pending_ids.insert(h->pending_ids.end(), h->ids.end() - n, h->ids.end());
+    h->pending_vectors.insert(h->pending_vectors.end(), vectors, vectors + n * h->dim);
     return {0, nullptr};
 }
 
-// ค้นหา
+// ค้นหา (cosine, brute force)
 SPFreshStatus spfresh_search(SPFreshIndex handle,
                              const float* query,
                              int32_t topk,
                              int64_t* out_ids,
                              float* out_scores) {
-    // TODO: ค้นหาจริง แล้วเติมผลลัพธ์
-    UNUSED(handle);
-    UNUSED(query);
-
-    if (topk > 0 && out_ids && out_scores) {
-        for (int32_t i = 0; i < topk; ++i) {
-            out_ids[i] = -1;      // ไม่มีผลจริง
-            out_scores[i] = 0.0f; // คะแนนว่าง
-        }
+    if (!handle || !query || !out_ids) {
+        static const char* kMsg = "null argument";
+        return {1, kMsg};
+    }
+    if (topk <= 0) {
+        return {0, nullptr};
+    }
+    auto* h = reinterpret_cast<SPFreshHandle*>(handle);
+
+    const float qn = norm_of(query, h->dim);
+    std::vector<std::pair<float, int64_t>> scored;
+    scored.reserve(h->ids.size());
+    for (size_t i = 0; i < h->ids.size(); ++i) {
+        const float* v = h->vectors.data() + i * h->dim;
+        float dot = 0.0f;
+        for (int32_t d = 0; d < h->dim; ++d) dot += query[d] * v[d];
+        const float denom = qn * norm_of(v, h->dim);
+        scored.emplace_back(denom > 0.0f ? dot / denom : 0.0f, h->ids[i]);
+    }
+
+    const size_t k = std::min(static_cast<size_t>(topk), scored.size());
+    std::partial_sort(scored.begin(), scored.begin() + k, scored.end(),
+                      [](const auto& a, const auto& b) { return a.first > b.first; });
+
+    for (int32_t i = 0; i < topk; ++i) {
+        const bool has = static_cast<size_t>(i) < k;
+        out_ids[i] = has ? scored[i].second : -1;      // -1 = ไม่มีผล
+        if (out_scores) out_scores[i] = has ? scored[i].first : 0.0f;
     }
     return {0, nullptr};
 }
 
-// บันทึก index
+// ลบเวกเตอร์ตาม id (มีผลกับไฟล์เมื่อ spfresh_save)
+SPFreshStatus spfresh_delete(SPFreshIndex handle,
+                             const int64_t* ids,
+                             size_t n) {
+    if (!handle) {
+        static const char* kMsg = "handle is null";
+        return {1, kMsg};
+    }
+    if (n == 0) {
+        return {0, nullptr};
+    }
+    if (!ids) {
+        static const char* kMsg = "ids is null";
+        return {1, kMsg};
+    }
+    auto* h = reinterpret_cast<SPFreshHandle*>(handle);
+    const std::unordered_set<int64_t> gone(ids, ids + n);
+    if (remove_ids(h, gone) > 0) {
+        h->pending_deletes.insert(h->pending_deletes.end(), ids, ids + n);
+    }
+    return {0, nullptr};
+}
+
+// รายการ id ทั้งหมด (สองจังหวะ: ถามขนาดก่อน แล้วค่อยคัดลอก)
+SPFreshStatus spfresh_ids(SPFreshIndex handle,
+                          int64_t* out_ids,
+                          size_t cap,
+                          size_t* out_n) {
+    if (!handle || !out_n) {
+        static const char* kMsg = "null argument";
+        return {1, kMsg};
+    }
+    auto* h = reinterpret_cast<SPFreshHandle*>(handle);
+    *out_n = h->ids.size();
+    if (out_ids && cap >= h->ids.size()) {
+        std::copy(h->ids.begin(), h->ids.end(), out_ids);
+    }
+    return {0, nullptr};
+}
+
+// บันทึก index: ต่อท้ายเวกเตอร์ที่เพิ่ม / id ที่ลบตั้งแต่ save ครั้งก่อน แล้ว fsync
+// (I/O ตามจำนวนที่เปลี่ยน ไม่ใช่ขนาดทั้ง index)
 SPFreshStatus spfresh_save(SPFreshIndex handle) {
-    UNUSED(handle);
-    // TODO: persist index
+    if (!handle) {
+        static const char* kMsg = "handle is null";
+        return {1, kMsg};
+    }
+    auto* h = reinterpret_cast<SPFreshHandle*>(handle);
+    if (!h->pending_ids.empty()) {
+        const std::vector<char> header = h->vectors_len == 0 ? file_header(h) : std::vector<char>();
+        const std::vector<char> body = encode_records(h->pending_ids, h->pending_vectors, h->dim);
+        if (!append_file(vectors_path(h), h->vectors_len, header.data(), header.size(),
+                         body.data(), body.size())) {
+            static const char* kMsg = "append spfresh_vectors.bin failed";
+            return {8, kMsg};
+        }
+        h->vectors_len += header.size() + body.size();
+        h->pending_ids.clear();
+        h->pending_vectors.clear();
+    }
+
+    if (!h->pending_deletes.empty()) {
+        const size_t len = h->pending_deletes.size() * sizeof(int64_t);
+        if (!append_file(deletes_path(h), h->deletes_len, nullptr, 0,
+                         h->pending_deletes.data(), len)) {
+            static const char* kMsg = "append spfresh_vectors.bin.del failed";
+            return {10, kMsg};
+        }
+        h->deletes_len += len;
+        h->pending_deletes.clear();
+    }
     return {0, nullptr};
 }
 
@@ -14,7 +14,7 @@ typedef struct {
     const char* message; // optional (nullptr ถ้าไม่มี)
 } SPFreshStatus;
 
-// เปิด/สร้าง index ที่เก็บเป็นไฟล์ใน index_dir
+// เปิด/สร้าง index ที่เก็บเป็นไฟล์ใน index_dir (โหลด spfresh_vectors.bin ถ้ามี)
 SPFreshStatus spfresh_open(const char* index_dir,
                            int32_t dim,
                            const char* params,   // path หรือ key=value ใส่พารามิเตอร์
@@ -29,14 +29,26 @@ SPFreshStatus spfresh_add(SPFreshIndex handle,
                           size_t n,
                           const int64_t* ids);  // ส่ง nullptr ถ้าให้ระบบรันนิ่ง id เอง
 
-// ค้นหา topk สำหรับ query 1 เวกเตอร์
+// ค้นหา topk สำหรับ query 1 เวกเตอร์ (ช่องที่ไม่มีผลจะได้ id = -1)
 SPFreshStatus spfresh_search(SPFreshIndex handle,
                              const float* query, // len=dim
                              int32_t topk,
                              int64_t* out_ids,   // len=topk (จำเป็น)
                              float* out_scores); // len=topk (optional; ส่ง nullptr ได้)
 
-// persist/snapshot ลงดิสก์ (ถ้า API ภายในต้องเรียก)
+// ลบ (tombstone) เวกเตอร์ตาม id; id ที่ไม่มีอยู่จะถูกข้าม
+SPFreshStatus spfresh_delete(SPFreshIndex handle,
+                             const int64_t* ids,
+                             size_t n);
+
+// id ทั้งหมดที่ยังอยู่ใน index: เขียนจำนวนลง *out_n เสมอ และคัดลอก id ลง out_ids
+// เมื่อ cap >= จำนวน (ส่ง out_ids = nullptr, cap = 0 เพื่อถามขนาดก่อน)
+SPFreshStatus spfresh_ids(SPFreshIndex handle,
+                          int64_t* out_ids,
+                          size_t cap,
+                          size_t* out_n);
+
+// persist ลงดิสก์: ต่อท้าย index_dir/spfresh_vectors.bin (+ .del) ด้วยส่วนที่เปลี่ยนตั้งแต่ save ครั้งก่อน แล้ว fsync
 SPFreshStatus spfresh_save(SPFreshIndex handle);
 
 #ifdef __cplusplus
@@ -0,0 +1,176 @@
+use anyhow::{bail, Result};
+use std::env;
+use std::sync::OnceLock;
+
+use crate::types::ChunkAggregate;
+
+/// แบ่งรีวิวยาวเป็นช่วงคำที่ซ้อนกัน เพื่อให้ทุกส่วนของข้อความอยู่ใน index
+/// (all-MiniLM ตัดที่ 256 token — ครึ่งหลังของรีวิวยาวจะค้นไม่เจอ)
+#[derive(Debug, Clone, Copy)]
+pub struct Chunker {
+    /// คำต่อ chunk; 0 = ไม่แบ่ง
+    pub words: usize,
+    /// คำที่ chunk ติดกันใช้ร่วมกัน
+    pub overlap: usize,
+}
+
+impl Chunker {
+    /// `CHUNK_WORDS` (160; 0 = ปิด), `CHUNK_OVERLAP` (32; ต้องน้อยกว่า `CHUNK_WORDS`)
+    pub fn from_env() -> Result<Self> {
+        let read = |name: &str, default: usize| -> Result<usize> {
+            match env::var(name) {
+                Ok(v) => match v.trim().parse() {
+                    Ok(n) => Ok(n),
+                    Err(_) => bail!("{name}={v} is not a number"),
+                },
+                Err(_) => Ok(default),
+            }
+        };
+        let words = read("CHUNK_WORDS", 160)?;
+        let overlap = read("CHUNK_OVERLAP", 32)?;
+        if words > 0 && overlap >= words {
+            bail!("CHUNK_OVERLAP ({overlap}) must be smaller than CHUNK_WORDS ({words})");
+        }
+        Ok(Self { words, overlap })
+    }
+
+    /// ช่วง byte `[start, end)` ใน `text` ของแต่ละ chunk; ว่าง = ทั้งข้อความเป็นเวกเตอร์เดียว
+    /// (ปิดการแบ่ง หรือข้อความไม่เกิน `words` คำ)
+    pub fn spans(&self, text: &str) -> Vec<[usize; 2]> {
+        if self.words == 0 {
+            return Vec::new();
+        }
+        let words = word_spans(text);
+        if words.len() <= self.words {
+            return Vec::new();
+        }
+        let step = self.words - self.overlap;
+        let mut out = Vec::new();
+        let mut first = 0;
+        loop {
+            let last = (first + self.words).min(words.len()) - 1;
+            out.push([words[first][0], words[last][1]]);
+            if last + 1 == words.len() {
+                break;
+            }
+            first += step;
+        }
+        out
+    }
+}
+
+/// ช่วง byte ของแต่ละคำ (คั่นด้วย whitespace)
+fn word_spans(text: &str) -> Vec<[usize; 2]> {
+    let mut out = Vec::new();
+    let mut start = None;
+    for (i, c) in text.char_indices() {
+        match (c.is_whitespace(), start) {
+            (true, Some(s)) => {
+                out.push([s, i]);
+                start = None;
+            }
+            (false, None) => start = Some(i),
+            _ => {}
+        }
+    }
+    if let Some(s) = start {
+        out.push([s, text.len()]);
+    }
+    out
+}
+
+/// ข้อความที่ต้อง embed: หนึ่งข้อความต่อ chunk หรือทั้งข้อความเมื่อไม่มี chunk
+pub fn texts(text: &str, spans: &[[usize; 2]]) -> Vec<String> {
+    if spans.is_empty() {
+        return vec![text.to_string()];
+    }
+    spans
+        .iter()
+        .map(|&[s, e]| text.get(s..e).unwrap_or(text).to_string())
+        .collect()
+}
+
+/// `SEARCH_CHUNK_AGG=max|mean` (ค่าเริ่มต้นของ `SearchRequest.aggregate`)
+pub fn default_aggregate() -> ChunkAggregate {
+    static AGGREGATE: OnceLock<ChunkAggregate> = OnceLock::new();
+    *AGGREGATE.get_or_init(|| {
+        match env::var("SEARCH_CHUNK_AGG")
+            .unwrap_or_default()
+            .to_ascii_lowercase()
+            .as_str()
+        {
+            "mean" => ChunkAggregate::Mean,
+            _ => ChunkAggregate::Max,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunker(words: usize, overlap: usize) -> Chunker {
+        Chunker { words, overlap }
+    }
+
+    fn chunk_words(text: &str, spans: &[[usize; 2]]) -> Vec<Vec<String>> {
+        texts(text, spans)
+            .iter()
+            .map(|t| t.split_whitespace().map(String::from).collect())
+            .collect()
+    }
+
+    #[test]
+    fn short_text_or_disabled_is_one_vector() {
+        let text = "one two three four";
+        assert!(chunker(4, 1).spans(text).is_empty());
+        assert!(chunker(0, 0).spans("a b c d e f g h").is_empty());
+        assert_eq!(texts(text, &[]), [text]);
+    }
+
+    #[test]
+    fn consecutive_chunks_share_overlap_words() {
+        let text = "w0 w1 w2 w3 w4 w5 w6 w7 w8 w9";
+        let spans = chunker(4, 1).spans(text);
+        assert_eq!(
+            chunk_words(text, &spans),
+            [
+                ["w0", "w1", "w2", "w3"],
+                ["w3", "w4", "w5", "w6"],
+                ["w6", "w7", "w8", "w9"],
+            ]
+        );
+        for pair in chunk_words(text, &chunker(5, 2).spans(text)).windows(2) {
+            assert_eq!(pair[0][pair[0].len() - 2..], pair[1][..2]);
+        }
+    }
+
+    #[test]
+    fn last_chunk_ends_at_the_last_word() {
+        // 7 คำ ขั้นละ 3: chunk ที่สองจบพอดี ไม่มี chunk สั้นต่อท้าย
+        let text = "a b c d e f g";
+        let spans = chunker(4, 1).spans(text);
+        assert_eq!(
+            chunk_words(text, &spans),
+            [["a", "b", "c", "d"], ["d", "e", "f", "g"]]
+        );
+
+        // เหลือเศษ: chunk สุดท้ายสั้นกว่า words แต่ยังครอบคำสุดท้าย
+        let text = "a b c d e f g h";
+        let words = chunk_words(text, &chunker(4, 1).spans(text));
+        assert_eq!(words.last().unwrap(), &["g", "h"]);
+    }
+
+    #[test]
+    fn spans_are_byte_ranges_on_char_boundaries() {
+        let text = "  อาหาร\tอร่อย  มาก\n\nบริการ ดี ราคา  ถูก ";
+        let spans = chunker(3, 1).spans(text);
+        assert!(spans.len() > 1);
+        for &[s, e] in &spans {
+            let chunk = text.get(s..e).expect("char boundary");
+            assert_eq!(chunk, chunk.trim());
+        }
+        assert_eq!(&text[spans[0][0]..spans[0][1]], "อาหาร\tอร่อย  มาก");
+        assert_eq!(spans.last().unwrap()[1], text.trim_end().len());
+    }
+}
@@ -1,38 +1,426 @@
-use anyhow::Result;
-use fastembed::{EmbeddingModel, InitOptions, TextEmbedding};
-use once_cell::sync::Lazy;
-use parking_lot::Mutex;
+use anyhow::{anyhow, bail, Context, Result};
+use fastembed::{
+    get_cache_dir, EmbeddingModel, InitOptions, InitOptionsUserDefined, Pooling, TextEmbedding,
+    TokenizerFiles, UserDefinedEmbeddingModel,
+};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use tracing::info;
 
-pub struct Embedder {
-    inner: TextEmbedding,
+use crate::preprocess::Preprocessor;
+
+/// model เริ่มต้น (เหมือนก่อนมี `EMBED_MODEL`)
+const DEFAULT_MODEL: &str = "sentence-transformers/all-MiniLM-L6-v2";
+/// `EMBED_MODEL` ของ [`HashEmbedder`]; dimension จาก `EMBED_DIM`
+const HASH_MODEL: &str = "hash";
+const HASH_DEFAULT_DIM: usize = 384;
+
+/// ไฟล์ tokenizer ที่ fastembed ต้องใช้ (ทั้ง model จาก hub และ model ของผู้ใช้)
+const TOKENIZER_FILES: [&str; 4] = [
+    "tokenizer.json",
+    "config.json",
+    "special_tokens_map.json",
+    "tokenizer_config.json",
+];
+
+/// ตัวสร้างเวกเตอร์จากข้อความ (`AppState.embedder`); เวกเตอร์ทุกตัวยาว `dim()`
+pub trait Embed: Send + Sync {
+    /// id ที่บันทึกคู่กับ index (`EMBED_MODEL`)
+    fn model_id(&self) -> &str;
+
+    fn dim(&self) -> usize;
+
+    /// เวกเตอร์หนึ่งตัวต่อข้อความ ตามลำดับเดิม (ข้อความตามที่ส่งมา ไม่เติม prefix)
+    fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>>;
+
+    /// ข้อความที่จะเก็บใน index (รีวิว / chunk): insert, update, rebuild, repair
+    fn embed_documents(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        self.embed(texts)
+    }
+
+    /// query ของ search (หลายตัวพร้อมกัน — micro-batch ของ `EmbedPool`)
+    fn embed_queries(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        self.embed(texts)
+    }
+
+    fn embed_query(&self, text: &str) -> Result<Vec<f32>> {
+        Ok(self.embed_queries(&[text.to_string()])?.remove(0))
+    }
+}
+
+/// prefix ที่ model แบบ asymmetric (E5, BGE, nomic, …) ต้องการหน้า query / เอกสาร
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Prefixes {
+    pub query: String,
+    pub document: String,
+}
+
+impl Prefixes {
+    /// ค่าที่ model card แนะนำ ตาม model id; model อื่นไม่มี prefix
+    fn for_model(id: &str) -> Self {
+        let id = id.to_ascii_lowercase();
+        let (query, document) = if id.contains("e5-") {
+            ("query: ", "passage: ")
+        } else if id.contains("nomic-embed-text") {
+            ("search_query: ", "search_document: ")
+        } else if (id.contains("bge-") && id.contains("-en")) || id.contains("mxbai-embed-large") {
+            ("Represent this sentence for searching relevant passages: ", "")
+        } else {
+            ("", "")
+        };
+        Self {
+            query: query.into(),
+            document: document.into(),
+        }
+    }
+
+    /// ค่าตาม model แล้วแทนด้วย `EMBED_QUERY_PREFIX` / `EMBED_DOCUMENT_PREFIX` ถ้าตั้งไว้ (ค่าว่าง = ไม่เติม)
+    pub fn from_env(model_id: &str) -> Self {
+        let mut p = Self::for_model(model_id);
+        if let Ok(q) = env::var("EMBED_QUERY_PREFIX") {
+            p.query = q;
+        }
+        if let Ok(d) = env::var("EMBED_DOCUMENT_PREFIX") {
+            p.document = d;
+        }
+        p
+    }
+
+    fn apply(prefix: &str, texts: &[String]) -> Vec<String> {
+        texts.iter().map(|t| format!("{prefix}{t}")).collect()
+    }
+}
+
+/// model โหลดจากไหน
+#[derive(Debug, Clone)]
+pub enum ModelSource {
+    /// model ที่ fastembed รู้จัก: ใช้จาก cache (`EMBED_CACHE_DIR`) หรือดาวน์โหลดจาก Hugging Face
+    Builtin(EmbeddingModel),
+    /// ONNX + tokenizer ของผู้ใช้ใน `EMBED_MODEL_DIR` (ไม่แตะ network)
+    Local {
+        dir: PathBuf,
+        onnx_file: String,
+        pooling: Pooling,
+    },
+    /// `EMBED_MODEL=hash`: [`HashEmbedder`] (ไม่ต้องมีไฟล์ model; สำหรับทดสอบ / CI)
+    Hash,
+}
+
+/// model ที่เลือกด้วย `EMBED_MODEL` / `EMBED_MODEL_DIR` และ dimension ของมัน
+#[derive(Debug, Clone)]
+pub struct ModelSpec {
+    pub source: ModelSource,
+    /// model code เช่น `BAAI/bge-small-en-v1.5` — ถูกบันทึกคู่กับ index
+    pub id: String,
+    pub dim: usize,
+    /// prefix ของ query / เอกสาร (`Prefixes::from_env`)
+    pub prefixes: Prefixes,
 }
 
-static EMBEDDER_SINGLETON: Lazy<Mutex<Option<Arc<Embedder>>>> =
-    Lazy::new(|| Mutex::new(None));
+impl ModelSpec {
+    /// อ่าน config ของ model โดยไม่โหลด model: dimension ของ model ในตัวมาจากข้อมูลของ fastembed,
+    /// ของ `EMBED_MODEL_DIR` มาจาก `hidden_size` ใน `config.json` (หรือ `EMBED_DIM`).
+    /// model ในตัวไม่ใช้ `EMBED_DIM` แล้ว — ถ้าตั้งไว้ต้องตรงกับ model
+    pub fn from_env() -> Result<Self> {
+        let env_dim = match env::var("EMBED_DIM") {
+            Ok(d) => Some(
+                d.trim()
+                    .parse::<usize>()
+                    .map_err(|_| anyhow!("EMBED_DIM={d} is not a number"))?,
+            ),
+            Err(_) => None,
+        };
+        if let Ok(dir) = env::var("EMBED_MODEL_DIR") {
+            return Self::local(PathBuf::from(dir), env_dim);
+        }
+
+        let code = env::var("EMBED_MODEL").unwrap_or_else(|_| DEFAULT_MODEL.into());
+        if code.trim().eq_ignore_ascii_case(HASH_MODEL) {
+            return Ok(Self {
+                source: ModelSource::Hash,
+                id: HASH_MODEL.into(),
+                dim: env_dim.unwrap_or(HASH_DEFAULT_DIM).max(1),
+                prefixes: Prefixes::from_env(HASH_MODEL),
+            });
+        }
+        let model: EmbeddingModel = code.trim().parse().map_err(|e| {
+            anyhow!(
+                "{e} (EMBED_MODEL: a fastembed model code such as {DEFAULT_MODEL}, \
+                 BAAI/bge-small-en-v1.5, BAAI/bge-base-en-v1.5, intfloat/multilingual-e5-small; \
+                 `hash` for the model-free test embedder, or set EMBED_MODEL_DIR for your own ONNX model)"
+            )
+        })?;
+        let info = TextEmbedding::get_model_info(&model)?;
+        if env_dim.is_some_and(|d| d != info.dim) {
+            bail!(
+                "EMBED_DIM={} but {} produces {}-dim vectors; unset EMBED_DIM",
+                env_dim.unwrap_or_default(),
+                info.model_code,
+                info.dim
+            );
+        }
+        Ok(Self {
+            source: ModelSource::Builtin(info.model.clone()),
+            id: info.model_code.clone(),
+            dim: info.dim,
+            prefixes: Prefixes::from_env(&info.model_code),
+        })
+    }
 
-impl Embedder {
-    pub fn get() -> Result<Arc<Embedder>> {
+    fn local(dir: PathBuf, env_dim: Option<usize>) -> Result<Self> {
+        let onnx_file = env::var("EMBED_ONNX_FILE").unwrap_or_else(|_| "model.onnx".into());
+        let pooling = match env::var("EMBED_POOLING")
+            .unwrap_or_else(|_| "mean".into())
+            .to_ascii_lowercase()
+            .as_str()
         {
-            let guard = EMBEDDER_SINGLETON.lock();
-            if let Some(existing) = guard.as_ref() {
-                return Ok(existing.clone());
+            "mean" => Pooling::Mean,
+            "cls" => Pooling::Cls,
+            other => bail!("unknown EMBED_POOLING: {other} (expected mean|cls)"),
+        };
+        let dim = match env_dim {
+            Some(d) => d,
+            None => {
+                let config = dir.join("config.json");
+                let value: serde_json::Value = serde_json::from_slice(
+                    &fs::read(&config).with_context(|| format!("read {}", config.display()))?,
+                )
+                .with_context(|| format!("parse {}", config.display()))?;
+                value
+                    .get("hidden_size")
+                    .and_then(serde_json::Value::as_u64)
+                    .ok_or_else(|| {
+                        anyhow!("{}: no hidden_size; set EMBED_DIM", config.display())
+                    })? as usize
             }
+        };
+        // id ที่บันทึกกับ index: EMBED_MODEL ถ้าตั้งไว้ ไม่งั้นชื่อไดเรกทอรี
+        let id = match env::var("EMBED_MODEL") {
+            Ok(id) => id,
+            Err(_) => format!(
+                "local/{}",
+                dir.file_name()
+                    .map(|n| n.to_string_lossy().into_owned())
+                    .unwrap_or_else(|| "model".into())
+            ),
+        };
+        Ok(Self {
+            source: ModelSource::Local {
+                dir,
+                onnx_file,
+                pooling,
+            },
+            prefixes: Prefixes::from_env(&id),
+            id,
+            dim,
+        })
+    }
+}
+
+/// `EMBED_OFFLINE=1|true`: ห้ามดาวน์โหลด — model ในตัวต้องอยู่ใน cache ครบแล้ว
+fn offline() -> bool {
+    matches!(
+        env::var("EMBED_OFFLINE").unwrap_or_default().to_ascii_lowercase().as_str(),
+        "1" | "true" | "yes"
+    )
+}
+
+/// cache ของ model จาก hub: `EMBED_CACHE_DIR` > `FASTEMBED_CACHE_DIR` > `.fastembed_cache`
+/// (`HF_HOME` ถ้าตั้งไว้ fastembed จะใช้แทนทั้งหมดนี้)
+fn cache_dir() -> PathBuf {
+    if let Ok(home) = env::var("HF_HOME") {
+        return PathBuf::from(home);
+    }
+    env::var("EMBED_CACHE_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| PathBuf::from(get_cache_dir()))
+}
+
+/// ไฟล์ `file` ของ `repo` ใน cache แบบ hf-hub (`models--org--name/refs/main` → `snapshots/<rev>/`)
+fn cached_file(cache: &Path, repo: &str, file: &str) -> Option<PathBuf> {
+    let repo_dir = cache.join(format!("models--{}", repo.replace('/', "--")));
+    let rev = fs::read_to_string(repo_dir.join("refs").join("main")).ok()?;
+    let path = repo_dir.join("snapshots").join(rev.trim()).join(file);
+    path.exists().then_some(path)
+}
+
+fn load_builtin(model: &EmbeddingModel) -> Result<TextEmbedding> {
+    let cache = cache_dir();
+    if offline() {
+        let info = TextEmbedding::get_model_info(model)?;
+        let missing: Vec<&str> = std::iter::once(info.model_file.as_str())
+            .chain(info.additional_files.iter().map(String::as_str))
+            .chain(TOKENIZER_FILES)
+            .filter(|f| cached_file(&cache, &info.model_code, f).is_none())
+            .collect();
+        if !missing.is_empty() {
+            bail!(
+                "EMBED_OFFLINE: {} is not in the model cache {} (missing {}); \
+                 copy a populated cache there, or point EMBED_MODEL_DIR at the model files",
+                info.model_code,
+                cache.display(),
+                missing.join(", ")
+            );
         }
-        let model = TextEmbedding::try_new(InitOptions::new(
-            EmbeddingModel::AllMiniLML6V2,
-        ))?;
-        let embedder = Arc::new(Embedder { inner: model });
-        *EMBEDDER_SINGLETON.lock() = Some(embedder.clone());
-        Ok(embedder)
+    }
+    TextEmbedding::try_new(InitOptions::new(model.clone()).with_cache_dir(cache))
+}
+
+fn load_local(dir: &Path, onnx_file: &str, pooling: &Pooling) -> Result<TextEmbedding> {
+    let read = |name: &str| {
+        let path = dir.join(name);
+        fs::read(&path).with_context(|| format!("read {}", path.display()))
+    };
+    let tokenizer_files = TokenizerFiles {
+        tokenizer_file: read(TOKENIZER_FILES[0])?,
+        config_file: read(TOKENIZER_FILES[1])?,
+        special_tokens_map_file: read(TOKENIZER_FILES[2])?,
+        tokenizer_config_file: read(TOKENIZER_FILES[3])?,
+    };
+    let model = UserDefinedEmbeddingModel::new(read(onnx_file)?, tokenizer_files)
+        .with_pooling(pooling.clone());
+    TextEmbedding::try_new_from_user_defined(model, InitOptionsUserDefined::new())
+}
+
+/// fastembed (ONNX) model
+pub struct FastEmbedder {
+    inner: TextEmbedding,
+    id: String,
+    dim: usize,
+    prefixes: Prefixes,
+}
+
+impl Embed for FastEmbedder {
+    fn model_id(&self) -> &str {
+        &self.id
+    }
+
+    fn dim(&self) -> usize {
+        self.dim
     }
 
-    pub fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+    fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
         let embeddings = self.inner.embed(texts.to_vec(), None)?;
         Ok(embeddings)
     }
 
-    pub fn embed_one(&self, text: &str) -> Result<Vec<f32>> {
-        Ok(self.embed(&[text.to_string()])?.remove(0))
+    fn embed_documents(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        self.embed(&Prefixes::apply(&self.prefixes.document, texts))
+    }
+
+    fn embed_queries(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        self.embed(&Prefixes::apply(&self.prefixes.query, texts))
+    }
+}
+
+/// bag-of-words แบบ feature hashing: คำ (ตัวพิมพ์เล็ก, ตัวอักษร/ตัวเลข) → FNV-1a → ช่อง + เครื่องหมาย,
+/// แล้ว normalize. ผลเหมือนเดิมทุกครั้งทุกเครื่อง และข้อความที่มีคำร่วมกันจะใกล้กัน —
+/// พอสำหรับทดสอบ insert / bulk / search แบบ end to end โดยไม่มีไฟล์ model
+pub struct HashEmbedder {
+    dim: usize,
+    prefixes: Prefixes,
+}
+
+impl HashEmbedder {
+    pub fn new(dim: usize, prefixes: Prefixes) -> Self {
+        Self {
+            dim: dim.max(1),
+            prefixes,
+        }
+    }
+
+    fn vector(&self, text: &str) -> Vec<f32> {
+        let mut v = vec![0f32; self.dim];
+        let lower = text.to_lowercase();
+        for token in lower.split(|c: char| !c.is_alphanumeric()).filter(|t| !t.is_empty()) {
+            let h = fnv1a(token.as_bytes());
+            let sign = if h >> 63 == 0 { 1.0 } else { -1.0 };
+            v[(h % self.dim as u64) as usize] += sign;
+        }
+        let norm = v.iter().map(|x| x * x).sum::<f32>().sqrt();
+        if norm == 0.0 {
+            // ไม่มีคำเลย: เวกเตอร์คงที่แทนเวกเตอร์ศูนย์ (cosine ของศูนย์ไม่นิยาม)
+            v[0] = 1.0;
+        } else {
+            v.iter_mut().for_each(|x| *x /= norm);
+        }
+        v
+    }
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |h, &b| {
+        (h ^ b as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+impl Embed for HashEmbedder {
+    fn model_id(&self) -> &str {
+        HASH_MODEL
+    }
+
+    fn dim(&self) -> usize {
+        self.dim
+    }
+
+    fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        Ok(texts.iter().map(|t| self.vector(t)).collect())
+    }
+
+    fn embed_documents(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        self.embed(&Prefixes::apply(&self.prefixes.document, texts))
+    }
+
+    fn embed_queries(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        self.embed(&Prefixes::apply(&self.prefixes.query, texts))
+    }
+}
+
+/// โหลด model ตาม `spec`; main เรียกตอนเริ่ม ให้ model ที่โหลดไม่ได้ล้มตั้งแต่ startup
+pub fn load(spec: &ModelSpec) -> Result<Arc<dyn Embed>> {
+    let model = match &spec.source {
+        ModelSource::Hash => {
+            return Ok(Arc::new(HashEmbedder::new(spec.dim, spec.prefixes.clone())))
+        }
+        ModelSource::Builtin(m) => load_builtin(m),
+        ModelSource::Local {
+            dir,
+            onnx_file,
+            pooling,
+        } => load_local(dir, onnx_file, pooling),
     }
-}
\ No newline at end of file
+    .with_context(|| format!("load embedding model {}", spec.id))?;
+    let embedder = FastEmbedder {
+        inner: model,
+        id: spec.id.clone(),
+        dim: spec.dim,
+        prefixes: spec.prefixes.clone(),
+    };
+
+    // dimension ที่ index ใช้ต้องตรงกับที่ model ให้จริง (สำคัญกับ EMBED_MODEL_DIR)
+    let probe = embedder.embed_query("dimension probe")?;
+    if probe.len() != spec.dim {
+        bail!(
+            "{} produces {}-dim vectors, expected {} (EMBED_DIM / config.json hidden_size)",
+            spec.id,
+            probe.len(),
+            spec.dim
+        );
+    }
+    if spec.prefixes != Prefixes::default() {
+        info!(
+            "{}: query prefix {:?}, document prefix {:?}",
+            spec.id, spec.prefixes.query, spec.prefixes.document
+        );
+    }
+    Ok(Arc::new(embedder))
+}
+
+/// `load(&ModelSpec::from_env()?)` ห่อด้วย `TEXT_PREPROCESS` — ทั้ง server และคำสั่ง CLI ที่ต้อง embed
+/// (rebuild / repair) จึงทำความสะอาดข้อความแบบเดียวกัน
+pub fn from_env() -> Result<Arc<dyn Embed>> {
+    let pre = Preprocessor::from_env()?;
+    Ok(pre.wrap(load(&ModelSpec::from_env()?)?))
+}
@@ -0,0 +1,122 @@
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::path::Path;
+
+use crate::index::{check_batch, dot, normalize, IndexError, VectorIndex};
+use crate::vector_log::VectorLog;
+
+/// Exact (brute-force) cosine search, kept fully in memory and backed by
+/// an append-only [`VectorLog`]. `add_batch` appends immediately; `save`
+/// fsyncs. Deleted ids are dropped from memory and tombstoned in the log.
+pub struct FlatIndex {
+    dim: usize,
+    ids: Vec<i64>,
+    /// unit-length vectors, `ids.len() * dim`
+    vectors: Vec<f32>,
+    log: VectorLog,
+}
+
+impl FlatIndex {
+    pub fn open(path: &Path, dim: usize) -> Result<Self, IndexError> {
+        let (log, loaded) = VectorLog::open(path, dim)?;
+        let mut ids = Vec::with_capacity(loaded.ids.len());
+        let mut vectors = Vec::with_capacity(loaded.vectors.len());
+        for (&id, v) in loaded.ids.iter().zip(loaded.vectors.chunks_exact(dim)) {
+            if loaded.deleted.contains(&id) {
+                continue;
+            }
+            ids.push(id);
+            let start = vectors.len();
+            vectors.extend_from_slice(v);
+            normalize(&mut vectors[start..]);
+        }
+        Ok(Self {
+            dim,
+            ids,
+            vectors,
+            log,
+        })
+    }
+}
+
+impl VectorIndex for FlatIndex {
+    fn dim(&self) -> usize {
+        self.dim
+    }
+
+    fn add_batch(&mut self, vectors: &[f32], ids: Option<&[i64]>) -> Result<(), IndexError> {
+        let n = check_batch(self.dim, vectors, ids)?;
+        if n == 0 {
+            return Ok(());
+        }
+        let first_auto = self.ids.iter().copied().max().map_or(0, |m| m + 1);
+
+        for (i, v) in vectors.chunks_exact(self.dim).enumerate() {
+            let id = ids.map_or(first_auto + i as i64, |s| s[i]);
+            self.log.append(id, v)?;
+            self.ids.push(id);
+            let start = self.vectors.len();
+            self.vectors.extend_from_slice(v);
+            normalize(&mut self.vectors[start..]);
+        }
+        // ส่งให้ OS ทันที; fsync ทำตอน save()
+        self.log.flush()
+    }
+
+    fn search(&self, query: &[f32], topk: usize) -> Result<(Vec<i64>, Vec<f32>), IndexError> {
+        if query.len() != self.dim {
+            return Err(IndexError::InvalidParam("query dim mismatch"));
+        }
+        if topk == 0 || self.ids.is_empty() {
+            return Ok((Vec::new(), Vec::new()));
+        }
+        let mut q = query.to_vec();
+        normalize(&mut q);
+
+        let mut scored: Vec<(f32, i64)> = self
+            .vectors
+            .chunks_exact(self.dim)
+            .zip(&self.ids)
+            .map(|(v, &id)| (dot(&q, v), id))
+            .collect();
+
+        let by_score_desc =
+            |a: &(f32, i64), b: &(f32, i64)| b.0.partial_cmp(&a.0).unwrap_or(Ordering::Equal);
+        if scored.len() > topk {
+            scored.select_nth_unstable_by(topk - 1, by_score_desc);
+            scored.truncate(topk);
+        }
+        scored.sort_by(by_score_desc);
+
+        Ok(scored.into_iter().map(|(s, id)| (id, s)).unzip())
+    }
+
+    fn delete(&mut self, ids: &[i64]) -> Result<(), IndexError> {
+        if ids.is_empty() {
+            return Ok(());
+        }
+        self.log.delete(ids)?;
+        let gone: HashSet<i64> = ids.iter().copied().collect();
+        let dim = self.dim;
+        let mut keep = 0;
+        for i in 0..self.ids.len() {
+            if gone.contains(&self.ids[i]) {
+                continue;
+            }
+            self.ids[keep] = self.ids[i];
+            self.vectors.copy_within(i * dim..(i + 1) * dim, keep * dim);
+            keep += 1;
+        }
+        self.ids.truncate(keep);
+        self.vectors.truncate(keep * dim);
+        Ok(())
+    }
+
+    fn ids(&self) -> Result<Vec<i64>, IndexError> {
+        Ok(self.ids.clone())
+    }
+
+    fn save(&mut self) -> Result<(), IndexError> {
+        self.log.sync()
+    }
+}
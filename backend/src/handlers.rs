@@ -1,33 +1,33 @@
-use crate::embedder::Embedder;
-use crate::storage::{append_review_line, append_vector_map_line, load_all_reviews};
-use crate::types::{BulkReviews, ReviewInput, SearchRequest, SearchResponse, StoredReview, SearchHit};
+use crate::chunk::{self, Chunker};
+use crate::embedder::Embed;
+use crate::lock::{read_only, DataDirLock, LockError};
+use crate::pool::{BlockingPool, EmbedPool};
+use crate::query_cache::QueryCache;
+use crate::review_store::ReviewStore;
+use crate::{rebuild, snapshot, verify};
+use crate::wal::{self, Wal, WalRecord};
+use crate::storage::{
+    append_review_line, append_tombstone_line, append_vector_map_lines, deletions_path,
+    load_deleted_vector_ids, recover_data_files, wal_path,
+};
+use crate::types::{
+    BulkReviews, ChunkAggregate, ChunkMatch, QueryCacheStats, RebuildStatus, RepairReport,
+    ReviewInput, ReviewPatch, SearchRequest, SearchResponse, StoredReview, SearchHit,
+    SnapshotReport, SnapshotRequest, Tombstone, VerifyReport,
+};
 
-// ใช้ Spfresh (FFI) แทน SpFreshIndex เดิม
-use crate::spfresh::Spfresh;
+// index engine เลือกได้ผ่าน INDEX_BACKEND (spfresh | flat | hnsw)
+use crate::index::{IndexConfig, VectorIndex};
 
-use axum::{extract::State, Json};
+use axum::{extract::{Path as AxumPath, State}, Json};
 use axum::http::StatusCode;
+use chrono::Utc;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::cmp::Ordering;
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::{BufRead, BufReader};
-use std::path::Path;
-use std::sync::{Arc, RwLock};
-use tracing::{error, warn};
-
-/// อ่านจำนวนบรรทัดของไฟล์ (ใช้เมื่อเปลี่ยน paths ใหม่ เพื่อรีเซ็ต next_vector_id)
-fn count_lines(path: &str) -> usize {
-    let p = Path::new(path);
-    if !p.exists() {
-        return 0;
-    }
-    match File::open(p).map(BufReader::new) {
-        Ok(reader) => reader.lines().count(),
-        Err(_) => 0,
-    }
-}
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard};
+use tracing::{error, info, warn};
 
 // ---- Health ----
 pub async fn health_handler() -> (StatusCode, Json<serde_json::Value>) {
@@ -44,11 +44,227 @@ pub struct Paths {
 
 #[derive(Clone)]
 pub struct AppState {
-    // เปลี่ยนชนิดเป็น Spfresh (FFI) + RwLock
-    pub index: Arc<RwLock<Spfresh>>,
+    // index engine ใดก็ได้ที่ implement VectorIndex (Spfresh FFI / FlatIndex / HnswIndex)
+    pub index: Arc<RwLock<Box<dyn VectorIndex>>>,
     pub paths: Arc<RwLock<Paths>>,
     // ตัวนับ ID เพื่อส่งให้ SPFresh (ตรงกับ vector_map.jsonl)
     pub next_vector_id: Arc<RwLock<usize>>,
+    // จำนวนเวกเตอร์ที่เพิ่มแล้วแต่ยังไม่ได้ idx.save()
+    pub unsaved_vectors: Arc<RwLock<usize>>,
+    // เรียก idx.save() ทุก ๆ N เวกเตอร์ (INDEX_FLUSH_EVERY)
+    pub flush_every: usize,
+    // vector_id ที่ถูกลบ (จาก deletions.jsonl) — search จะข้ามไป
+    pub deleted: Arc<RwLock<HashSet<usize>>>,
+    // handler ที่เขียนข้อมูลถือ read guard; rebuild ถือ write guard ตอน catch-up + swap
+    pub write_gate: Arc<RwLock<()>>,
+    // สถานะ rebuild ล่าสุด (GET /admin/rebuild)
+    pub rebuild: Arc<RwLock<RebuildStatus>>,
+    // write-ahead log ของ insert (wal.jsonl ข้าง reviews.jsonl)
+    pub wal: Arc<Mutex<Wal>>,
+    // vector_id → review (สำเนาของ reviews.jsonl) สำหรับ search / update / delete
+    pub reviews: Arc<RwLock<ReviewStore>>,
+    // flock บนไดเรกทอรีข้อมูลของ paths ปัจจุบัน (None = READ_ONLY)
+    pub data_lock: Arc<Mutex<Option<DataDirLock>>>,
+    // model ที่ใช้ embed ทั้ง insert / update / search / rebuild (fastembed หรือ hash สำหรับทดสอบ)
+    pub embedder: Arc<dyn Embed>,
+    // embed ของ request ผ่านคิวนี้ (EMBED_WORKERS / EMBED_QUEUE); คิวเต็ม => 503
+    pub embed_pool: Arc<EmbedPool>,
+    // search / insert / update / delete (index FFI + เขียนไฟล์) ผ่านคิวนี้ (INDEX_WORKERS / INDEX_QUEUE)
+    pub index_pool: Arc<BlockingPool>,
+    // เวกเตอร์ของ query ที่ค้นซ้ำ (QUERY_CACHE_SIZE); ผูกกับ embedder.model_id()
+    pub query_cache: Arc<QueryCache>,
+    // แบ่งรีวิวยาวเป็น chunk ละ vector (CHUNK_WORDS / CHUNK_OVERLAP)
+    pub chunker: Chunker,
+}
+
+/// READ_ONLY=1: ทุก endpoint ที่เขียนข้อมูลตอบ 403
+fn ensure_writable() -> Result<(), (StatusCode, String)> {
+    if read_only() {
+        return Err((StatusCode::FORBIDDEN, "server is read-only (READ_ONLY=1)".into()));
+    }
+    Ok(())
+}
+
+/// กันไม่ให้ rebuild สลับ index ระหว่างที่ handler นี้กำลังเขียน
+fn write_guard(state: &AppState) -> Result<RwLockReadGuard<'_, ()>, (StatusCode, String)> {
+    ensure_writable()?;
+    state
+        .write_gate
+        .read()
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "write gate poisoned".into()))
+}
+
+/// นับเวกเตอร์ที่ยังไม่ได้ persist แล้วเรียก `save()` เมื่อครบ `flush_every`.
+/// save ล้มเหลวจะไม่ทำให้ insert ล้ม (ตัวนับค้างไว้ ลองใหม่รอบถัดไป)
+fn flush_index_if_due(
+    state: &AppState,
+    idx: &mut dyn VectorIndex,
+    added: usize,
+) -> Result<(), (StatusCode, String)> {
+    let mut unsaved = state
+        .unsaved_vectors
+        .write()
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "flush lock poisoned".into()))?;
+    *unsaved += added;
+    if *unsaved < state.flush_every {
+        return Ok(());
+    }
+    match idx.save() {
+        Ok(()) => *unsaved = 0,
+        Err(e) => {
+            error!("index save error ({} unsaved vectors): {:?}", *unsaved, e);
+            return Ok(());
+        }
+    }
+    // index persist แล้ว => record ที่ apply ครบแล้วใน WAL ไม่จำเป็นอีก
+    if let Err(e) = state
+        .wal
+        .lock()
+        .map_err(|_| anyhow::anyhow!("wal lock poisoned"))
+        .and_then(|mut w| w.checkpoint())
+    {
+        warn!("wal checkpoint failed: {e:?}");
+    }
+    Ok(())
+}
+
+/// เขียนแถวใหม่พร้อมเวกเตอร์: WAL (fsync) → index → vector_map.jsonl → reviews.jsonl.
+/// crash ระหว่างทาง => record ใน WAL ถูก replay ตอนเปิดครั้งถัดไป;
+/// error ระหว่างทาง => `abort_record` ย้อนส่วนที่ทำไปแล้ว และ replay ข้าม record นี้
+fn commit_rows(
+    state: &AppState,
+    paths: &Paths,
+    reviews: Vec<StoredReview>,
+    vectors: Vec<Vec<f32>>,
+) -> Result<Vec<StoredReview>, (StatusCode, String)> {
+    let record = WalRecord { reviews, vectors };
+    state
+        .wal
+        .lock()
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "wal lock poisoned".into()))?
+        .append(&record)
+        .map_err(|e| {
+            error!("wal write error: {:?}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "wal write failed".to_string(),
+            )
+        })?;
+
+    let ids: Vec<i64> = record
+        .reviews
+        .iter()
+        .flat_map(StoredReview::vector_ids)
+        .map(|v| v as i64)
+        .collect();
+    let mut written = 0;
+    if let Err(e) = apply_record(state, paths, &record, &ids, &mut written) {
+        abort_record(state, paths, &record, &ids, written);
+        return Err(e);
+    }
+
+    if let Ok(mut w) = state.wal.lock() {
+        w.applied();
+    }
+    let mut idx = state
+        .index
+        .write()
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "index lock poisoned".into()))?;
+    flush_index_if_due(state, &mut **idx, ids.len())?;
+
+    Ok(record.reviews)
+}
+
+/// ส่วนหลัง WAL ของ `commit_rows`; `written` = จำนวนแถวที่อยู่ใน reviews.jsonl แล้ว.
+/// แถวรีวิวเขียนเป็นอย่างสุดท้ายของแต่ละแถว จึงไม่มีแถวที่มองเห็นได้แต่ไม่มี map / เวกเตอร์
+fn apply_record(
+    state: &AppState,
+    paths: &Paths,
+    record: &WalRecord,
+    ids: &[i64],
+    written: &mut usize,
+) -> Result<(), (StatusCode, String)> {
+    // add_batch ต้องการ buffer ต่อเนื่อง [n * dim]; เวกเตอร์เรียงตาม vector_ids() ของแต่ละแถว
+    let flat: Vec<f32> = record.vectors.concat();
+    state
+        .index
+        .write()
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "index lock poisoned".into()))?
+        .add_batch(&flat, Some(ids))
+        .map_err(|e| {
+            error!("index add_batch error: {:?}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "index append failed".to_string(),
+            )
+        })?;
+
+    for stored in &record.reviews {
+        append_vector_map_lines(&paths.map_path, stored).map_err(|e| {
+            error!("write vector_map error: {:?}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "vector_map write failed".to_string(),
+            )
+        })?;
+
+        let loc = append_review_line(&paths.jsonl_path, stored).map_err(|e| {
+            error!("write metadata error: {:?}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "metadata write failed".to_string(),
+            )
+        })?;
+        *written += 1;
+
+        state
+            .reviews
+            .write()
+            .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "reviews lock poisoned".into()))?
+            .insert(stored.clone(), loc);
+    }
+    Ok(())
+}
+
+/// ย้อน `apply_record` ที่ล้มกลางทาง (client ได้ 500): abort marker ใน WAL ก่อน
+/// (record นี้ settle แล้วและจะไม่ถูก replay) → ลบเวกเตอร์ออกจาก index →
+/// tombstone แถวที่เขียนลง reviews.jsonl ไปแล้ว. ทำเต็มที่ ผิดพลาดแค่ log
+fn abort_record(state: &AppState, paths: &Paths, record: &WalRecord, ids: &[i64], written: usize) {
+    if let Err(e) = state
+        .wal
+        .lock()
+        .map_err(|_| anyhow::anyhow!("wal lock poisoned"))
+        .and_then(|mut w| w.abort(record))
+    {
+        error!("wal abort marker error: {e:?}");
+    }
+
+    match state.index.write() {
+        Ok(mut idx) => {
+            if let Err(e) = idx.delete(ids) {
+                error!("index undo error: {e:?}");
+            }
+        }
+        Err(_) => error!("index undo error: index lock poisoned"),
+    }
+
+    let deletions = deletions_path(&paths.jsonl_path);
+    for row in &record.reviews[..written] {
+        for vector_id in row.vector_ids() {
+            let tombstone = Tombstone {
+                review_id: row.id.clone(),
+                vector_id,
+                deleted_at: Utc::now(),
+                superseded_by: None,
+            };
+            if let Err(e) = append_tombstone_line(&deletions, &tombstone) {
+                error!("undo tombstone error: {e:?}");
+            }
+        }
+        if let Ok(mut deleted) = state.deleted.write() {
+            deleted.extend(row.vector_ids());
+        }
+    }
 }
 
 // GET /api/config/paths
@@ -67,6 +283,15 @@ pub async fn set_paths_handler(
     State(state): State<AppState>,
     Json(newp): Json<Paths>,
 ) -> Result<Json<Paths>, (StatusCode, String)> {
+    // rebuild เขียนไฟล์ index ตาม paths เดิม ห้ามเปลี่ยนระหว่างนั้น
+    if state.rebuild.read().map(|r| r.running).unwrap_or(false) {
+        return Err((StatusCode::CONFLICT, "index rebuild in progress".into()));
+    }
+    // หยุดการเขียนทั้งหมดระหว่างสลับชุดข้อมูล
+    let _quiesce = state
+        .write_gate
+        .write()
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "write gate poisoned".into()))?;
 
     // Create parent dirs if needed
     for path in [&newp.index_path, &newp.jsonl_path, &newp.map_path] {
@@ -76,27 +301,62 @@ pub async fn set_paths_handler(
         }
     }
 
-    // อ่าน ENV สำหรับเปิด SPFresh
-    let embed_dim: usize = std::env::var("EMBED_DIM")
-        .ok()
-        .and_then(|s| s.parse().ok())
-        .unwrap_or(384);
-    let spf_params =
-        std::env::var("SPFRESH_PARAMS").unwrap_or_else(|_| "PostingPageLimit=12".into());
+    // lock ไดเรกทอรีใหม่ก่อนแตะไฟล์ใด ๆ (ไดเรกทอรีที่ถืออยู่แล้วใช้ lock เดิม)
+    let new_lock = if read_only() {
+        None
+    } else {
+        let current = state
+            .data_lock
+            .lock()
+            .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "data lock poisoned".into()))?;
+        Some(DataDirLock::acquire(&newp, current.as_ref()).map_err(|e| match e {
+            LockError::Held { .. } => (StatusCode::CONFLICT, e.to_string()),
+            LockError::Io { .. } => (StatusCode::BAD_REQUEST, e.to_string()),
+        })?)
+    };
+
+    // อ่าน ENV สำหรับเปิด index (backend / dim / SPFRESH_PARAMS)
+    let index_cfg = IndexConfig::from_env()
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("index config: {e}")))?;
+
+    rebuild::finish_install(&newp.index_path)
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("finish rebuild install failed: {e:#}")))?;
+
+    // Open (or create) index at new location
+    let mut new_index = index_cfg
+        .open(&newp.index_path)
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("open index failed: {e}")))?;
+
+    // ตัดเศษบรรทัดที่เขียนไม่จบท้ายไฟล์ (crash กลาง append) ก่อนเขียนต่อ
+    if !read_only() {
+        recover_data_files(&newp.jsonl_path, &newp.map_path)
+            .map_err(|e| (StatusCode::BAD_REQUEST, format!("recover metadata failed: {e}")))?;
+    }
+
+    // โหลดรายการที่ถูกลบของชุดข้อมูลใหม่
+    let new_deleted = load_deleted_vector_ids(&newp.jsonl_path).map_err(|e| {
+        (StatusCode::BAD_REQUEST, format!("read deletions failed: {e}"))
+    })?;
 
-    // ใช้ "ไดเรกทอรี" ของ index (parent ของไฟล์ reviews.index) เพื่อเปิด SPFresh
-    let index_dir = std::path::Path::new(&newp.index_path)
-        .parent()
-        .unwrap_or(std::path::Path::new("."))
-        .to_string_lossy()
-        .to_string();
+    // replay WAL ของชุดข้อมูลใหม่ (ถ้ามี record ค้าง)
+    let new_wal_path = wal_path(&newp.jsonl_path);
+    let new_wal = if read_only() {
+        Wal::detached(std::path::Path::new(&new_wal_path))
+    } else {
+        Wal::open(std::path::Path::new(&new_wal_path))
+            .and_then(|(mut w, records)| {
+                wal::replay(&mut w, records, &newp, &mut *new_index, &new_deleted)?;
+                Ok(w)
+            })
+            .map_err(|e| (StatusCode::BAD_REQUEST, format!("wal replay failed: {e:#}")))?
+    };
 
-    // Open (or create) index at new location (SPFresh)
-    let new_index = Spfresh::open(&index_dir, embed_dim, &spf_params)
-        .map_err(|e| (StatusCode::BAD_REQUEST, format!("open SPFresh failed: {e}")))?;
+    let new_reviews = ReviewStore::load(&newp.jsonl_path)
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("read metadata failed: {e}")))?;
 
-    // Update next_vector_id จากไฟล์ map ใหม่
-    let new_next_id = count_lines(&newp.map_path);
+    // next_vector_id = ต่อจาก id มากสุดที่เคยใช้ (ไฟล์ใหม่ + index ใหม่)
+    let new_next_id = verify::next_vector_id(&newp, &*new_index)
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("read metadata failed: {e}")))?;
 
     // Swap index atomically
     {
@@ -104,7 +364,25 @@ pub async fn set_paths_handler(
             .index
             .write()
             .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "index lock poisoned".into()))?;
+        // persist index เดิมก่อนสลับออก
+        if !read_only() {
+            if let Err(e) = idx_guard.save() {
+                warn!("save previous index failed: {e}");
+            }
+        }
         *idx_guard = new_index;
+        if let Ok(mut unsaved) = state.unsaved_vectors.write() {
+            *unsaved = 0;
+        }
+    }
+
+    // Swap WAL
+    {
+        let mut w = state
+            .wal
+            .lock()
+            .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "wal lock poisoned".into()))?;
+        *w = new_wal;
     }
 
     // Update paths atomically
@@ -125,6 +403,33 @@ pub async fn set_paths_handler(
         *idg = new_next_id;
     }
 
+    // Update deleted set atomically
+    {
+        let mut d = state
+            .deleted
+            .write()
+            .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "deleted lock poisoned".into()))?;
+        *d = new_deleted;
+    }
+
+    // Update review store atomically
+    {
+        let mut r = state
+            .reviews
+            .write()
+            .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "reviews lock poisoned".into()))?;
+        *r = new_reviews;
+    }
+
+    // ปล่อย lock ของไดเรกทอรีเดิม (ที่ไม่ได้ใช้ร่วมกับชุดใหม่)
+    {
+        let mut l = state
+            .data_lock
+            .lock()
+            .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "data lock poisoned".into()))?;
+        *l = new_lock;
+    }
+
     Ok(Json(newp))
 }
 
@@ -133,79 +438,58 @@ pub async fn insert_review_handler(
     State(state): State<AppState>,
     Json(payload): Json<ReviewInput>,
 ) -> Result<Json<StoredReview>, (StatusCode, String)> {
+    ensure_writable()?;
     payload
         .validate()
         .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
 
-    let embedder = Embedder::get().map_err(|e| {
-        error!("embedder init error: {:?}", e);
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "embedding init failed".to_string(),
-        )
-    })?;
+    // รีวิวยาว => หลาย chunk, หนึ่งเวกเตอร์ต่อ chunk
+    let spans = state.chunker.spans(&payload.review);
+    let vectors = state
+        .embed_pool
+        .embed_documents(chunk::texts(&payload.review, &spans))
+        .await?;
 
-    let vec = embedder.embed_one(&payload.review).map_err(|e| {
-        error!("embed error: {:?}", e);
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "embedding failed".to_string(),
-        )
-    })?;
+    let st = state.clone();
+    let stored = state
+        .index_pool
+        .run(move || insert_one(&st, payload, spans, vectors))
+        .await??;
+    Ok(Json(stored))
+}
 
-    // จอง vector_id หนึ่งค่า
-    let vector_id: usize = {
-        let mut g = state
-            .next_vector_id
-            .write()
-            .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "id lock poisoned".into()))?;
-        let id = *g;
-        *g += 1;
-        id
-    };
+fn insert_one(
+    state: &AppState,
+    payload: ReviewInput,
+    spans: Vec<[usize; 2]>,
+    vectors: Vec<Vec<f32>>,
+) -> Result<StoredReview, (StatusCode, String)> {
+    let _writes = write_guard(state)?;
 
-    // เพิ่มเวกเตอร์ลง SPFresh โดยส่ง id ชัดเจน
-    {
-        let idx = state
-            .index
-            .write()
-            .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "index lock poisoned".into()))?;
-        // add_batch ต้องการ buffer ต่อเนื่องความยาว dim (n=1)
-        idx.add_batch(&vec, Some(&[vector_id as i64]))
-            .map_err(|e| {
-                error!("index add_batch error: {:?}", e);
-                (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    "index append failed".to_string(),
-                )
-            })?;
-        // (ถ้าต้อง save/snapshot อาจเรียก idx.save()? แล้วแต่ flow)
-    }
+    // จอง vector_id ต่อเนื่อง หนึ่งค่าต่อ chunk
+    let vector_id = reserve_vector_ids(state, vectors.len())?;
 
-    let stored = StoredReview::from_input(payload, vector_id);
+    let stored = StoredReview::from_input(payload, vector_id).with_chunks(spans);
 
     let p = state
         .paths
         .read()
-        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "paths lock poisoned".into()))?;
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "paths lock poisoned".into()))?
+        .clone();
 
-    append_review_line(&p.jsonl_path, &stored).map_err(|e| {
-        error!("write metadata error: {:?}", e);
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "metadata write failed".to_string(),
-        )
-    })?;
-
-    append_vector_map_line(&p.map_path, vector_id, &stored.id).map_err(|e| {
-        error!("write vector_map error: {:?}", e);
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "vector_map write failed".to_string(),
-        )
-    })?;
+    let mut out = commit_rows(state, &p, vec![stored], vectors)?;
+    Ok(out.remove(0))
+}
 
-    Ok(Json(stored))
+/// จอง vector_id ต่อเนื่อง `n` ค่า คืนค่าแรก
+fn reserve_vector_ids(state: &AppState, n: usize) -> Result<usize, (StatusCode, String)> {
+    let mut g = state
+        .next_vector_id
+        .write()
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "id lock poisoned".into()))?;
+    let start = *g;
+    *g += n;
+    Ok(start)
 }
 
 // ---- Bulk insert ----
@@ -213,6 +497,7 @@ pub async fn bulk_insert_handler(
     State(state): State<AppState>,
     Json(BulkReviews(items)): Json<BulkReviews>,
 ) -> Result<Json<Vec<StoredReview>>, (StatusCode, String)> {
+    ensure_writable()?;
     if items.is_empty() {
         return Err((StatusCode::BAD_REQUEST, "Bulk list empty".into()));
     }
@@ -220,114 +505,73 @@ pub async fn bulk_insert_handler(
         r.validate().map_err(|e| (StatusCode::BAD_REQUEST, e.clone()))?;
     }
 
-    let embedder = Embedder::get().map_err(|e| {
-        error!("embedder init error: {:?}", e);
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "embedding init failed".to_string(),
-        )
-    })?;
+    let spans: Vec<Vec<[usize; 2]>> = items
+        .iter()
+        .map(|p| state.chunker.spans(&p.review))
+        .collect();
+    let texts: Vec<String> = items
+        .iter()
+        .zip(&spans)
+        .flat_map(|(p, s)| chunk::texts(&p.review, s))
+        .collect();
+    let vectors = state.embed_pool.embed_documents(texts).await?;
 
-    let texts: Vec<String> = items.iter().map(|p| p.review.clone()).collect();
-    let vectors = embedder.embed(&texts).map_err(|e| {
-        error!("embed error: {:?}", e);
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "embedding failed".to_string(),
-        )
-    })?;
-    if vectors.len() != texts.len() {
-        return Err((
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "Embedding count mismatch".into(),
-        ));
-    }
+    let st = state.clone();
+    let stored = state
+        .index_pool
+        .run(move || insert_many(&st, items, spans, vectors))
+        .await??;
+    Ok(Json(stored))
+}
 
-    // เตรียม ids ต่อเนื่องตามจำนวนรายการ
-    let (start_id, n) = {
-        let mut g = state
-            .next_vector_id
-            .write()
-            .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "id lock poisoned".into()))?;
-        let start = *g;
-        let n = vectors.len();
-        *g += n;
-        (start, n)
-    };
-    let ids: Vec<i64> = (start_id..start_id + n).map(|x| x as i64).collect();
+fn insert_many(
+    state: &AppState,
+    items: Vec<ReviewInput>,
+    spans: Vec<Vec<[usize; 2]>>,
+    vectors: Vec<Vec<f32>>,
+) -> Result<Vec<StoredReview>, (StatusCode, String)> {
+    let _writes = write_guard(state)?;
 
-    // flatten vectors เป็น buffer ต่อเนื่อง [n * dim]
-    let dim = vectors.get(0).map(|v| v.len()).unwrap_or(0);
+    // ทุกเวกเตอร์ต้องมีมิติเท่ากัน
+    let dim = vectors.first().map(|v| v.len()).unwrap_or(0);
     if dim == 0 {
         return Err((
             StatusCode::INTERNAL_SERVER_ERROR,
             "Empty embedding dimension".into(),
         ));
     }
-    let mut flat: Vec<f32> = Vec::with_capacity(n * dim);
-    for v in &vectors {
-        if v.len() != dim {
-            warn!(
-                "Inconsistent embedding dim: expected {}, got {}",
-                dim,
-                v.len()
-            );
-            return Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Inconsistent embedding dimension".into(),
-            ));
-        }
-        flat.extend_from_slice(v);
+    if let Some(v) = vectors.iter().find(|v| v.len() != dim) {
+        warn!(
+            "Inconsistent embedding dim: expected {}, got {}",
+            dim,
+            v.len()
+        );
+        return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Inconsistent embedding dimension".into(),
+        ));
     }
 
-    // เพิ่มทั้งหมดทีเดียว
-    {
-        let idx = state
-            .index
-            .write()
-            .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "index lock poisoned".into()))?;
-        idx.add_batch(&flat, Some(&ids)).map_err(|e| {
-            error!("index add_batch error: {:?}", e);
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "index append failed".to_string(),
-            )
-        })?;
-        // (ถ้าต้อง save/snapshot อาจเรียก idx.save()? แล้วแต่ flow)
-    }
+    // เตรียม ids ต่อเนื่องตามจำนวนเวกเตอร์ (chunk) ทั้งหมด; แต่ละแถวได้ช่วงของตัวเอง
+    let mut next_id = reserve_vector_ids(state, vectors.len())?;
+    let stored: Vec<StoredReview> = items
+        .into_iter()
+        .zip(spans)
+        .map(|(input, chunks)| {
+            let row = StoredReview::from_input(input, next_id).with_chunks(chunks);
+            next_id += row.vector_count();
+            row
+        })
+        .collect();
 
     let p = state
         .paths
         .read()
-        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "paths lock poisoned".into()))?;
-
-    // เขียน metadata และ map ตามลำดับ
-    let mut out = Vec::with_capacity(n);
-    for ((input, _vec), vid_i64) in items.into_iter().zip(vectors.into_iter()).zip(ids.into_iter())
-    {
-        let vector_id: usize = usize::try_from(vid_i64).unwrap_or(0);
-        let stored = StoredReview::from_input(input, vector_id);
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "paths lock poisoned".into()))?
+        .clone();
 
-        append_review_line(&p.jsonl_path, &stored).map_err(|e| {
-            error!("write metadata error: {:?}", e);
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "metadata write failed".to_string(),
-            )
-        })?;
-
-        append_vector_map_line(&p.map_path, vector_id, &stored.id).map_err(|e| {
-            error!("write vector_map error: {:?}", e);
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "vector_map write failed".to_string(),
-            )
-        })?;
-
-        out.push(stored);
-    }
-
-    Ok(Json(out))
+    // WAL record เดียวครอบคลุมทั้ง batch
+    commit_rows(state, &p, stored, vectors)
 }
 
 // ---- Search ----
@@ -341,30 +585,50 @@ pub async fn search_handler(
 
     const TOP_N: usize = 5;
 
-    let embedder = Embedder::get().map_err(|e| {
-        error!("embedder init error: {:?}", e);
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "embedding init failed".to_string(),
-        )
-    })?;
+    let model = state.embedder.model_id();
+    let qvec = match state.query_cache.get(model, &req.query) {
+        Some(v) => v,
+        None => {
+            let v = state.embed_pool.embed_query(req.query.clone()).await?;
+            state.query_cache.put(model, &req.query, &v);
+            v
+        }
+    };
 
-    let qvec = embedder.embed_one(&req.query).map_err(|e| {
-        error!("embed query failed: {:?}", e);
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "embedding failed".to_string(),
-        )
-    })?;
+    let ann_k = req.top_k.unwrap_or(TOP_N).clamp(TOP_N, 200);
+    // ef ต้องไม่น้อยกว่า ann_k; จำกัดเพดานกันคำขอที่แพงเกินไป
+    let ef = req.ef.map(|ef| ef.clamp(ann_k, 4096));
+    let aggregate = req.aggregate.unwrap_or_else(chunk::default_aggregate);
+
+    let st = state.clone();
+    let mut out = state
+        .index_pool
+        .run(move || search_hits(&st, &qvec, ann_k, ef, aggregate))
+        .await??;
+
+    out.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(Ordering::Equal));
+    if out.len() > TOP_N {
+        out.truncate(TOP_N);
+    }
 
-    let ann_k = req.top_k.unwrap_or(TOP_N).max(TOP_N).min(200);
+    Ok(Json(SearchResponse { hits: out }))
+}
 
+/// ค้น index แล้วจับคู่กับรีวิวที่ยังไม่ถูกลบ (รันบน index pool).
+/// chunk หลายตัวของรีวิวเดียวกันรวมเป็น hit เดียวตาม `aggregate`
+fn search_hits(
+    state: &AppState,
+    qvec: &[f32],
+    ann_k: usize,
+    ef: Option<usize>,
+    aggregate: ChunkAggregate,
+) -> Result<Vec<SearchHit>, (StatusCode, String)> {
     // เรียกค้นหา: ได้ (ids, scores)
     let (ids, scores) = state
         .index
         .read()
         .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "index lock poisoned".into()))?
-        .search(&qvec, ann_k)
+        .search_ef(qvec, ann_k, ef)
         .map_err(|e| {
             error!("index search error: {:?}", e);
             (
@@ -373,12 +637,26 @@ pub async fn search_handler(
             )
         })?;
 
-    // โหลด metadata ทั้งหมด (ยังคงใช้ไฟล์ jsonl เดิม)
-    let p = state
-        .paths
+    let store = state
+        .reviews
         .read()
-        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "paths lock poisoned".into()))?;
-    let reviews = load_all_reviews(&p.jsonl_path).map_err(|e| {
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "reviews lock poisoned".into()))?;
+    let deleted = state
+        .deleted
+        .read()
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "deleted lock poisoned".into()))?;
+
+    // อ่านเฉพาะรีวิวที่ index คืนมา
+    let hits: Vec<(usize, f32)> = ids
+        .into_iter()
+        .zip(scores)
+        .filter_map(|(vid_i64, score)| {
+            let vid: usize = usize::try_from(vid_i64).ok()?;
+            (!deleted.contains(&vid)).then_some((vid, score))
+        })
+        .collect();
+    let vids: Vec<usize> = hits.iter().map(|&(vid, _)| vid).collect();
+    let rows = store.get_many(&vids).map_err(|e| {
         error!("read metadata error: {:?}", e);
         (
             StatusCode::INTERNAL_SERVER_ERROR,
@@ -386,27 +664,857 @@ pub async fn search_handler(
         )
     })?;
 
-    // map vector_id -> review
-    let by_vec: HashMap<usize, &crate::types::StoredReview> =
-        reviews.iter().map(|r| (r.vector_id, r)).collect();
+    // รวมตามแถว (vector_id แรก) เรียงตามลำดับที่ index คืนมา
+    struct Group {
+        review: StoredReview,
+        best: (usize, f32),
+        sum: f32,
+        matched: usize,
+    }
+    let mut groups: Vec<Group> = Vec::new();
+    let mut by_row: HashMap<usize, usize> = HashMap::new();
+    for (row, (vid, score)) in rows.into_iter().zip(hits) {
+        let Some(review) = row else { continue };
+        let chunk = vid - review.vector_id;
+        match by_row.get(&review.vector_id) {
+            Some(&g) => {
+                let g = &mut groups[g];
+                if score > g.best.1 {
+                    g.best = (chunk, score);
+                }
+                g.sum += score;
+                g.matched += 1;
+            }
+            None => {
+                by_row.insert(review.vector_id, groups.len());
+                groups.push(Group {
+                    review,
+                    best: (chunk, score),
+                    sum: score,
+                    matched: 1,
+                });
+            }
+        }
+    }
 
-    // รวมผลลัพธ์
-    let mut out: Vec<SearchHit> = ids
+    Ok(groups
         .into_iter()
-        .zip(scores.into_iter())
-        .filter_map(|(vid_i64, score)| {
-            let vid: usize = usize::try_from(vid_i64).ok()?;
-            by_vec.get(&vid).map(|r| SearchHit {
-                review: (*r).clone(),
+        .map(|g| {
+            let score = match aggregate {
+                ChunkAggregate::Max => g.best.1,
+                ChunkAggregate::Mean => g.sum / g.matched as f32,
+            };
+            let chunk = (!g.review.chunks.is_empty()).then(|| {
+                let (index, chunk_score) = g.best;
+                let text = g
+                    .review
+                    .chunks
+                    .get(index)
+                    .and_then(|&[s, e]| g.review.review.get(s..e))
+                    .unwrap_or_default()
+                    .to_string();
+                ChunkMatch {
+                    index,
+                    count: g.review.chunks.len(),
+                    matched: g.matched,
+                    score: chunk_score,
+                    text,
+                }
+            });
+            SearchHit {
+                review: g.review,
                 score,
-            })
+                chunk,
+            }
         })
-        .collect();
+        .collect())
+}
 
-    out.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(Ordering::Equal));
-    if out.len() > TOP_N {
-        out.truncate(TOP_N);
+/// แถวล่าสุดของ `review_id` ถ้ายังไม่ถูกลบ
+fn find_current_review(
+    state: &AppState,
+    review_id: &str,
+) -> Result<StoredReview, (StatusCode, String)> {
+    let store = state
+        .reviews
+        .read()
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "reviews lock poisoned".into()))?;
+    let deleted = state
+        .deleted
+        .read()
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "deleted lock poisoned".into()))?;
+    store
+        .current(review_id)
+        .map_err(|e| {
+            error!("read metadata error: {:?}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "read metadata failed".to_string(),
+            )
+        })?
+        .filter(|r| !deleted.contains(&r.vector_id))
+        .ok_or((StatusCode::NOT_FOUND, "review not found".to_string()))
+}
+
+/// ลบทุก vector (chunk) ของแถวออกจากผลค้นหา: tombstone ลง deletions.jsonl → deleted set → index
+fn tombstone_vectors(
+    state: &AppState,
+    jsonl_path: &str,
+    row: &StoredReview,
+    superseded_by: Option<usize>,
+) -> Result<(), (StatusCode, String)> {
+    // 1) บันทึก tombstone ก่อน (เป็น source of truth)
+    for vector_id in row.vector_ids() {
+        let tombstone = Tombstone {
+            review_id: row.id.clone(),
+            vector_id,
+            deleted_at: Utc::now(),
+            superseded_by,
+        };
+        append_tombstone_line(&deletions_path(jsonl_path), &tombstone).map_err(|e| {
+            error!("write deletions error: {:?}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "deletion log write failed".to_string(),
+            )
+        })?;
     }
 
-    Ok(Json(SearchResponse { hits: out }))
+    // 2) search ข้าม vector เหล่านี้ทันที
+    state
+        .deleted
+        .write()
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "deleted lock poisoned".into()))?
+        .extend(row.vector_ids());
+
+    // 3) tombstone ใน index
+    let ids: Vec<i64> = row.vector_ids().map(|v| v as i64).collect();
+    let mut idx = state
+        .index
+        .write()
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "index lock poisoned".into()))?;
+    idx.delete(&ids).map_err(|e| {
+        error!("index delete error: {:?}", e);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "index delete failed".to_string(),
+        )
+    })?;
+    flush_index_if_due(state, &mut **idx, ids.len())
+}
+
+// ---- Delete ----
+pub async fn delete_review_handler(
+    State(state): State<AppState>,
+    AxumPath(review_id): AxumPath<String>,
+) -> Result<Json<StoredReview>, (StatusCode, String)> {
+    let st = state.clone();
+    let deleted = state
+        .index_pool
+        .run(move || delete_review(&st, &review_id))
+        .await??;
+    Ok(Json(deleted))
+}
+
+fn delete_review(state: &AppState, review_id: &str) -> Result<StoredReview, (StatusCode, String)> {
+    let _writes = write_guard(state)?;
+    let p = state
+        .paths
+        .read()
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "paths lock poisoned".into()))?
+        .clone();
+
+    let current = find_current_review(state, review_id)?;
+    tombstone_vectors(state, &p.jsonl_path, &current, None)?;
+
+    Ok(current)
+}
+
+// ---- Update (PUT = แทนที่ทั้งก้อน, PATCH = เฉพาะ field ที่ส่งมา) ----
+pub async fn put_review_handler(
+    State(state): State<AppState>,
+    AxumPath(review_id): AxumPath<String>,
+    Json(payload): Json<ReviewInput>,
+) -> Result<Json<StoredReview>, (StatusCode, String)> {
+    let stored = update_review(&state, review_id, |_| payload).await?;
+    Ok(Json(stored))
+}
+
+pub async fn patch_review_handler(
+    State(state): State<AppState>,
+    AxumPath(review_id): AxumPath<String>,
+    Json(patch): Json<ReviewPatch>,
+) -> Result<Json<StoredReview>, (StatusCode, String)> {
+    let stored = update_review(&state, review_id, move |current| patch.apply_to(current)).await?;
+    Ok(Json(stored))
+}
+
+/// append เวอร์ชันใหม่ของรีวิว (id เดิม). ถ้าข้อความเปลี่ยน: embed ใหม่ผ่าน embed pool
+/// (ก่อนเข้า index pool / write gate) แล้ว `commit_update` บน index pool
+async fn update_review(
+    state: &AppState,
+    review_id: String,
+    build: impl FnOnce(&StoredReview) -> ReviewInput,
+) -> Result<StoredReview, (StatusCode, String)> {
+    ensure_writable()?;
+    let st = state.clone();
+    let id = review_id.clone();
+    let current = state
+        .index_pool
+        .run(move || find_current_review(&st, &id))
+        .await??;
+    let input = build(&current);
+    input
+        .validate()
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+
+    // ข้อความเดิม => ใช้ vector (และ chunk) เดิมต่อ ไม่ต้อง embed
+    let embedded = if input.review == current.review {
+        None
+    } else {
+        let spans = state.chunker.spans(&input.review);
+        let vectors = state
+            .embed_pool
+            .embed_documents(chunk::texts(&input.review, &spans))
+            .await?;
+        Some((spans, vectors))
+    };
+
+    let st = state.clone();
+    state
+        .index_pool
+        .run(move || commit_update(&st, &review_id, current, input, embedded))
+        .await?
+}
+
+/// chunk span + เวกเตอร์ของข้อความใหม่ (embed แล้วนอก index pool)
+type Embedded = (Vec<[usize; 2]>, Vec<Vec<f32>>);
+
+/// เขียนเวอร์ชันใหม่ที่ `update_review` เตรียมไว้จาก `current` (รันบน index pool).
+/// ระหว่าง embed มีคนแก้ / ลบรีวิวนี้ไปก่อน => 409 / 404 ไม่เขียนทับ
+fn commit_update(
+    state: &AppState,
+    review_id: &str,
+    current: StoredReview,
+    input: ReviewInput,
+    embedded: Option<Embedded>,
+) -> Result<StoredReview, (StatusCode, String)> {
+    let _writes = write_guard(state)?;
+    let p = state
+        .paths
+        .read()
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "paths lock poisoned".into()))?
+        .clone();
+
+    let latest = find_current_review(state, review_id)?;
+    let unchanged = latest.vector_id == current.vector_id
+        && latest.review == current.review
+        && latest.rating == current.rating
+        && latest.category == current.category;
+    if !unchanged {
+        return Err((
+            StatusCode::CONFLICT,
+            "review was modified concurrently; retry".into(),
+        ));
+    }
+
+    let Some((spans, vectors)) = embedded else {
+        let stored = StoredReview::revision_of(&current, input, current.vector_id)
+            .with_chunks(current.chunks.clone());
+        let loc = append_review_line(&p.jsonl_path, &stored).map_err(|e| {
+            error!("write metadata error: {:?}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "metadata write failed".to_string(),
+            )
+        })?;
+        state
+            .reviews
+            .write()
+            .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "reviews lock poisoned".into()))?
+            .insert(stored.clone(), loc);
+        return Ok(stored);
+    };
+
+    // จอง vector_id ใหม่ (หนึ่งค่าต่อ chunk)
+    let vector_id = reserve_vector_ids(state, vectors.len())?;
+
+    let stored = StoredReview::revision_of(&current, input, vector_id).with_chunks(spans);
+    let stored = commit_rows(state, &p, vec![stored], vectors)?.remove(0);
+
+    // เวอร์ชันใหม่เขียนครบแล้ว ค่อยปลด vector เก่า (ทุก chunk). ล้มตรงนี้ไม่ทำให้ update ล้ม:
+    // revision ใหม่ commit ไปแล้ว (ตอบ 500 => client retry สร้าง revision ที่สาม) — vector เก่าที่ค้าง
+    // verify รายงานเป็น stale_vectors และ repair ปลดให้
+    if let Err((_, e)) = tombstone_vectors(state, &p.jsonl_path, &current, Some(vector_id)) {
+        warn!("update {review_id}: old vectors of {} left searchable ({e}); run repair", current.vector_id);
+    }
+
+    Ok(stored)
+}
+
+// ---- Admin: rebuild index จาก reviews.jsonl ----
+// POST /admin/rebuild — เริ่ม rebuild เบื้องหลัง (202); ซ้ำระหว่างรันอยู่ => 409
+pub async fn start_rebuild_handler(
+    State(state): State<AppState>,
+) -> Result<(StatusCode, Json<RebuildStatus>), (StatusCode, String)> {
+    ensure_writable()?;
+    let status = {
+        let mut r = state
+            .rebuild
+            .write()
+            .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "rebuild lock poisoned".into()))?;
+        if r.running {
+            return Err((StatusCode::CONFLICT, "index rebuild already running".into()));
+        }
+        *r = RebuildStatus {
+            running: true,
+            started_at: Some(Utc::now()),
+            ..Default::default()
+        };
+        r.clone()
+    };
+
+    let bg = state.clone();
+    tokio::task::spawn_blocking(move || {
+        let result = rebuild::rebuild_live(&bg);
+        if let Err(e) = &result {
+            error!("index rebuild failed: {e:?}");
+        }
+        if let Ok(mut r) = bg.rebuild.write() {
+            r.running = false;
+            r.finished_at = Some(Utc::now());
+            match result {
+                Ok(n) => info!("index rebuild done: {n} vector(s)"),
+                Err(e) => r.error = Some(format!("{e:#}")),
+            }
+        }
+    });
+
+    Ok((StatusCode::ACCEPTED, Json(status)))
+}
+
+// GET /admin/rebuild
+pub async fn rebuild_status_handler(
+    State(state): State<AppState>,
+) -> Result<Json<RebuildStatus>, (StatusCode, String)> {
+    let r = state
+        .rebuild
+        .read()
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "rebuild lock poisoned".into()))?;
+    Ok(Json(r.clone()))
+}
+
+// ---- Admin: cache ของ query embedding ----
+// GET /admin/query-cache
+pub async fn query_cache_stats_handler(State(state): State<AppState>) -> Json<QueryCacheStats> {
+    Json(state.query_cache.stats())
+}
+
+// DELETE /admin/query-cache — ล้างรายการ (ตัวนับ hit/miss คงไว้)
+pub async fn clear_query_cache_handler(State(state): State<AppState>) -> Json<QueryCacheStats> {
+    state.query_cache.clear();
+    Json(state.query_cache.stats())
+}
+
+// ---- Admin: ตรวจ/ซ่อมความสอดคล้องของ data directory ----
+// GET /admin/verify
+pub async fn verify_handler(
+    State(state): State<AppState>,
+) -> Result<Json<VerifyReport>, (StatusCode, String)> {
+    tokio::task::spawn_blocking(move || verify::verify_live(&state))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("verify task failed: {e}")))?
+        .map(Json)
+        .map_err(|e| {
+            error!("verify error: {e:?}");
+            (StatusCode::INTERNAL_SERVER_ERROR, format!("verify failed: {e:#}"))
+        })
+}
+
+// POST /admin/repair
+pub async fn repair_handler(
+    State(state): State<AppState>,
+) -> Result<Json<RepairReport>, (StatusCode, String)> {
+    ensure_writable()?;
+    if state.rebuild.read().map(|r| r.running).unwrap_or(false) {
+        return Err((StatusCode::CONFLICT, "index rebuild in progress".into()));
+    }
+    tokio::task::spawn_blocking(move || verify::repair_live(&state))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("repair task failed: {e}")))?
+        .map(Json)
+        .map_err(|e| {
+            error!("repair error: {e:?}");
+            (StatusCode::INTERNAL_SERVER_ERROR, format!("repair failed: {e:#}"))
+        })
+}
+
+// ---- Admin: snapshot สำรองข้อมูลที่ใช้ได้แน่นอน ----
+// POST /admin/snapshot — body (optional): {"compress": false} = tar ไม่บีบอัด
+pub async fn snapshot_handler(
+    State(state): State<AppState>,
+    body: Option<Json<SnapshotRequest>>,
+) -> Result<Json<SnapshotReport>, (StatusCode, String)> {
+    ensure_writable()?;
+    let compress = body.and_then(|Json(b)| b.compress).unwrap_or(true);
+    tokio::task::spawn_blocking(move || snapshot::snapshot_live(&state, compress))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("snapshot task failed: {e}")))?
+        .map(Json)
+        .map_err(|e| {
+            error!("snapshot error: {e:?}");
+            (StatusCode::INTERNAL_SERVER_ERROR, format!("snapshot failed: {e:#}"))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::embedder::{HashEmbedder, Prefixes};
+    use crate::flat_index::FlatIndex;
+    use crate::index::IndexError;
+    use crate::pool::{BatchConfig, PoolConfig};
+    use crate::storage::load_all_reviews;
+    use std::fs;
+    use std::path::Path;
+    use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    const DIM: usize = 64;
+    const NO_CHUNKS: Chunker = Chunker {
+        words: 0,
+        overlap: 0,
+    };
+
+    fn paths_in(dir: &TempDir) -> Paths {
+        let p = |name: &str| dir.path().join(name).to_string_lossy().into_owned();
+        Paths {
+            index_path: p("reviews.index"),
+            jsonl_path: p("reviews.jsonl"),
+            map_path: p("vector_map.jsonl"),
+        }
+    }
+
+    /// AppState ของชุดข้อมูลว่างใน `dir` (HashEmbedder, save + checkpoint ทุก insert)
+    fn test_state(dir: &TempDir, index: Box<dyn VectorIndex>, chunker: Chunker) -> AppState {
+        let paths = paths_in(dir);
+        let (wal, pending) = Wal::open(Path::new(&wal_path(&paths.jsonl_path))).unwrap();
+        assert!(pending.is_empty());
+        let reviews = ReviewStore::load(&paths.jsonl_path).unwrap();
+        let embedder: Arc<dyn Embed> = Arc::new(HashEmbedder::new(DIM, Prefixes::default()));
+        let embed_pool = EmbedPool::start(
+            embedder.clone(),
+            PoolConfig {
+                workers: 1,
+                queue: 16,
+            },
+            BatchConfig {
+                max_batch: 16,
+                max_wait: Duration::ZERO,
+            },
+        )
+        .unwrap();
+        let index_pool = BlockingPool::start(
+            "index",
+            PoolConfig {
+                workers: 2,
+                queue: 16,
+            },
+        )
+        .unwrap();
+        AppState {
+            index: Arc::new(RwLock::new(index)),
+            paths: Arc::new(RwLock::new(paths)),
+            next_vector_id: Arc::new(RwLock::new(0)),
+            unsaved_vectors: Arc::new(RwLock::new(0)),
+            flush_every: 1,
+            deleted: Arc::new(RwLock::new(HashSet::new())),
+            write_gate: Arc::new(RwLock::new(())),
+            rebuild: Arc::new(RwLock::new(Default::default())),
+            wal: Arc::new(Mutex::new(wal)),
+            reviews: Arc::new(RwLock::new(reviews)),
+            data_lock: Arc::new(Mutex::new(None)),
+            query_cache: Arc::new(QueryCache::new(embedder.model_id(), 16)),
+            embedder,
+            embed_pool: Arc::new(embed_pool),
+            index_pool: Arc::new(index_pool),
+            chunker,
+        }
+    }
+
+    fn flat_index(dir: &TempDir) -> FlatIndex {
+        FlatIndex::open(&dir.path().join("reviews.index.flat"), DIM).unwrap()
+    }
+
+    fn input(review: &str) -> ReviewInput {
+        ReviewInput {
+            review: review.into(),
+            rating: 4,
+            category: None,
+        }
+    }
+
+    /// FlatIndex ที่ `add_batch` ล้มเมื่อเปิด `fail` — จำลอง error หลังเขียน WAL แล้ว
+    struct FlakyIndex {
+        inner: FlatIndex,
+        fail: Arc<AtomicBool>,
+    }
+
+    impl VectorIndex for FlakyIndex {
+        fn dim(&self) -> usize {
+            self.inner.dim()
+        }
+        fn add_batch(&mut self, vectors: &[f32], ids: Option<&[i64]>) -> Result<(), IndexError> {
+            if self.fail.load(AtomicOrdering::SeqCst) {
+                return Err(IndexError::Corrupt("injected add_batch failure".into()));
+            }
+            self.inner.add_batch(vectors, ids)
+        }
+        fn search(&self, query: &[f32], topk: usize) -> Result<(Vec<i64>, Vec<f32>), IndexError> {
+            self.inner.search(query, topk)
+        }
+        fn delete(&mut self, ids: &[i64]) -> Result<(), IndexError> {
+            self.inner.delete(ids)
+        }
+        fn ids(&self) -> Result<Vec<i64>, IndexError> {
+            self.inner.ids()
+        }
+        fn save(&mut self) -> Result<(), IndexError> {
+            self.inner.save()
+        }
+    }
+
+    fn wal_file(state: &AppState) -> String {
+        wal_path(&state.paths.read().unwrap().jsonl_path)
+    }
+
+    #[tokio::test]
+    async fn failed_index_add_is_aborted_in_wal() {
+        let dir = TempDir::new().unwrap();
+        let fail = Arc::new(AtomicBool::new(true));
+        let index = FlakyIndex {
+            inner: flat_index(&dir),
+            fail: fail.clone(),
+        };
+        let state = test_state(&dir, Box::new(index), NO_CHUNKS);
+
+        let err = insert_review_handler(State(state.clone()), Json(input("lost review")))
+            .await
+            .unwrap_err();
+        assert_eq!(err.0, StatusCode::INTERNAL_SERVER_ERROR);
+
+        // record อยู่ใน WAL แต่มี abort marker => เปิดใหม่แล้วไม่มีอะไรให้ replay
+        let (_, records) = Wal::open(Path::new(&wal_file(&state))).unwrap();
+        assert!(records.is_empty());
+
+        // record นั้น settle แล้ว: insert ถัดไป save + checkpoint ล้าง WAL ได้
+        fail.store(false, AtomicOrdering::SeqCst);
+        let kept = insert_review_handler(State(state.clone()), Json(input("kept review")))
+            .await
+            .unwrap()
+            .0;
+        assert_eq!(std::fs::metadata(wal_file(&state)).unwrap().len(), 0);
+
+        let rows = load_all_reviews(&state.paths.read().unwrap().jsonl_path).unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].id, kept.id);
+        assert_eq!(state.reviews.read().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn failed_map_write_undoes_index_add() {
+        let dir = TempDir::new().unwrap();
+        let state = test_state(&dir, Box::new(flat_index(&dir)), NO_CHUNKS);
+        // vector_map.jsonl เป็นไดเรกทอรี => append ล้มหลัง index add สำเร็จแล้ว
+        let map_path = state.paths.read().unwrap().map_path.clone();
+        std::fs::create_dir(&map_path).unwrap();
+
+        let err = insert_review_handler(State(state.clone()), Json(input("half written")))
+            .await
+            .unwrap_err();
+        assert_eq!(err.0, StatusCode::INTERNAL_SERVER_ERROR);
+
+        assert!(state.index.read().unwrap().ids().unwrap().is_empty());
+        assert_eq!(state.reviews.read().unwrap().len(), 0);
+        let (_, records) = Wal::open(Path::new(&wal_file(&state))).unwrap();
+        assert!(records.is_empty());
+
+        assert!(search(&state, "half written", 5).await.is_empty());
+    }
+
+    async fn search(state: &AppState, query: &str, top_k: usize) -> Vec<SearchHit> {
+        search_handler(
+            State(state.clone()),
+            Json(SearchRequest {
+                query: query.into(),
+                top_k: Some(top_k),
+                ef: None,
+                aggregate: None,
+            }),
+        )
+        .await
+        .unwrap()
+        .0
+        .hits
+    }
+
+    const REVIEWS: [&str; 4] = [
+        "the pasta was cold and the waiter ignored us",
+        "great espresso and friendly baristas every morning",
+        "parking is impossible downtown on weekends",
+        "best sushi rolls in town with fresh salmon",
+    ];
+
+    #[tokio::test]
+    async fn inserted_reviews_are_found_by_search() {
+        let dir = TempDir::new().unwrap();
+        let state = test_state(&dir, Box::new(flat_index(&dir)), NO_CHUNKS);
+        let mut stored = Vec::new();
+        for text in REVIEWS {
+            let row = insert_review_handler(State(state.clone()), Json(input(text)))
+                .await
+                .unwrap()
+                .0;
+            assert_eq!(row.review, text);
+            assert_eq!(row.schema_version, crate::types::SCHEMA_VERSION);
+            stored.push(row);
+        }
+        let vids: Vec<usize> = stored.iter().map(|r| r.vector_id).collect();
+        assert_eq!(vids, [0, 1, 2, 3]);
+
+        for row in &stored {
+            // top_k กำหนดแค่จำนวน candidate จาก index; ตอบไม่เกิน 5 hit เสมอ
+            let hits = search(&state, &row.review, 2).await;
+            assert_eq!(hits.len(), REVIEWS.len());
+            assert_eq!(hits[0].review.id, row.id);
+            assert!(hits[0].score > 0.99, "score {}", hits[0].score);
+            assert!(hits[1].score < hits[0].score);
+        }
+        // คำร่วมกันบางส่วนก็พอ
+        assert_eq!(
+            search(&state, "fresh sushi", 1).await[0].review.id,
+            stored[3].id
+        );
+    }
+
+    #[tokio::test]
+    async fn bulk_insert_writes_every_row_and_is_searchable() {
+        let dir = TempDir::new().unwrap();
+        let state = test_state(&dir, Box::new(flat_index(&dir)), NO_CHUNKS);
+        let items = REVIEWS.iter().map(|t| input(t)).collect();
+        let stored = bulk_insert_handler(State(state.clone()), Json(BulkReviews(items)))
+            .await
+            .unwrap()
+            .0;
+        assert_eq!(stored.len(), REVIEWS.len());
+
+        let on_disk = load_all_reviews(&state.paths.read().unwrap().jsonl_path).unwrap();
+        assert_eq!(on_disk.len(), REVIEWS.len());
+        assert_eq!(
+            state.index.read().unwrap().ids().unwrap().len(),
+            REVIEWS.len()
+        );
+
+        for (row, text) in stored.iter().zip(REVIEWS) {
+            assert_eq!(row.review, text);
+            let hits = search(&state, text, 1).await;
+            assert_eq!(hits[0].review.id, row.id);
+        }
+        // top_k มากกว่าจำนวนรีวิว => ได้ทุกแถว ไม่ซ้ำ
+        let all = search(&state, "town", 10).await;
+        assert_eq!(all.len(), REVIEWS.len());
+    }
+
+    #[tokio::test]
+    async fn invalid_input_is_rejected_before_writing() {
+        let dir = TempDir::new().unwrap();
+        let state = test_state(&dir, Box::new(flat_index(&dir)), NO_CHUNKS);
+        let err = insert_review_handler(State(state.clone()), Json(input("   ")))
+            .await
+            .unwrap_err();
+        assert_eq!(err.0, StatusCode::BAD_REQUEST);
+
+        let items = vec![input("fine review"), input("")];
+        let err = bulk_insert_handler(State(state.clone()), Json(BulkReviews(items)))
+            .await
+            .unwrap_err();
+        assert_eq!(err.0, StatusCode::BAD_REQUEST);
+        assert_eq!(state.reviews.read().unwrap().len(), 0);
+        assert!(search(&state, "fine review", 5).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn deleted_reviews_drop_out_of_search() {
+        let dir = TempDir::new().unwrap();
+        let state = test_state(&dir, Box::new(flat_index(&dir)), NO_CHUNKS);
+        let items = REVIEWS.iter().map(|t| input(t)).collect();
+        let stored = bulk_insert_handler(State(state.clone()), Json(BulkReviews(items)))
+            .await
+            .unwrap()
+            .0;
+
+        let removed = delete_review_handler(State(state.clone()), AxumPath(stored[1].id.clone()))
+            .await
+            .unwrap()
+            .0;
+        assert_eq!(removed.id, stored[1].id);
+        let hits = search(&state, REVIEWS[1], 10).await;
+        assert_eq!(hits.len(), REVIEWS.len() - 1);
+        assert!(hits.iter().all(|h| h.review.id != stored[1].id));
+    }
+
+    #[tokio::test]
+    async fn updates_reembed_only_when_the_text_changes() {
+        let dir = TempDir::new().unwrap();
+        let state = test_state(&dir, Box::new(flat_index(&dir)), NO_CHUNKS);
+        let items = REVIEWS.iter().map(|t| input(t)).collect();
+        let stored = bulk_insert_handler(State(state.clone()), Json(BulkReviews(items)))
+            .await
+            .unwrap()
+            .0;
+        let target = &stored[0];
+
+        // PATCH rating อย่างเดียว => vector เดิม
+        let patch = ReviewPatch {
+            review: None,
+            rating: Some(1),
+            category: None,
+        };
+        let patched = patch_review_handler(
+            State(state.clone()),
+            AxumPath(target.id.clone()),
+            Json(patch),
+        )
+        .await
+        .unwrap()
+        .0;
+        assert_eq!((patched.vector_id, patched.rating), (target.vector_id, 1));
+        assert_eq!(
+            state.index.read().unwrap().ids().unwrap().len(),
+            REVIEWS.len()
+        );
+
+        // PUT ข้อความใหม่ => vector ใหม่, vector เก่าถูก tombstone
+        let text = "quiet library cafe with slow wifi";
+        let put = put_review_handler(
+            State(state.clone()),
+            AxumPath(target.id.clone()),
+            Json(input(text)),
+        )
+        .await
+        .unwrap()
+        .0;
+        assert_eq!(put.id, target.id);
+        assert_eq!(put.vector_id, REVIEWS.len());
+        assert!(state.deleted.read().unwrap().contains(&target.vector_id));
+
+        let hits = search(&state, text, 5).await;
+        assert_eq!(hits[0].review.id, target.id);
+        assert_eq!(hits[0].review.review, text);
+        let old = search(&state, REVIEWS[0], 5).await;
+        assert!(old.iter().all(|h| h.review.review != REVIEWS[0]));
+
+        let missing = put_review_handler(
+            State(state.clone()),
+            AxumPath("no-such-review".into()),
+            Json(input(text)),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(missing.0, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn failed_supersede_after_commit_still_returns_the_update() {
+        let dir = TempDir::new().unwrap();
+        let state = test_state(&dir, Box::new(flat_index(&dir)), NO_CHUNKS);
+        let target = insert_review_handler(State(state.clone()), Json(input(REVIEWS[0])))
+            .await
+            .unwrap()
+            .0;
+
+        // deletions.jsonl เขียนไม่ได้: tombstone ของเวอร์ชันเก่าล้มหลังเวอร์ชันใหม่ commit แล้ว
+        let deletions = dir.path().join("deletions.jsonl");
+        fs::create_dir(&deletions).unwrap();
+        let text = "quiet library cafe with slow wifi";
+        let put = put_review_handler(
+            State(state.clone()),
+            AxumPath(target.id.clone()),
+            Json(input(text)),
+        )
+        .await
+        .unwrap()
+        .0;
+        assert_ne!(put.vector_id, target.vector_id);
+        assert_eq!(state.reviews.read().unwrap().len(), 1);
+
+        // vector เก่าที่ค้างถูกรายงาน แล้ว repair ปลดให้
+        fs::remove_dir(&deletions).unwrap();
+        let report = verify::verify_live(&state).unwrap();
+        assert_eq!(report.stale_vectors, vec![target.vector_id]);
+        let repaired = verify::repair_live(&state).unwrap();
+        assert!(repaired.after.consistent);
+        assert!(state.deleted.read().unwrap().contains(&target.vector_id));
+    }
+
+    async fn search_with(
+        state: &AppState,
+        query: &str,
+        aggregate: ChunkAggregate,
+    ) -> Vec<SearchHit> {
+        search_handler(
+            State(state.clone()),
+            Json(SearchRequest {
+                query: query.into(),
+                top_k: Some(10),
+                ef: None,
+                aggregate: Some(aggregate),
+            }),
+        )
+        .await
+        .unwrap()
+        .0
+        .hits
+    }
+
+    #[tokio::test]
+    async fn chunk_hits_merge_into_one_hit_per_review() {
+        let dir = TempDir::new().unwrap();
+        let chunker = Chunker {
+            words: 4,
+            overlap: 1,
+        };
+        let state = test_state(&dir, Box::new(flat_index(&dir)), chunker);
+        let long = "crispy dumplings arrived hot then dessert menu offered mango sticky";
+        let items = vec![input(long), input("mango smoothie")];
+        let stored = bulk_insert_handler(State(state.clone()), Json(BulkReviews(items)))
+            .await
+            .unwrap()
+            .0;
+        // 3 chunk (ขั้นละ 3 คำ) => vector_id 0..3, รีวิวสั้นได้ 3
+        assert_eq!(stored[0].chunks.len(), 3);
+        assert_eq!(stored[1].vector_id, 3);
+        assert_eq!(state.index.read().unwrap().ids().unwrap().len(), 4);
+
+        let hits = search_with(&state, "offered mango sticky", ChunkAggregate::Max).await;
+        assert_eq!(hits.len(), 2);
+        assert_eq!(hits[0].review.id, stored[0].id);
+        let chunk = hits[0].chunk.as_ref().unwrap();
+        assert_eq!((chunk.index, chunk.count, chunk.matched), (2, 3, 3));
+        assert_eq!(chunk.text, "menu offered mango sticky");
+        assert_eq!(hits[0].score, chunk.score);
+        assert!(hits[1].chunk.is_none());
+
+        // mean ของ 3 chunk ต่ำกว่า chunk ที่ดีที่สุด
+        let mean = search_with(&state, "offered mango sticky", ChunkAggregate::Mean).await;
+        let merged = mean.iter().find(|h| h.review.id == stored[0].id).unwrap();
+        assert!(merged.score < hits[0].score);
+        assert_eq!(merged.chunk.as_ref().unwrap().index, 2);
+
+        // chunk แรกเท่านั้นที่มีคำนี้ => ยังคืนทั้งรีวิว
+        let first = search_with(&state, "crispy dumplings", ChunkAggregate::Max).await;
+        assert_eq!(first[0].review.review, long);
+        assert_eq!(first[0].chunk.as_ref().unwrap().index, 0);
+    }
 }
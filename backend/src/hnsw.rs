@@ -0,0 +1,700 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use tracing::{info, warn};
+
+use crate::index::{check_batch, dot, normalize, IndexError, VectorIndex};
+use crate::vector_log::VectorLog;
+
+const MAGIC: &[u8; 4] = b"RHNS";
+const VERSION: u32 = 1;
+/// upper bound for a node's level (keeps the on-disk `u8` safe)
+const MAX_LEVEL: usize = 16;
+
+/// Tunables for [`HnswIndex`] (env `HNSW_M`, `HNSW_EF_CONSTRUCTION`, `HNSW_EF_SEARCH`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HnswParams {
+    /// links per node on upper layers (layer 0 keeps `2 * m`)
+    pub m: usize,
+    pub ef_construction: usize,
+    /// default candidate list size at query time; overridable per request
+    pub ef_search: usize,
+}
+
+impl Default for HnswParams {
+    fn default() -> Self {
+        Self {
+            m: 16,
+            ef_construction: 200,
+            ef_search: 64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Cand {
+    dist: f32,
+    node: u32,
+}
+
+impl PartialEq for Cand {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+impl Eq for Cand {}
+impl PartialOrd for Cand {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for Cand {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.dist
+            .total_cmp(&other.dist)
+            .then(self.node.cmp(&other.node))
+    }
+}
+
+/// Hierarchical Navigable Small World graph (approximate cosine search).
+///
+/// Vectors live in an append-only [`VectorLog`] (`reviews.hnsw.vec`), so an
+/// insert is durable as soon as it is flushed. The graph itself is
+/// snapshotted to `reviews.hnsw` on `save`; nodes appended after the last
+/// snapshot are re-inserted into the graph when the index is opened.
+/// Deleted ids stay in the graph for navigation but never appear in results.
+pub struct HnswIndex {
+    graph_path: PathBuf,
+    dim: usize,
+    params: HnswParams,
+    ids: Vec<i64>,
+    /// unit-length vectors, `ids.len() * dim`
+    vectors: Vec<f32>,
+    /// `links[node][layer]`; a node's level is `links[node].len() - 1`
+    links: Vec<Vec<Vec<u32>>>,
+    entry: Option<u32>,
+    max_level: usize,
+    deleted: HashSet<i64>,
+    log: VectorLog,
+    /// graph changed since the last snapshot
+    dirty: bool,
+}
+
+impl HnswIndex {
+    /// `graph_path` is the snapshot file (e.g. `data/reviews.hnsw`); vectors go to `<graph_path>.vec`.
+    pub fn open(graph_path: &Path, dim: usize, params: HnswParams) -> Result<Self, IndexError> {
+        if params.m < 2 {
+            return Err(IndexError::InvalidParam("HNSW_M must be >= 2"));
+        }
+        let vec_path = graph_path.with_extension("hnsw.vec");
+        let (log, loaded) = VectorLog::open(&vec_path, dim)?;
+        let mut vectors = loaded.vectors;
+        vectors.chunks_exact_mut(dim).for_each(normalize);
+        // tombstones for ids that were never added would shrink the live count
+        let deleted = loaded
+            .ids
+            .iter()
+            .copied()
+            .filter(|id| loaded.deleted.contains(id))
+            .collect();
+
+        let mut index = Self {
+            graph_path: graph_path.to_path_buf(),
+            dim,
+            params,
+            ids: loaded.ids,
+            vectors,
+            links: Vec::new(),
+            entry: None,
+            max_level: 0,
+            deleted,
+            log,
+            dirty: false,
+        };
+
+        match index.load_graph() {
+            Ok(()) => {}
+            Err(e) => {
+                if graph_path.exists() {
+                    warn!("{}: ignoring graph snapshot ({e}); rebuilding", graph_path.display());
+                }
+                index.links.clear();
+                index.entry = None;
+                index.max_level = 0;
+                // เขียน snapshot ใหม่ตอน save แม้ index จะว่าง
+                index.dirty = true;
+            }
+        }
+
+        let covered = index.links.len();
+        let total = index.ids.len();
+        if covered < total {
+            info!("hnsw: inserting {} node(s) not in snapshot", total - covered);
+            for node in covered..total {
+                index.insert_node(node as u32);
+            }
+            index.dirty = true;
+        }
+        Ok(index)
+    }
+
+    fn vec(&self, node: u32) -> &[f32] {
+        let start = node as usize * self.dim;
+        &self.vectors[start..start + self.dim]
+    }
+
+    fn dist(&self, q: &[f32], node: u32) -> f32 {
+        1.0 - dot(q, self.vec(node))
+    }
+
+    fn max_links(&self, layer: usize) -> usize {
+        if layer == 0 {
+            self.params.m * 2
+        } else {
+            self.params.m
+        }
+    }
+
+    /// Deterministic level from the node number, so rebuilding the graph
+    /// from the same vectors gives the same layout.
+    fn random_level(&self, node: u32) -> usize {
+        // splitmix64
+        let mut z = (node as u64).wrapping_add(0x9E37_79B9_7F4A_7C15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+        let u = (z >> 11) as f64 / (1u64 << 53) as f64;
+        let ml = 1.0 / (self.params.m as f64).ln();
+        ((-(1.0 - u).ln() * ml).floor() as usize).min(MAX_LEVEL)
+    }
+
+    /// Best-first search on one layer; returns up to `ef` candidates, closest first.
+    fn search_layer(&self, q: &[f32], entries: &[u32], ef: usize, layer: usize) -> Vec<Cand> {
+        let mut visited: HashSet<u32> = entries.iter().copied().collect();
+        let mut candidates: BinaryHeap<Reverse<Cand>> = BinaryHeap::new();
+        let mut results: BinaryHeap<Cand> = BinaryHeap::new();
+        for &e in entries {
+            let c = Cand {
+                dist: self.dist(q, e),
+                node: e,
+            };
+            candidates.push(Reverse(c));
+            results.push(c);
+        }
+        while results.len() > ef {
+            results.pop();
+        }
+
+        while let Some(Reverse(c)) = candidates.pop() {
+            let worst = results.peek().map_or(f32::INFINITY, |r| r.dist);
+            if c.dist > worst && results.len() >= ef {
+                break;
+            }
+            for &nb in &self.links[c.node as usize][layer] {
+                if !visited.insert(nb) {
+                    continue;
+                }
+                let d = self.dist(q, nb);
+                let worst = results.peek().map_or(f32::INFINITY, |r| r.dist);
+                if results.len() < ef || d < worst {
+                    let cand = Cand { dist: d, node: nb };
+                    candidates.push(Reverse(cand));
+                    results.push(cand);
+                    if results.len() > ef {
+                        results.pop();
+                    }
+                }
+            }
+        }
+        results.into_sorted_vec()
+    }
+
+    /// Neighbour selection heuristic (HNSW paper, alg. 4) topped up with the
+    /// closest pruned candidates so nodes keep `m` links where possible.
+    fn select_neighbors(&self, sorted: &[Cand], m: usize) -> Vec<u32> {
+        let mut out: Vec<u32> = Vec::with_capacity(m);
+        for c in sorted {
+            if out.len() >= m {
+                break;
+            }
+            let diverse = out
+                .iter()
+                .all(|&r| 1.0 - dot(self.vec(c.node), self.vec(r)) > c.dist);
+            if diverse {
+                out.push(c.node);
+            }
+        }
+        for c in sorted {
+            if out.len() >= m {
+                break;
+            }
+            if !out.contains(&c.node) {
+                out.push(c.node);
+            }
+        }
+        out
+    }
+
+    fn insert_node(&mut self, node: u32) {
+        let level = self.random_level(node);
+        self.links.push(vec![Vec::new(); level + 1]);
+
+        let Some(mut ep) = self.entry else {
+            self.entry = Some(node);
+            self.max_level = level;
+            return;
+        };
+
+        let q = self.vec(node).to_vec();
+        for layer in (level + 1..=self.max_level).rev() {
+            ep = self.search_layer(&q, &[ep], 1, layer)[0].node;
+        }
+
+        let mut eps = vec![ep];
+        for layer in (0..=level.min(self.max_level)).rev() {
+            let found = self.search_layer(&q, &eps, self.params.ef_construction, layer);
+            let neighbors = self.select_neighbors(&found, self.params.m);
+            let cap = self.max_links(layer);
+            for &nb in &neighbors {
+                self.links[nb as usize][layer].push(node);
+                if self.links[nb as usize][layer].len() > cap {
+                    self.shrink(nb, layer, cap);
+                }
+            }
+            self.links[node as usize][layer] = neighbors;
+            eps = found.iter().map(|c| c.node).collect();
+        }
+
+        if level > self.max_level {
+            self.max_level = level;
+            self.entry = Some(node);
+        }
+    }
+
+    fn shrink(&mut self, node: u32, layer: usize, cap: usize) {
+        let base = self.vec(node);
+        let mut cands: Vec<Cand> = self.links[node as usize][layer]
+            .iter()
+            .map(|&n| Cand {
+                dist: self.dist(base, n),
+                node: n,
+            })
+            .collect();
+        cands.sort();
+        let kept = self.select_neighbors(&cands, cap);
+        self.links[node as usize][layer] = kept;
+    }
+
+    fn load_graph(&mut self) -> Result<(), IndexError> {
+        let file = File::open(&self.graph_path)?;
+        let mut r = BufReader::new(file);
+
+        let mut magic = [0u8; 4];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(IndexError::Corrupt("bad magic".into()));
+        }
+        let version = read_u32(&mut r)?;
+        if version != VERSION {
+            return Err(IndexError::Corrupt(format!("unsupported version {version}")));
+        }
+        let dim = read_u32(&mut r)? as usize;
+        let m = read_u32(&mut r)? as usize;
+        let ef_construction = read_u32(&mut r)? as usize;
+        if dim != self.dim || m != self.params.m || ef_construction != self.params.ef_construction {
+            return Err(IndexError::Corrupt(format!(
+                "built with dim={dim} M={m} ef_construction={ef_construction}, configured dim={} M={} ef_construction={}",
+                self.dim, self.params.m, self.params.ef_construction
+            )));
+        }
+        let n = read_u64(&mut r)? as usize;
+        if n > self.ids.len() {
+            return Err(IndexError::Corrupt(format!(
+                "snapshot has {n} nodes but vector log only {}",
+                self.ids.len()
+            )));
+        }
+        let entry = read_u64(&mut r)?;
+        let max_level = read_u32(&mut r)? as usize;
+
+        let mut links = Vec::with_capacity(n);
+        for _ in 0..n {
+            let mut lvl = [0u8; 1];
+            r.read_exact(&mut lvl)?;
+            let mut layers = Vec::with_capacity(lvl[0] as usize + 1);
+            for _ in 0..=lvl[0] {
+                let cnt = read_u32(&mut r)? as usize;
+                let mut nbs = Vec::with_capacity(cnt);
+                for _ in 0..cnt {
+                    let nb = read_u32(&mut r)?;
+                    if nb as usize >= n {
+                        return Err(IndexError::Corrupt(format!("link to unknown node {nb}")));
+                    }
+                    nbs.push(nb);
+                }
+                layers.push(nbs);
+            }
+            links.push(layers);
+        }
+        // like the neighbour ids: a bad entry point or level would index out of bounds in `knn`
+        if n > 0 && links.get(entry as usize).map(Vec::len) != Some(max_level + 1) {
+            return Err(IndexError::Corrupt(format!(
+                "entry point {entry} is not a level-{max_level} node"
+            )));
+        }
+        for (node, layers) in links.iter().enumerate() {
+            if layers.len() > max_level + 1 {
+                return Err(IndexError::Corrupt(format!("node {node} is above level {max_level}")));
+            }
+            for (layer, nbs) in layers.iter().enumerate() {
+                if nbs.iter().any(|&nb| links[nb as usize].len() <= layer) {
+                    return Err(IndexError::Corrupt(format!(
+                        "node {node} links to a node below layer {layer}"
+                    )));
+                }
+            }
+        }
+
+        self.entry = if n == 0 { None } else { Some(entry as u32) };
+        self.max_level = max_level;
+        self.links = links;
+        Ok(())
+    }
+
+    fn write_graph(&self, path: &Path) -> io::Result<()> {
+        let mut w = BufWriter::new(File::create(path)?);
+        w.write_all(MAGIC)?;
+        w.write_all(&VERSION.to_le_bytes())?;
+        w.write_all(&(self.dim as u32).to_le_bytes())?;
+        w.write_all(&(self.params.m as u32).to_le_bytes())?;
+        w.write_all(&(self.params.ef_construction as u32).to_le_bytes())?;
+        w.write_all(&(self.links.len() as u64).to_le_bytes())?;
+        w.write_all(&(self.entry.unwrap_or(0) as u64).to_le_bytes())?;
+        w.write_all(&(self.max_level as u32).to_le_bytes())?;
+        for layers in &self.links {
+            w.write_all(&[(layers.len() - 1) as u8])?;
+            for nbs in layers {
+                w.write_all(&(nbs.len() as u32).to_le_bytes())?;
+                for nb in nbs {
+                    w.write_all(&nb.to_le_bytes())?;
+                }
+            }
+        }
+        w.flush()?;
+        w.get_ref().sync_all()
+    }
+
+    fn knn(
+        &self,
+        query: &[f32],
+        topk: usize,
+        ef: Option<usize>,
+    ) -> Result<(Vec<i64>, Vec<f32>), IndexError> {
+        if query.len() != self.dim {
+            return Err(IndexError::InvalidParam("query dim mismatch"));
+        }
+        let Some(mut ep) = self.entry else {
+            return Ok((Vec::new(), Vec::new()));
+        };
+        if topk == 0 {
+            return Ok((Vec::new(), Vec::new()));
+        }
+        let mut q = query.to_vec();
+        normalize(&mut q);
+
+        for layer in (1..=self.max_level).rev() {
+            ep = self.search_layer(&q, &[ep], 1, layer)[0].node;
+        }
+        // เผื่อ candidate สำหรับ node ที่ถูกลบ (ยังอยู่ในกราฟ)
+        let want = topk + self.deleted.len().min(topk);
+        let mut ef = ef.unwrap_or(self.params.ef_search).max(want);
+        let live = self.ids.len().saturating_sub(self.deleted.len());
+        loop {
+            let hits: Vec<Cand> = self
+                .search_layer(&q, &[ep], ef, 0)
+                .into_iter()
+                .filter(|c| !self.deleted.contains(&self.ids[c.node as usize]))
+                .take(topk)
+                .collect();
+            // node ที่ถูกลบกินที่ใน candidate list จนได้ไม่ครบ topk: ขยาย ef จนครบหรือครอบทั้งกราฟ
+            if hits.len() >= topk.min(live) || ef >= self.ids.len() {
+                return Ok(hits
+                    .into_iter()
+                    .map(|c| (self.ids[c.node as usize], 1.0 - c.dist))
+                    .unzip());
+            }
+            ef = (ef * 2).min(self.ids.len());
+        }
+    }
+}
+
+impl VectorIndex for HnswIndex {
+    fn dim(&self) -> usize {
+        self.dim
+    }
+
+    fn add_batch(&mut self, vectors: &[f32], ids: Option<&[i64]>) -> Result<(), IndexError> {
+        let n = check_batch(self.dim, vectors, ids)?;
+        if n == 0 {
+            return Ok(());
+        }
+        let first_auto = self.ids.iter().copied().max().map_or(0, |m| m + 1);
+
+        // เขียนลง vector log ก่อน แล้วค่อยต่อกราฟในหน่วยความจำ
+        for (i, v) in vectors.chunks_exact(self.dim).enumerate() {
+            let id = ids.map_or(first_auto + i as i64, |s| s[i]);
+            self.log.append(id, v)?;
+        }
+        self.log.flush()?;
+
+        for (i, v) in vectors.chunks_exact(self.dim).enumerate() {
+            let id = ids.map_or(first_auto + i as i64, |s| s[i]);
+            let node = self.ids.len() as u32;
+            self.ids.push(id);
+            let start = self.vectors.len();
+            self.vectors.extend_from_slice(v);
+            normalize(&mut self.vectors[start..]);
+            self.insert_node(node);
+        }
+        self.dirty = true;
+        Ok(())
+    }
+
+    fn search(&self, query: &[f32], topk: usize) -> Result<(Vec<i64>, Vec<f32>), IndexError> {
+        self.knn(query, topk, None)
+    }
+
+    fn search_ef(
+        &self,
+        query: &[f32],
+        topk: usize,
+        ef: Option<usize>,
+    ) -> Result<(Vec<i64>, Vec<f32>), IndexError> {
+        self.knn(query, topk, ef)
+    }
+
+    fn delete(&mut self, ids: &[i64]) -> Result<(), IndexError> {
+        if ids.is_empty() {
+            return Ok(());
+        }
+        // only ids in the graph: callers also tombstone ids that never made it in
+        let gone: HashSet<i64> = ids
+            .iter()
+            .copied()
+            .filter(|id| !self.deleted.contains(id))
+            .collect();
+        let known: Vec<i64> = self.ids.iter().copied().filter(|id| gone.contains(id)).collect();
+        if known.is_empty() {
+            return Ok(());
+        }
+        self.log.delete(&known)?;
+        self.deleted.extend(known);
+        Ok(())
+    }
+
+    fn ids(&self) -> Result<Vec<i64>, IndexError> {
+        Ok(self
+            .ids
+            .iter()
+            .copied()
+            .filter(|id| !self.deleted.contains(id))
+            .collect())
+    }
+
+    fn save(&mut self) -> Result<(), IndexError> {
+        self.log.sync()?;
+        if !self.dirty {
+            return Ok(());
+        }
+        // เขียนไฟล์ชั่วคราวแล้ว rename ทับ เพื่อไม่ให้ snapshot เสียครึ่ง ๆ
+        let tmp = self.graph_path.with_extension("hnsw.tmp");
+        self.write_graph(&tmp)?;
+        fs::rename(&tmp, &self.graph_path)?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+fn read_u32(r: &mut impl Read) -> io::Result<u32> {
+    let mut b = [0u8; 4];
+    r.read_exact(&mut b)?;
+    Ok(u32::from_le_bytes(b))
+}
+
+fn read_u64(r: &mut impl Read) -> io::Result<u64> {
+    let mut b = [0u8; 8];
+    r.read_exact(&mut b)?;
+    Ok(u64::from_le_bytes(b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    const DIM: usize = 32;
+
+    /// เวกเตอร์สุ่มแบบกำหนดได้ (splitmix64 → [-1, 1))
+    fn random_vectors(n: usize, seed: u64) -> Vec<f32> {
+        let mut z = seed;
+        (0..n * DIM)
+            .map(|_| {
+                z = z.wrapping_add(0x9E37_79B9_7F4A_7C15);
+                let mut x = z;
+                x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+                x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+                x ^= x >> 31;
+                (x >> 11) as f32 / (1u64 << 53) as f32 * 2.0 - 1.0
+            })
+            .collect()
+    }
+
+    /// top-k จริงแบบ brute force (ข้าม `skip`)
+    fn exact(data: &[f32], q: &[f32], k: usize, skip: &HashSet<i64>) -> Vec<i64> {
+        let mut q = q.to_vec();
+        normalize(&mut q);
+        let mut scored: Vec<(f32, i64)> = data
+            .chunks_exact(DIM)
+            .enumerate()
+            .filter(|(i, _)| !skip.contains(&(*i as i64)))
+            .map(|(i, v)| {
+                let mut v = v.to_vec();
+                normalize(&mut v);
+                (dot(&q, &v), i as i64)
+            })
+            .collect();
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+        scored.into_iter().take(k).map(|(_, id)| id).collect()
+    }
+
+    fn build(dir: &TempDir, data: &[f32], params: HnswParams) -> HnswIndex {
+        let mut index = HnswIndex::open(&dir.path().join("reviews.hnsw"), DIM, params).unwrap();
+        index.add_batch(data, None).unwrap();
+        index
+    }
+
+    fn recall(
+        index: &HnswIndex,
+        data: &[f32],
+        queries: &[f32],
+        k: usize,
+        ef: Option<usize>,
+    ) -> f32 {
+        let mut found = 0;
+        for q in queries.chunks_exact(DIM) {
+            let truth: HashSet<i64> = exact(data, q, k, &HashSet::new()).into_iter().collect();
+            let (ids, _) = index.search_ef(q, k, ef).unwrap();
+            found += ids.iter().filter(|id| truth.contains(id)).count();
+        }
+        found as f32 / (queries.len() / DIM * k) as f32
+    }
+
+    #[test]
+    fn recall_against_brute_force() {
+        let dir = TempDir::new().unwrap();
+        let data = random_vectors(1000, 1);
+        let index = build(&dir, &data, HnswParams::default());
+        let r = recall(&index, &data, &random_vectors(20, 2), 10, None);
+        assert!(r >= 0.9, "recall@10 = {r}");
+    }
+
+    #[test]
+    fn ef_override_trades_speed_for_recall() {
+        let dir = TempDir::new().unwrap();
+        let data = random_vectors(1000, 3);
+        let params = HnswParams {
+            ef_search: 10,
+            ..Default::default()
+        };
+        let index = build(&dir, &data, params);
+        let queries = random_vectors(20, 4);
+        let low = recall(&index, &data, &queries, 10, None);
+        let high = recall(&index, &data, &queries, 10, Some(1000));
+        assert!(high >= low, "ef=1000 recall {high} < default recall {low}");
+        assert!(high >= 0.99, "ef=1000 recall@10 = {high}");
+    }
+
+    #[test]
+    fn returns_topk_when_more_than_topk_nearest_are_deleted() {
+        let dir = TempDir::new().unwrap();
+        let data = random_vectors(200, 5);
+        let mut index = build(&dir, &data, HnswParams::default());
+        let q = &random_vectors(1, 6)[..];
+
+        // ลบ 100 ตัวที่ใกล้ query ที่สุด — มากกว่า ef_search (64) ทั้งหมด
+        let nearest = exact(&data, q, 100, &HashSet::new());
+        index.delete(&nearest).unwrap();
+        let deleted: HashSet<i64> = nearest.into_iter().collect();
+
+        let (ids, scores) = index.search(q, 10).unwrap();
+        assert_eq!(ids.len(), 10);
+        assert_eq!(scores.len(), 10);
+        assert!(ids.iter().all(|id| !deleted.contains(id)));
+        let truth: HashSet<i64> = exact(&data, q, 10, &deleted).into_iter().collect();
+        let found = ids.iter().filter(|id| truth.contains(id)).count();
+        assert!(found >= 9, "{found}/10 of the live nearest neighbours");
+    }
+
+    #[test]
+    fn deleting_unknown_ids_does_not_shrink_the_live_count() {
+        let dir = TempDir::new().unwrap();
+        let data = random_vectors(300, 9);
+        let mut index = build(&dir, &data, HnswParams::default());
+        let q = &random_vectors(1, 10)[..];
+
+        // 100 ตัวที่ใกล้สุด + id ที่ไม่เคยถูกเพิ่ม (undo ของ add ที่ล้ม, orphan จาก repair) + ลบซ้ำ
+        let nearest = exact(&data, q, 100, &HashSet::new());
+        index.delete(&nearest).unwrap();
+        index.delete(&(1000..1400).collect::<Vec<i64>>()).unwrap();
+        index.delete(&nearest[..10]).unwrap();
+        assert_eq!(index.deleted.len(), 100);
+        assert_eq!(index.search(q, 10).unwrap().0.len(), 10);
+
+        index.save().unwrap();
+        let index =
+            HnswIndex::open(&dir.path().join("reviews.hnsw"), DIM, HnswParams::default()).unwrap();
+        assert_eq!(index.ids().unwrap().len(), 200);
+        assert_eq!(index.search(q, 10).unwrap().0.len(), 10);
+    }
+
+    #[test]
+    fn corrupt_entry_point_rebuilds_the_graph() {
+        let dir = TempDir::new().unwrap();
+        let data = random_vectors(100, 11);
+        let q = &random_vectors(1, 12)[..];
+        let path = dir.path().join("reviews.hnsw");
+        {
+            let mut index = build(&dir, &data, HnswParams::default());
+            index.save().unwrap();
+        }
+        // magic | version | dim | M | ef_construction | n (u64) | entry (u64) | max_level
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[28..36].copy_from_slice(&1_000u64.to_le_bytes());
+        fs::write(&path, &bytes).unwrap();
+
+        let mut index = HnswIndex::open(&path, DIM, HnswParams::default());
+        assert!(index.as_mut().unwrap().load_graph().is_err());
+        let (ids, _) = index.unwrap().search(q, 5).unwrap();
+        assert_eq!(ids.len(), 5);
+    }
+
+    #[test]
+    fn reopen_keeps_vectors_graph_and_tombstones() {
+        let dir = TempDir::new().unwrap();
+        let data = random_vectors(300, 7);
+        let q = &random_vectors(1, 8)[..];
+        let before = {
+            let mut index = build(&dir, &data, HnswParams::default());
+            index.delete(&[0, 1, 2]).unwrap();
+            index.save().unwrap();
+            index.search(q, 10).unwrap()
+        };
+        let index =
+            HnswIndex::open(&dir.path().join("reviews.hnsw"), DIM, HnswParams::default()).unwrap();
+        assert_eq!(index.ids().unwrap().len(), 297);
+        assert_eq!(index.search(q, 10).unwrap(), before);
+    }
+}
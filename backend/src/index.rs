@@ -0,0 +1,424 @@
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+use crate::embedder::ModelSpec;
+use crate::flat_index::FlatIndex;
+use crate::hnsw::{HnswIndex, HnswParams};
+use crate::lock::read_only;
+use crate::preprocess::Preprocessor;
+use crate::spfresh::{Spfresh, SpfreshError};
+
+/// Common interface for every vector index engine (SPFresh FFI, in-process Rust indexes).
+/// Scores returned by `search` are similarities: higher means closer.
+pub trait VectorIndex: Send + Sync {
+    fn dim(&self) -> usize;
+
+    /// `vectors` is a contiguous `[n * dim]` buffer; `ids` (when given) must have length `n`.
+    fn add_batch(&mut self, vectors: &[f32], ids: Option<&[i64]>) -> Result<(), IndexError>;
+
+    /// Returns up to `topk` `(ids, scores)`; an id of `-1` marks an empty slot.
+    fn search(&self, query: &[f32], topk: usize) -> Result<(Vec<i64>, Vec<f32>), IndexError>;
+
+    /// `search` with a per-request candidate list size; engines without an
+    /// `ef` knob ignore it.
+    fn search_ef(
+        &self,
+        query: &[f32],
+        topk: usize,
+        _ef: Option<usize>,
+    ) -> Result<(Vec<i64>, Vec<f32>), IndexError> {
+        self.search(query, topk)
+    }
+
+    /// Tombstone `ids`; they must no longer be returned by `search`.
+    fn delete(&mut self, ids: &[i64]) -> Result<(), IndexError>;
+
+    /// Every live (searchable, not deleted) id, in no particular order.
+    fn ids(&self) -> Result<Vec<i64>, IndexError>;
+
+    /// Persist everything added (and deleted) so far.
+    fn save(&mut self) -> Result<(), IndexError>;
+}
+
+#[derive(Debug, Error)]
+pub enum IndexError {
+    #[error(transparent)]
+    Spfresh(#[from] SpfreshError),
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("invalid parameter: {0}")]
+    InvalidParam(&'static str),
+    #[error("corrupt index file: {0}")]
+    Corrupt(String),
+    #[error("unknown index backend: {0} (expected spfresh|flat|hnsw)")]
+    UnknownBackend(String),
+    #[error("embedding model: {0}")]
+    Model(String),
+    #[error("index was built with {stored} but the configuration is {configured}; rebuild it (`backend rebuild`) or switch EMBED_MODEL / EMBED_DOCUMENT_PREFIX / TEXT_PREPROCESS back")]
+    ModelMismatch { stored: String, configured: String },
+}
+
+/// Which engine backs `AppState.index` (env `INDEX_BACKEND`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexBackend {
+    /// C++ shim in `native/spfresh_c_api.cc` (needs the SPTAG/SPFresh native build).
+    Spfresh,
+    /// Exact brute-force search in pure Rust, persisted to `<index>.flat`.
+    Flat,
+    /// Approximate HNSW graph in pure Rust, persisted to `<index>.hnsw` (+ `.hnsw.vec`).
+    Hnsw,
+}
+
+impl IndexBackend {
+    pub fn parse(s: &str) -> Result<Self, IndexError> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "spfresh" => Ok(Self::Spfresh),
+            "flat" => Ok(Self::Flat),
+            "hnsw" => Ok(Self::Hnsw),
+            other => Err(IndexError::UnknownBackend(other.to_string())),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Spfresh => "spfresh",
+            Self::Flat => "flat",
+            Self::Hnsw => "hnsw",
+        }
+    }
+}
+
+/// Index settings read from the environment.
+#[derive(Debug, Clone)]
+pub struct IndexConfig {
+    pub backend: IndexBackend,
+    /// Embedding model id (`EMBED_MODEL`); recorded next to the index.
+    pub model: String,
+    /// Taken from the model, never configured separately.
+    pub dim: usize,
+    /// Prefix put in front of every document before embedding (`EMBED_DOCUMENT_PREFIX`).
+    pub document_prefix: String,
+    /// Preprocessing steps applied before embedding (`TEXT_PREPROCESS`, see [`Preprocessor::describe`]).
+    pub preprocess: String,
+    pub spfresh_params: String,
+    pub hnsw: HnswParams,
+}
+
+impl IndexConfig {
+    pub fn from_env() -> Result<Self, IndexError> {
+        let backend = IndexBackend::parse(
+            &env::var("INDEX_BACKEND").unwrap_or_else(|_| "spfresh".into()),
+        )?;
+        let spec = ModelSpec::from_env().map_err(|e| IndexError::Model(format!("{e:#}")))?;
+        let preprocess = Preprocessor::from_env()
+            .map_err(|e| IndexError::Model(format!("{e:#}")))?
+            .describe();
+        let spfresh_params =
+            env::var("SPFRESH_PARAMS").unwrap_or_else(|_| "PostingPageLimit=12".into());
+        let defaults = HnswParams::default();
+        let hnsw = HnswParams {
+            m: env_usize("HNSW_M").unwrap_or(defaults.m),
+            ef_construction: env_usize("HNSW_EF_CONSTRUCTION").unwrap_or(defaults.ef_construction),
+            ef_search: env_usize("HNSW_EF_SEARCH").unwrap_or(defaults.ef_search),
+        };
+        Ok(Self {
+            backend,
+            model: spec.id,
+            dim: spec.dim,
+            document_prefix: spec.prefixes.document,
+            preprocess,
+            spfresh_params,
+            hnsw,
+        })
+    }
+
+    /// Open (or create) the configured index for `index_path` (e.g. `data/reviews.index`).
+    /// SPFresh works on the parent directory; Rust engines keep a sibling file.
+    /// Fails with [`IndexError::ModelMismatch`] if the index was built with another model,
+    /// document prefix or preprocessing.
+    pub fn open(&self, index_path: &str) -> Result<Box<dyn VectorIndex>, IndexError> {
+        let path = Path::new(index_path);
+        let stamp_path = model_stamp_path(path);
+        let stamp = self.stamp();
+        let stored = read_stamp(&stamp_path)?;
+        if let Some(stored) = &stored {
+            check_stamp(stored, &stamp)?;
+        }
+        let index = self.open_engine(path)?;
+        if stored.is_none() {
+            // ไม่มี stamp: index ใหม่ หรือ index จากก่อนมี stamp (engine ตรวจ dim ให้แล้ว)
+            // ซึ่ง embed ด้วย model เดียวกันแต่ไม่มี prefix และไม่ได้ preprocess
+            if !index.ids()?.is_empty() {
+                check_stamp(
+                    &ModelStamp {
+                        document_prefix: String::new(),
+                        preprocess: no_preprocess(),
+                        ..self.stamp()
+                    },
+                    &stamp,
+                )?;
+            }
+            if !read_only() {
+                let tmp = stamp_path.with_extension("json.tmp");
+                fs::write(&tmp, serde_json::to_vec(&stamp).map_err(|e| IndexError::Corrupt(e.to_string()))?)?;
+                fs::rename(&tmp, &stamp_path)?;
+            }
+        }
+        Ok(index)
+    }
+
+    /// Take the document prefix and preprocessing recorded next to `index_path`
+    /// (if any), e.g. to open a restored snapshot whatever the current env says.
+    pub fn adopt_stamp(&mut self, index_path: &str) -> Result<(), IndexError> {
+        if let Some(stored) = read_stamp(&model_stamp_path(Path::new(index_path)))? {
+            self.document_prefix = stored.document_prefix;
+            self.preprocess = stored.preprocess;
+        }
+        Ok(())
+    }
+
+    fn stamp(&self) -> ModelStamp {
+        ModelStamp {
+            model: self.model.clone(),
+            dim: self.dim,
+            document_prefix: self.document_prefix.clone(),
+            preprocess: self.preprocess.clone(),
+        }
+    }
+
+    fn open_engine(&self, path: &Path) -> Result<Box<dyn VectorIndex>, IndexError> {
+        match self.backend {
+            IndexBackend::Spfresh => {
+                let index_dir = index_dir(path).to_string_lossy().to_string();
+                Ok(Box::new(Spfresh::open(&index_dir, self.dim, &self.spfresh_params)?))
+            }
+            IndexBackend::Flat => Ok(Box::new(FlatIndex::open(
+                &path.with_extension("flat"),
+                self.dim,
+            )?)),
+            IndexBackend::Hnsw => Ok(Box::new(HnswIndex::open(
+                &path.with_extension("hnsw"),
+                self.dim,
+                self.hnsw,
+            )?)),
+        }
+    }
+
+    /// Every file the engine may persist for `index_path` (some may not exist yet,
+    /// e.g. a `.del` log before the first delete).
+    pub fn files(&self, index_path: &str) -> Vec<PathBuf> {
+        let path = Path::new(index_path);
+        let mut files = vec![model_stamp_path(path)];
+        files.extend(match self.backend {
+            IndexBackend::Spfresh => vec![
+                index_dir(path).join("spfresh_vectors.bin"),
+                index_dir(path).join("spfresh_vectors.bin.del"),
+            ],
+            IndexBackend::Flat => vec![
+                path.with_extension("flat"),
+                path.with_extension("flat.del"),
+            ],
+            IndexBackend::Hnsw => vec![
+                path.with_extension("hnsw"),
+                path.with_extension("hnsw.vec"),
+                path.with_extension("hnsw.vec.del"),
+            ],
+        });
+        files
+    }
+}
+
+/// `<index>.model.json`: the embedding model the vectors came from, and how the
+/// text was prepared for it. Stamps written before the prefix / preprocessing were
+/// recorded read as "no prefix, no preprocessing" — which is how those indexes were built.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct ModelStamp {
+    model: String,
+    dim: usize,
+    #[serde(default)]
+    document_prefix: String,
+    #[serde(default = "no_preprocess")]
+    preprocess: String,
+}
+
+impl std::fmt::Display for ModelStamp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} ({}-dim, document prefix {:?}, preprocess {})",
+            self.model, self.dim, self.document_prefix, self.preprocess
+        )
+    }
+}
+
+fn no_preprocess() -> String {
+    "none".into()
+}
+
+fn read_stamp(stamp_path: &Path) -> Result<Option<ModelStamp>, IndexError> {
+    match fs::read(stamp_path) {
+        Ok(bytes) => serde_json::from_slice(&bytes)
+            .map(Some)
+            .map_err(|e| IndexError::Corrupt(format!("{}: {e}", stamp_path.display()))),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn check_stamp(stored: &ModelStamp, configured: &ModelStamp) -> Result<(), IndexError> {
+    if stored != configured {
+        return Err(IndexError::ModelMismatch {
+            stored: stored.to_string(),
+            configured: configured.to_string(),
+        });
+    }
+    Ok(())
+}
+
+fn model_stamp_path(index_path: &Path) -> PathBuf {
+    index_path.with_extension("model.json")
+}
+
+fn index_dir(index_path: &Path) -> &Path {
+    index_path
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or(Path::new("."))
+}
+
+fn env_usize(key: &str) -> Option<usize> {
+    env::var(key).ok().and_then(|s| s.parse().ok())
+}
+
+/// Scale `v` to unit length so that dot product == cosine similarity.
+pub(crate) fn normalize(v: &mut [f32]) {
+    let norm = v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        v.iter_mut().for_each(|x| *x /= norm);
+    }
+}
+
+pub(crate) fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+/// Shared argument checks for `add_batch`; returns the number of vectors.
+pub(crate) fn check_batch(dim: usize, vectors: &[f32], ids: Option<&[i64]>) -> Result<usize, IndexError> {
+    if dim == 0 {
+        return Err(IndexError::InvalidParam("self.dim == 0"));
+    }
+    let n = vectors.len() / dim;
+    if vectors.len() != n * dim {
+        return Err(IndexError::InvalidParam("vectors buffer must be contiguous [n*dim]"));
+    }
+    if let Some(ids_slice) = ids {
+        if ids_slice.len() != n {
+            return Err(IndexError::InvalidParam("ids length != number of vectors"));
+        }
+    }
+    Ok(n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn config(document_prefix: &str, preprocess: &str) -> IndexConfig {
+        IndexConfig {
+            backend: IndexBackend::Flat,
+            model: "hash".into(),
+            dim: 4,
+            document_prefix: document_prefix.into(),
+            preprocess: preprocess.into(),
+            spfresh_params: String::new(),
+            hnsw: HnswParams::default(),
+        }
+    }
+
+    fn index_path(dir: &TempDir) -> String {
+        dir.path()
+            .join("reviews.index")
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    fn add_one(cfg: &IndexConfig, path: &str) {
+        let mut index = cfg.open(path).unwrap();
+        index.add_batch(&[1.0, 0.0, 0.0, 0.0], Some(&[0])).unwrap();
+        index.save().unwrap();
+    }
+
+    #[test]
+    fn same_config_reopens() {
+        let dir = TempDir::new().unwrap();
+        let path = index_path(&dir);
+        let cfg = config("passage: ", "html,nfkc,whitespace");
+        add_one(&cfg, &path);
+        assert_eq!(cfg.open(&path).unwrap().ids().unwrap(), vec![0]);
+    }
+
+    #[test]
+    fn changed_prefix_or_preprocessing_is_refused() {
+        let dir = TempDir::new().unwrap();
+        let path = index_path(&dir);
+        add_one(&config("passage: ", "html,nfkc,whitespace"), &path);
+        for cfg in [
+            config("", "html,nfkc,whitespace"),
+            config("passage: ", "none"),
+        ] {
+            assert!(matches!(
+                cfg.open(&path),
+                Err(IndexError::ModelMismatch { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn legacy_stamp_means_no_prefix_and_no_preprocessing() {
+        let dir = TempDir::new().unwrap();
+        let path = index_path(&dir);
+        add_one(&config("", "none"), &path);
+        fs::write(
+            model_stamp_path(Path::new(&path)),
+            r#"{"model":"hash","dim":4}"#,
+        )
+        .unwrap();
+        assert!(config("", "none").open(&path).is_ok());
+        assert!(matches!(
+            config("", "html,nfkc,whitespace").open(&path),
+            Err(IndexError::ModelMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn unstamped_index_is_checked_unless_empty() {
+        let dir = TempDir::new().unwrap();
+        let path = index_path(&dir);
+        add_one(&config("", "none"), &path);
+        fs::remove_file(model_stamp_path(Path::new(&path))).unwrap();
+        assert!(matches!(
+            config("", "html").open(&path),
+            Err(IndexError::ModelMismatch { .. })
+        ));
+
+        // ไม่มีเวกเตอร์: ไม่มีอะไรต้อง embed ใหม่ จึงรับ config ปัจจุบันได้เลย
+        let empty = TempDir::new().unwrap();
+        let path = index_path(&empty);
+        let cfg = config("passage: ", "html");
+        cfg.open(&path).unwrap();
+        assert!(cfg.open(&path).is_ok());
+        let mut adopted = config("", "none");
+        adopted.adopt_stamp(&path).unwrap();
+        assert_eq!(
+            (
+                adopted.document_prefix.as_str(),
+                adopted.preprocess.as_str()
+            ),
+            ("passage: ", "html")
+        );
+    }
+}
@@ -0,0 +1,120 @@
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use thiserror::Error;
+
+use crate::handlers::Paths;
+use crate::segments::SegmentLayout;
+
+const LOCK_FILE: &str = ".lock";
+
+/// `READ_ONLY=1|true`: ไม่ถือ lock, ไม่กู้ / replay / เขียนไฟล์ใด ๆ และปฏิเสธทุกการเขียน
+pub fn read_only() -> bool {
+    static READ_ONLY: OnceLock<bool> = OnceLock::new();
+    *READ_ONLY.get_or_init(|| {
+        matches!(
+            env::var("READ_ONLY").unwrap_or_default().to_ascii_lowercase().as_str(),
+            "1" | "true" | "yes"
+        )
+    })
+}
+
+#[derive(Debug, Error)]
+pub enum LockError {
+    #[error("{dir} is locked by another process ({owner}); stop it first, or start this one with READ_ONLY=1")]
+    Held { dir: String, owner: String },
+    #[error("lock {dir}: {source}")]
+    Io {
+        dir: String,
+        #[source]
+        source: std::io::Error,
+    },
+}
+
+/// advisory lock (`flock`) บน `<dir>/.lock` ของทุกไดเรกทอรีที่ชุดข้อมูลเขียน
+/// (index, segment ของ reviews, vector map); ปล่อยเมื่อ drop หรือ process จบ
+#[derive(Debug)]
+pub struct DataDirLock {
+    held: Vec<(PathBuf, File)>,
+}
+
+impl DataDirLock {
+    /// lock ทุกไดเรกทอรีของ `paths` (สร้างไดเรกทอรีถ้ายังไม่มี). ไดเรกทอรีที่ `current` ถืออยู่แล้ว
+    /// ใช้ lock เดิมร่วมกัน (dup fd) — `flock` ซ้ำจาก fd ใหม่ใน process เดียวกันจะชนกันเอง
+    pub fn acquire(paths: &Paths, current: Option<&DataDirLock>) -> Result<Self, LockError> {
+        let mut held = Vec::new();
+        for dir in Self::dirs(paths)? {
+            let reused = current
+                .and_then(|c| c.held.iter().find(|(d, _)| *d == dir))
+                .map(|(_, f)| f.try_clone());
+            let file = match reused {
+                Some(f) => f.map_err(|source| io_err(&dir, source))?,
+                None => lock_dir(&dir)?,
+            };
+            held.push((dir, file));
+        }
+        Ok(Self { held })
+    }
+
+    fn dirs(paths: &Paths) -> Result<Vec<PathBuf>, LockError> {
+        let parent = |p: &str| match Path::new(p).parent() {
+            Some(d) if !d.as_os_str().is_empty() => d.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        let mut dirs = Vec::new();
+        for dir in [
+            parent(&paths.index_path),
+            SegmentLayout::from_path(&paths.jsonl_path).dir().to_path_buf(),
+            parent(&paths.map_path),
+        ] {
+            fs::create_dir_all(&dir).map_err(|source| io_err(&dir, source))?;
+            let dir = dir.canonicalize().map_err(|source| io_err(&dir, source))?;
+            if !dirs.contains(&dir) {
+                dirs.push(dir);
+            }
+        }
+        Ok(dirs)
+    }
+}
+
+fn io_err(dir: &Path, source: std::io::Error) -> LockError {
+    LockError::Io {
+        dir: dir.display().to_string(),
+        source,
+    }
+}
+
+fn lock_dir(dir: &Path) -> Result<File, LockError> {
+    let path = dir.join(LOCK_FILE);
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)
+        .map_err(|source| io_err(dir, source))?;
+    // SAFETY: fd เป็นของ `file` ที่ยังเปิดอยู่
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        let err = std::io::Error::last_os_error();
+        if err.raw_os_error() == Some(libc::EWOULDBLOCK) {
+            let mut owner = String::new();
+            let _ = file.read_to_string(&mut owner);
+            let owner = owner.trim();
+            return Err(LockError::Held {
+                dir: dir.display().to_string(),
+                owner: if owner.is_empty() { "unknown pid".into() } else { owner.into() },
+            });
+        }
+        return Err(io_err(dir, err));
+    }
+    // บันทึกเจ้าของไว้ให้ข้อความ error ของ process อื่น (ตัว lock คือ flock ไม่ใช่เนื้อหาไฟล์)
+    let write_owner = |file: &mut File| -> std::io::Result<()> {
+        file.set_len(0)?;
+        file.write_all(format!("pid {}\n", std::process::id()).as_bytes())
+    };
+    write_owner(&mut file).map_err(|source| io_err(dir, source))?;
+    Ok(file)
+}
@@ -1,19 +1,34 @@
+mod chunk;
 mod embedder;
+mod flat_index;
 mod handlers;
+mod hnsw;
+mod index;
+mod lock;
+mod pool;
+mod preprocess;
+mod query_cache;
+mod rebuild;
+mod review_store;
 mod routes;
+mod schema;
+mod seekable;
+mod segments;
+mod snapshot;
 mod storage;
 mod types;
 mod spfresh;
+mod vector_log;
+mod verify;
+mod wal;
 
 use axum::Router;
 use handlers::{AppState, Paths};
 use std::{
     env,
-    fs::File,
-    io::{BufRead, BufReader},
     net::SocketAddr,
     path::{Path, PathBuf},
-    sync::{Arc, RwLock},
+    sync::{Arc, Mutex, RwLock},
 };
 use tower_http::{
     cors::{Any, CorsLayer},
@@ -32,15 +47,18 @@ use http_body_util::BodyExt as _;
 // ใช้เรียก .call() บน service
 use tower::Service;
 
-fn count_lines(path: &str) -> usize {
-    let p = Path::new(path);
-    if !p.exists() {
-        return 0;
-    }
-    match File::open(p).map(BufReader::new) {
-        Ok(reader) => reader.lines().count(),
-        Err(_) => 0,
-    }
+/// paths เริ่มต้นจาก DATA_DIR / INDEX_FILE / METADATA_FILE / MAP_FILE
+fn paths_from_env() -> anyhow::Result<Paths> {
+    let data_dir = env::var("DATA_DIR").unwrap_or_else(|_| "data".into());
+    std::fs::create_dir_all(&data_dir)?;
+    Ok(Paths {
+        index_path: env::var("INDEX_FILE")
+            .unwrap_or_else(|_| format!("{}/reviews.index", &data_dir)),
+        jsonl_path: env::var("METADATA_FILE")
+            .unwrap_or_else(|_| format!("{}/reviews.jsonl", &data_dir)),
+        map_path: env::var("MAP_FILE")
+            .unwrap_or_else(|_| format!("{}/vector_map.jsonl", &data_dir)),
+    })
 }
 
 #[tokio::main]
@@ -50,33 +68,144 @@ async fn main() -> anyhow::Result<()> {
         .init();
 
     // -------- Initial file paths --------
-    let data_dir = env::var("DATA_DIR").unwrap_or_else(|_| "data".into());
-    std::fs::create_dir_all(&data_dir)?;
-    let index_path =
-        env::var("INDEX_FILE").unwrap_or_else(|_| format!("{}/reviews.index", &data_dir));
-    let jsonl_path =
-        env::var("METADATA_FILE").unwrap_or_else(|_| format!("{}/reviews.jsonl", &data_dir));
-    let map_path =
-        env::var("MAP_FILE").unwrap_or_else(|_| format!("{}/vector_map.jsonl", &data_dir));
-
-    // -------- Open SPFresh index (FFI) --------
-    let embed_dim: usize = env::var("EMBED_DIM")
+    let paths = paths_from_env()?;
+
+    // restore เขียนไดเรกทอรีใหม่ (lock ของตัวเอง) — ทำได้แม้ server ถือ data directory ปัจจุบันอยู่
+    if env::args().nth(1).as_deref() == Some("restore") {
+        let args: Vec<String> = env::args().skip(2).collect();
+        let [archive, target] = args.as_slice() else {
+            anyhow::bail!("usage: backend restore <archive> <target-dir>");
+        };
+        return snapshot::run_cli(archive, target);
+    }
+
+    // -------- Exclusive lock on the data directory (READ_ONLY=1 ข้าม) --------
+    // ถือไว้ตลอดอายุ process; process อื่นที่ชี้ไดเรกทอรีเดียวกันจะเริ่มไม่ได้
+    let data_lock = if lock::read_only() {
+        tracing::warn!("READ_ONLY: data directory not locked; writes are rejected");
+        None
+    } else {
+        Some(lock::DataDirLock::acquire(&paths, None)?)
+    };
+    let writable = |cmd: &str| {
+        if lock::read_only() {
+            anyhow::bail!("`{cmd}` writes the data directory; unset READ_ONLY");
+        }
+        Ok(())
+    };
+
+    // rebuild ที่ crash ระหว่างสลับไฟล์ index (มี commit marker แล้ว) ถูกติดตั้งต่อให้จบก่อนเปิด index
+    rebuild::finish_install(&paths.index_path)?;
+
+    // -------- Subcommands (ไม่มี = รัน server) --------
+    match env::args().nth(1).as_deref() {
+        None | Some("serve") => {}
+        Some("rebuild") => {
+            writable("rebuild")?;
+            return rebuild::run_cli(&paths);
+        }
+        Some("verify") => return verify::run_cli(&paths, false),
+        Some("repair") => {
+            writable("repair")?;
+            let repaired = verify::run_cli(&paths, true);
+            storage::finish_compression();
+            return repaired;
+        }
+        Some("migrate") => {
+            let dry_run = env::args().skip(2).any(|a| a == "--dry-run");
+            if !dry_run {
+                writable("migrate")?;
+            }
+            return schema::run_cli(&paths, dry_run);
+        }
+        Some(other) => anyhow::bail!(
+            "unknown command `{other}` (expected: serve | rebuild | verify | repair | migrate [--dry-run] | restore <archive> <dir>)"
+        ),
+    }
+
+    // -------- Embedding model: โหลดตอนเริ่ม (host ที่ไม่มี internet ล้มที่นี่ ไม่ใช่ตอน insert แรก) --------
+    let embedder = embedder::from_env().map_err(|e| e.context("embedding model failed to load"))?;
+    tracing::info!(
+        "embedding model: {} (dim {}), text preprocessing: {}",
+        embedder.model_id(),
+        embedder.dim(),
+        preprocess::Preprocessor::from_env()?.describe()
+    );
+
+    // -------- Open vector index (INDEX_BACKEND=spfresh|flat|hnsw) --------
+    let index_cfg = index::IndexConfig::from_env()?;
+    let mut index = index_cfg.open(&paths.index_path)?;
+    tracing::info!(
+        "vector index backend: {:?} (model {}, dim {})",
+        index_cfg.backend,
+        index_cfg.model,
+        index.dim()
+    );
+
+    // -------- กู้ท้ายไฟล์ JSONL ที่เขียนไม่จบ แล้วทำ insert ที่ค้างใน WAL ให้ครบ --------
+    let wal_file = storage::wal_path(&paths.jsonl_path);
+    let deleted;
+    let wal = if lock::read_only() {
+        deleted = storage::load_deleted_vector_ids(&paths.jsonl_path)?;
+        wal::Wal::detached(Path::new(&wal_file))
+    } else {
+        storage::recover_data_files(&paths.jsonl_path, &paths.map_path)?;
+        deleted = storage::load_deleted_vector_ids(&paths.jsonl_path)?;
+        let (mut wal, pending) = wal::Wal::open(Path::new(&wal_file))?;
+        wal::replay(&mut wal, pending, &paths, &mut *index, &deleted)?;
+        wal
+    };
+
+    // ต่อจาก id มากสุดที่เคยใช้ (ไม่ใช่จำนวนบรรทัดของ vector_map.jsonl)
+    let next_vector_id = verify::next_vector_id(&paths, &*index)?;
+
+    // -------- metadata ในหน่วยความจำ (search ไม่อ่าน reviews.jsonl ซ้ำ) --------
+    let reviews = review_store::ReviewStore::load(&paths.jsonl_path)?;
+    tracing::info!("loaded {} review(s) from {}", reviews.len(), paths.jsonl_path);
+
+    // save index ทุก ๆ N เวกเตอร์ที่เพิ่ม (และตอน shutdown เสมอ)
+    let flush_every: usize = env::var("INDEX_FLUSH_EVERY")
         .ok()
         .and_then(|s| s.parse().ok())
-        .unwrap_or(384);
-    let spf_params = env::var("SPFRESH_PARAMS").unwrap_or_else(|_| "PostingPageLimit=12".into());
-    let index = spfresh::Spfresh::open(&data_dir, embed_dim, &spf_params)?;
+        .unwrap_or(100)
+        .max(1);
 
-    let next_vector_id = count_lines(&map_path);
+    // embed / index ไม่รันบน async runtime: worker thread + คิวจำกัด (เต็ม => 503)
+    let embed_cfg = pool::PoolConfig::from_env("EMBED", 2, 32);
+    let pool_cfg = pool::PoolConfig::from_env("INDEX", 4, 256);
+    let batch_cfg = pool::BatchConfig::from_env();
+    tracing::info!(
+        "worker pools: embed {} worker(s) / queue {} (batch ≤{} texts, wait {:?}), index {} worker(s) / queue {}",
+        embed_cfg.workers,
+        embed_cfg.queue,
+        batch_cfg.max_batch,
+        batch_cfg.max_wait,
+        pool_cfg.workers,
+        pool_cfg.queue
+    );
+    let embed_pool = Arc::new(pool::EmbedPool::start(embedder.clone(), embed_cfg, batch_cfg)?);
+    let index_pool = Arc::new(pool::BlockingPool::start("index", pool_cfg)?);
+
+    let chunker = chunk::Chunker::from_env()?;
+    let query_cache = Arc::new(query_cache::QueryCache::from_env(embedder.model_id()));
 
     let state = AppState {
         index: Arc::new(RwLock::new(index)),
-        paths: Arc::new(RwLock::new(Paths {
-            index_path,
-            jsonl_path,
-            map_path: map_path.clone(),
-        })),
+        paths: Arc::new(RwLock::new(paths)),
         next_vector_id: Arc::new(RwLock::new(next_vector_id)),
+        unsaved_vectors: Arc::new(RwLock::new(0)),
+        flush_every,
+        deleted: Arc::new(RwLock::new(deleted)),
+        write_gate: Arc::new(RwLock::new(())),
+        rebuild: Arc::new(RwLock::new(Default::default())),
+        wal: Arc::new(Mutex::new(wal)),
+        reviews: Arc::new(RwLock::new(reviews)),
+        data_lock: Arc::new(Mutex::new(data_lock)),
+        embedder,
+        embed_pool,
+        index_pool,
+        query_cache,
+        chunker,
     };
 
     // -------- CORS --------
@@ -116,8 +245,14 @@ async fn main() -> anyhow::Result<()> {
     // เตรียม state ไว้ใส่ลง request.extensions
     let svc_state = state.clone();
 
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+
     loop {
-        let (stream, peer) = listener.accept().await?;
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = &mut shutdown => break,
+        };
         tracing::debug!("accepted connection from {:?}", peer);
 
         let app_clone = app.clone();
@@ -165,4 +300,52 @@ async fn main() -> anyhow::Result<()> {
             }
         });
     }
+
+    // -------- Graceful shutdown: persist index --------
+    if lock::read_only() {
+        return Ok(());
+    }
+    tracing::info!("shutting down; saving index");
+    match state.index.write() {
+        Ok(mut idx) => {
+            idx.save()?;
+            if let Ok(mut w) = state.wal.lock() {
+                w.checkpoint()?;
+            }
+        }
+        Err(_) => tracing::error!("index lock poisoned; index not saved"),
+    }
+    // segment ที่ปิดระหว่างรันต้องบีบอัดให้เสร็จก่อนออก (ไม่ทิ้ง `.zst.tmp` ค้าง)
+    storage::finish_compression();
+    Ok(())
+}
+
+/// รอ Ctrl+C หรือ SIGTERM (docker stop)
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!("ctrl_c handler failed: {e}");
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut sig) => {
+                sig.recv().await;
+            }
+            Err(e) => {
+                tracing::error!("SIGTERM handler failed: {e}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
 }
@@ -0,0 +1,514 @@
+use anyhow::anyhow;
+use axum::http::StatusCode;
+use std::env;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::sync::oneshot;
+use tracing::{debug, error, warn};
+
+use crate::embedder::Embed;
+
+/// งานที่ block (embed, index FFI, I/O ไฟล์) ไม่รันบน async runtime: แต่ละ pool มี worker thread
+/// ของตัวเองกับคิวจำกัดขนาด — คิวเต็ม => 503 ทันที แทนที่ทุก request จะช้าลงเรื่อย ๆ
+#[derive(Debug, Error)]
+pub enum PoolError {
+    #[error("{0} queue is full")]
+    Saturated(&'static str),
+    #[error("{0} worker stopped before replying")]
+    Stopped(&'static str),
+    #[error("embedding failed: {0:#}")]
+    Embed(anyhow::Error),
+}
+
+impl From<PoolError> for (StatusCode, String) {
+    fn from(e: PoolError) -> Self {
+        match e {
+            PoolError::Saturated(name) => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                format!("server busy ({name} queue full), retry later"),
+            ),
+            PoolError::Stopped(_) => {
+                error!("{e}");
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "worker failed".to_string(),
+                )
+            }
+            PoolError::Embed(_) => {
+                error!("{e}");
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "embedding failed".to_string(),
+                )
+            }
+        }
+    }
+}
+
+/// จำนวน worker / ความยาวคิวจาก `<PREFIX>_WORKERS` / `<PREFIX>_QUEUE`
+#[derive(Debug, Clone, Copy)]
+pub struct PoolConfig {
+    pub workers: usize,
+    pub queue: usize,
+}
+
+impl PoolConfig {
+    pub fn from_env(prefix: &str, workers: usize, queue: usize) -> Self {
+        let read = |name: String, default: usize| match env::var(&name) {
+            Ok(v) => v.trim().parse().unwrap_or_else(|_| {
+                warn!("{name}={v} is not a number; using {default}");
+                default
+            }),
+            Err(_) => default,
+        };
+        Self {
+            workers: read(format!("{prefix}_WORKERS"), workers).max(1),
+            queue: read(format!("{prefix}_QUEUE"), queue).max(1),
+        }
+    }
+}
+
+/// คิว `sync_channel` ขนาด `queue` (นับเฉพาะงานที่รอ ไม่รวมที่ worker กำลังทำ) + worker threads
+struct Queue<J> {
+    name: &'static str,
+    tx: SyncSender<J>,
+}
+
+impl<J: Send + 'static> Queue<J> {
+    /// worker รับงานแรกแล้วให้ `gather` ดึงงานที่รออยู่เพิ่มได้ (ยังถือ receiver อยู่ — worker อื่นรอคิว)
+    /// ก่อนส่งทั้งก้อนให้ `work` หลังปล่อย receiver แล้ว
+    fn start<B: 'static>(
+        name: &'static str,
+        cfg: PoolConfig,
+        gather: impl Fn(&Receiver<J>, J) -> B + Send + Sync + 'static,
+        work: impl Fn(B) + Send + Sync + 'static,
+    ) -> std::io::Result<Self> {
+        let (tx, rx) = mpsc::sync_channel::<J>(cfg.queue);
+        let rx: Arc<Mutex<Receiver<J>>> = Arc::new(Mutex::new(rx));
+        let gather = Arc::new(gather);
+        let work = Arc::new(work);
+        for i in 0..cfg.workers {
+            let rx = rx.clone();
+            let gather = gather.clone();
+            let work = work.clone();
+            thread::Builder::new()
+                .name(format!("{name}-{i}"))
+                .spawn(move || loop {
+                    let batch = match rx.lock() {
+                        Ok(rx) => rx.recv().map(|first| gather(&rx, first)),
+                        Err(_) => return,
+                    };
+                    let Ok(batch) = batch else { return };
+                    // งาน panic => ผู้รอได้ Stopped (reply ถูก drop); worker ยังรับงานต่อ
+                    if panic::catch_unwind(AssertUnwindSafe(|| work(batch))).is_err() {
+                        error!("{name} worker {i}: job panicked");
+                    }
+                })?;
+        }
+        Ok(Self { name, tx })
+    }
+
+    fn submit(&self, job: J) -> Result<(), PoolError> {
+        self.tx.try_send(job).map_err(|e| match e {
+            TrySendError::Full(_) => PoolError::Saturated(self.name),
+            TrySendError::Disconnected(_) => PoolError::Stopped(self.name),
+        })
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// pool ทั่วไปสำหรับงาน index (search / add / delete) และการเขียนไฟล์ที่ตามมา
+pub struct BlockingPool {
+    queue: Queue<Job>,
+}
+
+impl BlockingPool {
+    pub fn start(name: &'static str, cfg: PoolConfig) -> std::io::Result<Self> {
+        Ok(Self {
+            queue: Queue::start(name, cfg, |_, job| job, |job: Job| job())?,
+        })
+    }
+
+    /// รัน `f` บน worker แล้วรอผล; คิวเต็ม => `Saturated` โดยไม่รอ
+    pub async fn run<T, F>(&self, f: F) -> Result<T, PoolError>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        self.queue.submit(Box::new(move || {
+            let _ = tx.send(f());
+        }))?;
+        rx.await.map_err(|_| PoolError::Stopped(self.queue.name))
+    }
+}
+
+/// เอกสารกับ query ใช้ prefix ต่างกัน (`Embed::embed_documents` / `embed_queries`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EmbedKind {
+    Documents,
+    Queries,
+}
+
+struct EmbedJob {
+    kind: EmbedKind,
+    texts: Vec<String>,
+    reply: oneshot::Sender<anyhow::Result<Vec<Vec<f32>>>>,
+}
+
+/// micro-batching ของ [`EmbedPool`]: รวมคำขอที่มาถึงภายใน `max_wait` เป็น `embed` ครั้งเดียว
+/// สูงสุดราว `max_batch` ข้อความ (คำขอเดียวไม่ถูกแบ่ง — bulk ใหญ่กว่า `max_batch` ไปทั้งก้อน)
+#[derive(Debug, Clone, Copy)]
+pub struct BatchConfig {
+    pub max_batch: usize,
+    pub max_wait: Duration,
+}
+
+impl BatchConfig {
+    /// `EMBED_BATCH_MAX` (64 ข้อความ), `EMBED_BATCH_WAIT_MS` (2; 0 = รวมเฉพาะที่รออยู่แล้วในคิว)
+    pub fn from_env() -> Self {
+        let read = |name: &str, default: u64| match env::var(name) {
+            Ok(v) => v.trim().parse().unwrap_or_else(|_| {
+                warn!("{name}={v} is not a number; using {default}");
+                default
+            }),
+            Err(_) => default,
+        };
+        Self {
+            max_batch: read("EMBED_BATCH_MAX", 64).max(1) as usize,
+            max_wait: Duration::from_millis(read("EMBED_BATCH_WAIT_MS", 2)),
+        }
+    }
+
+    /// ต่อจาก `first`: ดึงงานจากคิวจนครบ `max_batch` ข้อความหรือหมดเวลา `max_wait` นับจากงานแรก
+    fn gather(&self, rx: &Receiver<EmbedJob>, first: EmbedJob) -> Vec<EmbedJob> {
+        let deadline = Instant::now() + self.max_wait;
+        let mut texts = first.texts.len();
+        let mut jobs = vec![first];
+        while texts < self.max_batch {
+            let next = match deadline.checked_duration_since(Instant::now()) {
+                Some(left) if !left.is_zero() => rx.recv_timeout(left).ok(),
+                _ => rx.try_recv().ok(),
+            };
+            let Some(job) = next else { break };
+            texts += job.texts.len();
+            jobs.push(job);
+        }
+        jobs
+    }
+}
+
+/// คิว embed หน้า `AppState.embedder` (insert / update / search); rebuild เรียก embedder ตรง.
+/// worker รวมคำขอที่มาใกล้กันเป็น batch เดียว (ดู [`BatchConfig`]) แล้วแจกเวกเตอร์คืนตามลำดับ
+pub struct EmbedPool {
+    queue: Queue<EmbedJob>,
+}
+
+impl EmbedPool {
+    pub fn start(
+        embedder: Arc<dyn Embed>,
+        cfg: PoolConfig,
+        batch: BatchConfig,
+    ) -> std::io::Result<Self> {
+        let queue = Queue::start(
+            "embed",
+            cfg,
+            move |rx, first| batch.gather(rx, first),
+            move |jobs: Vec<EmbedJob>| embed_batch(&*embedder, jobs),
+        )?;
+        Ok(Self { queue })
+    }
+
+    fn submit(
+        &self,
+        kind: EmbedKind,
+        texts: Vec<String>,
+    ) -> Result<oneshot::Receiver<anyhow::Result<Vec<Vec<f32>>>>, PoolError> {
+        let (reply, rx) = oneshot::channel();
+        self.queue.submit(EmbedJob { kind, texts, reply })?;
+        Ok(rx)
+    }
+
+    /// รีวิว / chunk ที่จะเก็บใน index
+    pub async fn embed_documents(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>, PoolError> {
+        let rx = self.submit(EmbedKind::Documents, texts)?;
+        rx.await
+            .map_err(|_| PoolError::Stopped(self.queue.name))?
+            .map_err(PoolError::Embed)
+    }
+
+    pub async fn embed_query(&self, text: String) -> Result<Vec<f32>, PoolError> {
+        let rx = self.submit(EmbedKind::Queries, vec![text])?;
+        rx.await
+            .map_err(|_| PoolError::Stopped(self.queue.name))?
+            .map_err(PoolError::Embed)
+            .map(|mut v| v.remove(0))
+    }
+}
+
+/// embed ครั้งเดียวต่อชนิด (เอกสาร / query) สำหรับทุกงานใน batch แล้วแบ่งเวกเตอร์คืนให้แต่ละงาน
+fn embed_batch(embedder: &dyn Embed, jobs: Vec<EmbedJob>) {
+    let (documents, queries): (Vec<EmbedJob>, Vec<EmbedJob>) = jobs
+        .into_iter()
+        .partition(|j| j.kind == EmbedKind::Documents);
+    for (kind, jobs) in [
+        (EmbedKind::Documents, documents),
+        (EmbedKind::Queries, queries),
+    ] {
+        if !jobs.is_empty() {
+            embed_jobs(embedder, kind, jobs);
+        }
+    }
+}
+
+fn embed_jobs(embedder: &dyn Embed, kind: EmbedKind, jobs: Vec<EmbedJob>) {
+    let texts: Vec<String> = jobs.iter().flat_map(|j| j.texts.iter().cloned()).collect();
+    debug!(
+        "embed batch ({kind:?}): {} request(s), {} text(s)",
+        jobs.len(),
+        texts.len()
+    );
+    let out = match kind {
+        EmbedKind::Documents => embedder.embed_documents(&texts),
+        EmbedKind::Queries => embedder.embed_queries(&texts),
+    };
+    match out.and_then(|v| check_count(v, texts.len())) {
+        Ok(vectors) => {
+            let mut vectors = vectors.into_iter();
+            for job in jobs {
+                let mine: Vec<Vec<f32>> = vectors.by_ref().take(job.texts.len()).collect();
+                let _ = job.reply.send(Ok(mine));
+            }
+        }
+        // anyhow::Error clone ไม่ได้: ทุกงานใน batch ได้ข้อความ error เดียวกัน
+        Err(e) => {
+            for job in jobs {
+                let _ = job.reply.send(Err(anyhow!("{e:#}")));
+            }
+        }
+    }
+}
+
+fn check_count(vectors: Vec<Vec<f32>>, texts: usize) -> anyhow::Result<Vec<Vec<f32>>> {
+    if vectors.len() == texts {
+        Ok(vectors)
+    } else {
+        Err(anyhow!("{} vectors for {} texts", vectors.len(), texts))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// เวกเตอร์ = [เลขท้ายข้อความ, 1 ถ้าเป็น query] — ตรวจได้ว่าแต่ละงานได้เวกเตอร์ของข้อความตัวเอง
+    /// และนับว่า `embed_*` ถูกเรียกกี่ครั้ง
+    #[derive(Default)]
+    struct Tagging {
+        calls: Mutex<Vec<(EmbedKind, usize)>>,
+        /// ตอบเวกเตอร์ขาดไปหนึ่งตัว
+        short: bool,
+    }
+
+    impl Tagging {
+        fn tag(&self, kind: EmbedKind, texts: &[String]) -> anyhow::Result<Vec<Vec<f32>>> {
+            self.calls.lock().unwrap().push((kind, texts.len()));
+            let query = f32::from(u8::from(kind == EmbedKind::Queries));
+            let mut out: Vec<Vec<f32>> = texts
+                .iter()
+                .map(|t| vec![t.rsplit('-').next().unwrap().parse().unwrap(), query])
+                .collect();
+            if self.short {
+                out.pop();
+            }
+            Ok(out)
+        }
+    }
+
+    impl Embed for Tagging {
+        fn model_id(&self) -> &str {
+            "tagging"
+        }
+        fn dim(&self) -> usize {
+            2
+        }
+        fn embed(&self, texts: &[String]) -> anyhow::Result<Vec<Vec<f32>>> {
+            self.tag(EmbedKind::Documents, texts)
+        }
+        fn embed_queries(&self, texts: &[String]) -> anyhow::Result<Vec<Vec<f32>>> {
+            self.tag(EmbedKind::Queries, texts)
+        }
+    }
+
+    type Reply = oneshot::Receiver<anyhow::Result<Vec<Vec<f32>>>>;
+
+    fn job(kind: EmbedKind, ids: &[u32]) -> (EmbedJob, Reply) {
+        let (reply, rx) = oneshot::channel();
+        let texts = ids.iter().map(|i| format!("text-{i}")).collect();
+        (EmbedJob { kind, texts, reply }, rx)
+    }
+
+    fn tags(rx: Reply) -> Vec<(u32, bool)> {
+        rx.blocking_recv()
+            .unwrap()
+            .unwrap()
+            .into_iter()
+            .map(|v| (v[0] as u32, v[1] == 1.0))
+            .collect()
+    }
+
+    #[test]
+    fn gather_takes_queued_jobs_up_to_max_batch() {
+        let batch = BatchConfig {
+            max_batch: 4,
+            max_wait: Duration::ZERO,
+        };
+        let (tx, rx) = mpsc::channel();
+        let (first, _r0) = job(EmbedKind::Documents, &[0, 1]);
+        let mut replies = Vec::new();
+        for ids in [[2, 3], [4, 5]] {
+            let (j, r) = job(EmbedKind::Documents, &ids);
+            tx.send(j).unwrap();
+            replies.push(r);
+        }
+
+        let jobs = batch.gather(&rx, first);
+        let texts: Vec<&str> = jobs
+            .iter()
+            .flat_map(|j| j.texts.iter().map(String::as_str))
+            .collect();
+        assert_eq!(texts, ["text-0", "text-1", "text-2", "text-3"]);
+        // งานที่เหลือยังอยู่ในคิวให้ batch ถัดไป
+        assert_eq!(rx.try_recv().unwrap().texts, ["text-4", "text-5"]);
+    }
+
+    #[test]
+    fn gather_waits_up_to_max_wait_for_more_jobs() {
+        let batch = BatchConfig {
+            max_batch: 64,
+            max_wait: Duration::from_millis(500),
+        };
+        let (tx, rx) = mpsc::channel();
+        let (first, _r0) = job(EmbedKind::Queries, &[0]);
+        let (late, _r1) = job(EmbedKind::Queries, &[1]);
+        let sender = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            tx.send(late).unwrap();
+            // tx ถูก drop ที่นี่ => gather ไม่ต้องรอจนครบ max_wait
+        });
+        assert_eq!(batch.gather(&rx, first).len(), 2);
+        sender.join().unwrap();
+    }
+
+    #[test]
+    fn batch_is_embedded_once_per_kind_and_scattered_in_order() {
+        let embedder = Tagging::default();
+        let (d1, r1) = job(EmbedKind::Documents, &[1, 2, 3]);
+        let (q1, r2) = job(EmbedKind::Queries, &[4]);
+        let (d2, r3) = job(EmbedKind::Documents, &[5]);
+        let (q2, r4) = job(EmbedKind::Queries, &[6]);
+        let (d3, r5) = job(EmbedKind::Documents, &[7, 8]);
+        embed_batch(&embedder, vec![d1, q1, d2, q2, d3]);
+
+        assert_eq!(tags(r1), [(1, false), (2, false), (3, false)]);
+        assert_eq!(tags(r2), [(4, true)]);
+        assert_eq!(tags(r3), [(5, false)]);
+        assert_eq!(tags(r4), [(6, true)]);
+        assert_eq!(tags(r5), [(7, false), (8, false)]);
+        assert_eq!(
+            *embedder.calls.lock().unwrap(),
+            [(EmbedKind::Documents, 6), (EmbedKind::Queries, 2)]
+        );
+    }
+
+    #[test]
+    fn wrong_vector_count_fails_every_job_in_the_batch() {
+        let embedder = Tagging {
+            short: true,
+            ..Default::default()
+        };
+        let (a, ra) = job(EmbedKind::Documents, &[1, 2]);
+        let (b, rb) = job(EmbedKind::Documents, &[3]);
+        embed_batch(&embedder, vec![a, b]);
+        for rx in [ra, rb] {
+            let err = rx.blocking_recv().unwrap().unwrap_err();
+            assert!(err.to_string().contains("2 vectors for 3 texts"), "{err}");
+        }
+    }
+
+    #[tokio::test]
+    async fn full_queue_is_rejected_without_waiting() {
+        let pool = BlockingPool::start(
+            "test",
+            PoolConfig {
+                workers: 1,
+                queue: 1,
+            },
+        )
+        .unwrap();
+        // worker ตัวเดียวติดงานแรกจนกว่าจะปล่อย, งานที่สองเต็มคิวพอดี
+        let (started_tx, started_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        pool.queue
+            .submit(Box::new(move || {
+                started_tx.send(()).unwrap();
+                let _ = release_rx.recv();
+            }))
+            .unwrap();
+        started_rx.recv().unwrap();
+        pool.queue.submit(Box::new(|| {})).unwrap();
+
+        let submitted = Instant::now();
+        let err = pool.run(|| ()).await.unwrap_err();
+        assert!(matches!(err, PoolError::Saturated("test")), "{err}");
+        assert!(submitted.elapsed() < Duration::from_secs(1));
+        let (status, _) = <(StatusCode, String)>::from(err);
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        drop(release_tx);
+    }
+
+    #[tokio::test]
+    async fn concurrent_requests_each_get_their_own_vectors() {
+        let embedder = Arc::new(Tagging::default());
+        let pool = Arc::new(
+            EmbedPool::start(
+                embedder.clone(),
+                PoolConfig {
+                    workers: 1,
+                    queue: 64,
+                },
+                BatchConfig {
+                    max_batch: 16,
+                    max_wait: Duration::from_millis(20),
+                },
+            )
+            .unwrap(),
+        );
+
+        let mut tasks = Vec::new();
+        for i in 0..20u32 {
+            let pool = pool.clone();
+            tasks.push(tokio::spawn(async move {
+                if i % 3 == 0 {
+                    let v = pool.embed_query(format!("q-{i}")).await.unwrap();
+                    assert_eq!(v, [i as f32, 1.0]);
+                } else {
+                    let texts = vec![format!("a-{i}"), format!("b-{}", i + 100)];
+                    let v = pool.embed_documents(texts).await.unwrap();
+                    assert_eq!(v, [[i as f32, 0.0], [(i + 100) as f32, 0.0]]);
+                }
+            }));
+        }
+        for t in tasks {
+            t.await.unwrap();
+        }
+        // ทุกข้อความถูก embed ครั้งเดียว (รวม batch กันได้ แต่ไม่ซ้ำ)
+        let calls = embedder.calls.lock().unwrap();
+        assert_eq!(calls.iter().map(|&(_, n)| n).sum::<usize>(), 7 + 13 * 2);
+    }
+}
@@ -0,0 +1,243 @@
+use anyhow::{bail, Result};
+use std::env;
+use std::sync::Arc;
+use unicode_normalization::UnicodeNormalization;
+
+use crate::embedder::Embed;
+
+/// ขั้นตอนเริ่มต้นของ `TEXT_PREPROCESS`
+const DEFAULT_STEPS: &str = "html,nfkc,whitespace";
+
+/// ทำความสะอาดข้อความก่อน embed (ทั้ง insert / update / rebuild และ query).
+/// `StoredReview.review` ยังเก็บข้อความเดิม — เฉพาะเวกเตอร์ที่มาจากข้อความที่ผ่านขั้นตอนนี้
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Preprocessor {
+    /// ลบ tag HTML (แทนด้วยช่องว่าง) แล้ว decode entity (`&amp;`, `&#39;`, …)
+    pub html: bool,
+    /// Unicode NFKC (ตัวอักษรเต็มความกว้าง, ligature, …)
+    pub nfkc: bool,
+    /// ตัดช่องว่างหัวท้าย, ยุบช่องว่าง / ขึ้นบรรทัดซ้ำเป็นช่องว่างเดียว
+    pub whitespace: bool,
+    pub lowercase: bool,
+}
+
+impl Preprocessor {
+    /// `TEXT_PREPROCESS`: รายการคั่นด้วยจุลภาคจาก `html`, `nfkc`, `whitespace`, `lowercase`
+    /// (ค่าเริ่มต้น `html,nfkc,whitespace`; `none` = ส่งข้อความเดิมให้ model). ลำดับการทำคงที่ตามนี้
+    pub fn from_env() -> Result<Self> {
+        let steps = env::var("TEXT_PREPROCESS").unwrap_or_else(|_| DEFAULT_STEPS.into());
+        let mut p = Self::default();
+        for step in steps.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            match step.to_ascii_lowercase().as_str() {
+                "none" => {}
+                "html" => p.html = true,
+                "nfkc" => p.nfkc = true,
+                "whitespace" => p.whitespace = true,
+                "lowercase" => p.lowercase = true,
+                other => bail!(
+                    "TEXT_PREPROCESS: unknown step {other:?} (expected html, nfkc, whitespace, lowercase or none)"
+                ),
+            }
+        }
+        Ok(p)
+    }
+
+    pub fn is_noop(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// สำหรับ log ตอนเริ่ม
+    pub fn describe(&self) -> String {
+        let steps: Vec<&str> = [
+            (self.html, "html"),
+            (self.nfkc, "nfkc"),
+            (self.whitespace, "whitespace"),
+            (self.lowercase, "lowercase"),
+        ]
+        .into_iter()
+        .filter_map(|(on, name)| on.then_some(name))
+        .collect();
+        if steps.is_empty() {
+            "none".into()
+        } else {
+            steps.join(",")
+        }
+    }
+
+    pub fn apply(&self, text: &str) -> String {
+        let mut s = text.to_string();
+        if self.html {
+            s = html_escape::decode_html_entities(&strip_tags(&s)).into_owned();
+        }
+        if self.nfkc {
+            s = s.nfkc().collect();
+        }
+        if self.whitespace {
+            s = s.split_whitespace().collect::<Vec<_>>().join(" ");
+        }
+        if self.lowercase {
+            s = s.to_lowercase();
+        }
+        s
+    }
+
+    /// ห่อ embedder ให้ทุกเส้นทางที่ embed (handler, rebuild, repair) ใช้ข้อความแบบเดียวกัน
+    pub fn wrap(self, inner: Arc<dyn Embed>) -> Arc<dyn Embed> {
+        if self.is_noop() {
+            return inner;
+        }
+        Arc::new(Preprocessed { inner, pre: self })
+    }
+}
+
+/// ลบ `<tag …>`, `</tag>`, `<!-- … -->` (ต้องขึ้นต้นด้วยตัวอักษร, `/`, `!` หรือ `?` หลัง `<`
+/// เพื่อไม่กิน "<3" หรือ "a < b"); tag ที่ไม่มี `>` ปิดคงไว้ตามเดิม
+fn strip_tags(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('<') {
+        let after = &rest[start + 1..];
+        let is_tag = after
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || matches!(c, '/' | '!' | '?'));
+        match after.find('>') {
+            Some(end) if is_tag => {
+                out.push_str(&rest[..start]);
+                out.push(' ');
+                rest = &after[end + 1..];
+            }
+            _ => {
+                out.push_str(&rest[..=start]);
+                rest = after;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+struct Preprocessed {
+    inner: Arc<dyn Embed>,
+    pre: Preprocessor,
+}
+
+impl Preprocessed {
+    fn clean(&self, texts: &[String]) -> Vec<String> {
+        texts.iter().map(|t| self.pre.apply(t)).collect()
+    }
+}
+
+impl Embed for Preprocessed {
+    fn model_id(&self) -> &str {
+        self.inner.model_id()
+    }
+
+    fn dim(&self) -> usize {
+        self.inner.dim()
+    }
+
+    fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        self.inner.embed(&self.clean(texts))
+    }
+
+    // ทำความสะอาดก่อน แล้วให้ model เติม prefix (prefix ไม่ผ่าน lowercase ฯลฯ)
+    fn embed_documents(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        self.inner.embed_documents(&self.clean(texts))
+    }
+
+    fn embed_queries(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        self.inner.embed_queries(&self.clean(texts))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::embedder::{HashEmbedder, Prefixes};
+
+    const ALL: Preprocessor = Preprocessor {
+        html: true,
+        nfkc: true,
+        whitespace: true,
+        lowercase: true,
+    };
+
+    #[test]
+    fn strip_tags_removes_markup_only() {
+        assert_eq!(strip_tags("<p>Great <b>food</b></p>"), " Great  food  ");
+        assert_eq!(strip_tags("a<br/>b<!-- note -->c"), "a b c");
+        assert_eq!(strip_tags("<?xml version=\"1.0\"?>ok"), " ok");
+        // ไม่ใช่ tag
+        assert_eq!(
+            strip_tags("I <3 it, 1 < 2 and 3 > 2"),
+            "I <3 it, 1 < 2 and 3 > 2"
+        );
+        // tag ไม่ปิด คงไว้
+        assert_eq!(strip_tags("broken <b unclosed"), "broken <b unclosed");
+        assert_eq!(strip_tags("ไทย<i>ดี</i>มาก"), "ไทย ดี มาก");
+    }
+
+    #[test]
+    fn html_step_decodes_entities_after_stripping() {
+        let html = Preprocessor {
+            html: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            html.apply("Fish &amp; chips&#39;s <em>best</em> &lt;3"),
+            "Fish & chips's  best  <3"
+        );
+    }
+
+    #[test]
+    fn nfkc_folds_compatibility_characters() {
+        let nfkc = Preprocessor {
+            nfkc: true,
+            ..Default::default()
+        };
+        assert_eq!(nfkc.apply("ＡＢＣ１２３"), "ABC123");
+        assert_eq!(nfkc.apply("ﬁne ﬂavor"), "fine flavor");
+        assert_eq!(nfkc.apply("①"), "1");
+        // NFKC ไม่เปลี่ยนตัวพิมพ์
+        assert_eq!(nfkc.apply("Ｃafé"), "Café");
+    }
+
+    #[test]
+    fn steps_run_in_a_fixed_order() {
+        assert_eq!(
+            ALL.apply("  <p>ＧＲＥＡＴ\n\n&nbsp;Ｓｕｓｈｉ</p>\t"),
+            "great sushi"
+        );
+        let none = Preprocessor::default();
+        assert!(none.is_noop());
+        assert_eq!(none.apply(" <b>As Is</b> "), " <b>As Is</b> ");
+    }
+
+    #[test]
+    fn describe_lists_enabled_steps() {
+        assert_eq!(ALL.describe(), "html,nfkc,whitespace,lowercase");
+        assert_eq!(Preprocessor::default().describe(), "none");
+    }
+
+    #[test]
+    fn wrapped_embedder_sees_cleaned_text() {
+        let inner: Arc<dyn Embed> = Arc::new(HashEmbedder::new(32, Prefixes::default()));
+        assert!(Arc::ptr_eq(
+            &Preprocessor::default().wrap(inner.clone()),
+            &inner
+        ));
+
+        let wrapped = ALL.wrap(inner.clone());
+        let raw = vec!["<b>ＮＯＯＤＬＥＳ</b>  &amp; Soup".to_string()];
+        let clean = vec!["noodles & soup".to_string()];
+        assert_eq!(
+            wrapped.embed_documents(&raw).unwrap(),
+            inner.embed_documents(&clean).unwrap()
+        );
+        assert_eq!(
+            wrapped.embed_queries(&raw).unwrap(),
+            inner.embed_queries(&clean).unwrap()
+        );
+    }
+}
@@ -0,0 +1,176 @@
+use lru::LruCache;
+use std::env;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::types::QueryCacheStats;
+
+/// LRU ของเวกเตอร์ query บนเส้นทาง search (`QUERY_CACHE_SIZE` รายการ, 0 = ปิด).
+/// key คือข้อความ query ที่ normalize แล้ว; ทั้งก้อนผูกกับ model id — model เปลี่ยนเมื่อไรก็ล้างทิ้ง
+pub struct QueryCache {
+    inner: Mutex<Inner>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+struct Inner {
+    model: String,
+    lru: Option<LruCache<String, Vec<f32>>>,
+}
+
+impl QueryCache {
+    pub fn new(model: &str, capacity: usize) -> Self {
+        Self {
+            inner: Mutex::new(Inner {
+                model: model.to_string(),
+                lru: NonZeroUsize::new(capacity).map(LruCache::new),
+            }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn from_env(model: &str) -> Self {
+        let capacity = env::var("QUERY_CACHE_SIZE")
+            .ok()
+            .and_then(|s| s.trim().parse().ok())
+            .unwrap_or(1024);
+        Self::new(model, capacity)
+    }
+
+    /// ตัดช่องว่างหัวท้ายและยุบช่องว่างซ้ำ — query ที่ต่างกันแค่ช่องว่างใช้เวกเตอร์เดียวกัน
+    fn key(query: &str) -> String {
+        query.split_whitespace().collect::<Vec<_>>().join(" ")
+    }
+
+    /// ล้างเมื่อ `model` ไม่ใช่ model ที่ cache ผูกอยู่
+    fn for_model<'a>(
+        inner: &'a mut Inner,
+        model: &str,
+    ) -> Option<&'a mut LruCache<String, Vec<f32>>> {
+        if inner.model != model {
+            inner.model = model.to_string();
+            if let Some(lru) = inner.lru.as_mut() {
+                lru.clear();
+            }
+        }
+        inner.lru.as_mut()
+    }
+
+    pub fn get(&self, model: &str, query: &str) -> Option<Vec<f32>> {
+        let mut inner = self.inner.lock().ok()?;
+        let lru = Self::for_model(&mut inner, model)?;
+        let hit = lru.get(&Self::key(query)).cloned();
+        let counter = if hit.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        hit
+    }
+
+    pub fn put(&self, model: &str, query: &str, vector: &[f32]) {
+        let Ok(mut inner) = self.inner.lock() else {
+            return;
+        };
+        if let Some(lru) = Self::for_model(&mut inner, model) {
+            lru.put(Self::key(query), vector.to_vec());
+        }
+    }
+
+    pub fn clear(&self) {
+        if let Ok(mut inner) = self.inner.lock() {
+            if let Some(lru) = inner.lru.as_mut() {
+                lru.clear();
+            }
+        }
+    }
+
+    pub fn stats(&self) -> QueryCacheStats {
+        let (model, capacity, entries) = match self.inner.lock() {
+            Ok(inner) => (
+                inner.model.clone(),
+                inner.lru.as_ref().map_or(0, |l| l.cap().get()),
+                inner.lru.as_ref().map_or(0, |l| l.len()),
+            ),
+            Err(_) => Default::default(),
+        };
+        QueryCacheStats {
+            model,
+            capacity,
+            entries,
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODEL: &str = "model-a";
+
+    #[test]
+    fn evicts_the_least_recently_used_query() {
+        let cache = QueryCache::new(MODEL, 2);
+        cache.put(MODEL, "a", &[1.0]);
+        cache.put(MODEL, "b", &[2.0]);
+        // ใช้ "a" ล่าสุด => "b" ถูกไล่ออกเมื่อเพิ่ม "c"
+        assert_eq!(cache.get(MODEL, "a"), Some(vec![1.0]));
+        cache.put(MODEL, "c", &[3.0]);
+
+        assert_eq!(cache.get(MODEL, "b"), None);
+        assert_eq!(cache.get(MODEL, "a"), Some(vec![1.0]));
+        assert_eq!(cache.get(MODEL, "c"), Some(vec![3.0]));
+        assert_eq!(cache.stats().entries, 2);
+    }
+
+    #[test]
+    fn counts_hits_and_misses() {
+        let cache = QueryCache::new(MODEL, 8);
+        assert_eq!(cache.get(MODEL, "pizza"), None);
+        cache.put(MODEL, "pizza", &[0.5]);
+        // ต่างกันแค่ช่องว่าง => key เดียวกัน
+        assert!(cache.get(MODEL, "  pizza ").is_some());
+        assert!(cache.get(MODEL, "pizza").is_some());
+        assert_eq!(cache.get(MODEL, "cheap  pizza"), None);
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (2, 2));
+        assert_eq!((stats.capacity, stats.entries), (8, 1));
+        assert_eq!(stats.model, MODEL);
+    }
+
+    #[test]
+    fn clear_drops_entries_but_keeps_counters() {
+        let cache = QueryCache::new(MODEL, 8);
+        cache.put(MODEL, "q", &[1.0]);
+        assert!(cache.get(MODEL, "q").is_some());
+        cache.clear();
+        assert_eq!(cache.get(MODEL, "q"), None);
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.hits, stats.misses), (0, 1, 1));
+    }
+
+    #[test]
+    fn switching_model_empties_the_cache() {
+        let cache = QueryCache::new(MODEL, 8);
+        cache.put(MODEL, "q", &[1.0]);
+        assert_eq!(cache.get("model-b", "q"), None);
+        assert_eq!(cache.stats().model, "model-b");
+        // กลับไป model เดิมก็ไม่ได้เวกเตอร์เก่าคืน
+        assert_eq!(cache.get(MODEL, "q"), None);
+    }
+
+    #[test]
+    fn zero_capacity_disables_the_cache() {
+        let cache = QueryCache::new(MODEL, 0);
+        cache.put(MODEL, "q", &[1.0]);
+        assert_eq!(cache.get(MODEL, "q"), None);
+        let stats = cache.stats();
+        assert_eq!((stats.capacity, stats.entries), (0, 0));
+    }
+}
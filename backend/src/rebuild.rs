@@ -0,0 +1,469 @@
+use anyhow::{anyhow, Context, Result};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::env;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::{info, warn};
+
+use crate::embedder::{self, Embed};
+use crate::handlers::{AppState, Paths};
+use crate::index::{IndexConfig, VectorIndex};
+use crate::lock::read_only;
+use crate::storage::{load_all_reviews, load_deleted_vector_ids};
+use crate::types::{RebuildStatus, StoredReview};
+
+/// ชื่อโฟลเดอร์ชั่วคราว (อยู่ในไดเรกทอรีเดียวกับ index เพื่อให้ rename เป็น atomic)
+const TMP_DIR_NAME: &str = ".rebuild";
+
+/// commit marker ใน `.rebuild/`: มีไฟล์นี้ = index ใหม่สมบูรณ์แล้ว ต้องติดตั้งให้จบ
+/// (crash กลางการย้ายไฟล์ถูกทำต่อโดย `finish_install` ตอนเปิดครั้งถัดไป)
+const INSTALL_MARKER: &str = ".install";
+
+/// ไฟล์ที่ต้องย้ายจาก `.rebuild/` และไฟล์ index เดิมที่ต้องลบ (ชื่อไฟล์ใน index dir)
+#[derive(Debug, Serialize, Deserialize)]
+struct InstallMarker {
+    install: Vec<String>,
+    remove: Vec<String>,
+}
+
+/// แถวที่ยัง live: แถวล่าสุดต่อ vector_id ที่ไม่ถูกลบ เรียงตาม vector_id
+pub fn live_rows(reviews: Vec<StoredReview>, deleted: &HashSet<usize>) -> Vec<StoredReview> {
+    let mut by_vec: BTreeMap<usize, StoredReview> = BTreeMap::new();
+    for r in reviews {
+        if !deleted.contains(&r.vector_id) {
+            by_vec.insert(r.vector_id, r);
+        }
+    }
+    by_vec.into_values().collect()
+}
+
+fn batch_size() -> usize {
+    env::var("REBUILD_BATCH")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(64)
+        .max(1)
+}
+
+fn index_dir(index_path: &str) -> PathBuf {
+    Path::new(index_path)
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or(Path::new("."))
+        .to_path_buf()
+}
+
+/// `(vector_id, ข้อความ)` ของทุกเวกเตอร์ใน `rows` (หนึ่งต่อ chunk)
+pub fn vector_texts(rows: &[StoredReview]) -> Vec<(usize, String)> {
+    rows.iter().flat_map(StoredReview::vector_texts).collect()
+}
+
+/// embed `units` ทีละ `REBUILD_BATCH` เวกเตอร์ แล้ว `add_batch` ด้วย vector_id ของแต่ละตัว
+pub fn embed_into(
+    embedder: &dyn Embed,
+    index: &mut dyn VectorIndex,
+    units: &[(usize, String)],
+    mut progress: impl FnMut(usize, usize),
+) -> Result<()> {
+    if units.is_empty() {
+        return Ok(());
+    }
+    let total = units.len();
+    let mut done = 0;
+
+    for batch in units.chunks(batch_size()) {
+        let texts: Vec<String> = batch.iter().map(|(_, t)| t.clone()).collect();
+        let vectors = embedder.embed_documents(&texts).context("embedding failed")?;
+        if vectors.len() != batch.len() {
+            return Err(anyhow!("Embedding count mismatch"));
+        }
+        let flat: Vec<f32> = vectors.concat();
+        let ids: Vec<i64> = batch.iter().map(|&(v, _)| v as i64).collect();
+        index.add_batch(&flat, Some(&ids))?;
+
+        done += batch.len();
+        progress(done, total);
+    }
+    Ok(())
+}
+
+/// index ใหม่ที่สร้างในโฟลเดอร์ชั่วคราว ยังไม่ถูกติดตั้งแทนของเดิม
+pub struct StagedIndex {
+    tmp_dir: PathBuf,
+    embedder: Arc<dyn Embed>,
+    index: Box<dyn VectorIndex>,
+    vector_ids: HashSet<usize>,
+}
+
+impl StagedIndex {
+    /// สร้าง index เปล่าใน `<index_dir>/.rebuild/` (ลบของค้างจากรอบก่อนทิ้ง)
+    pub fn create(cfg: &IndexConfig, index_path: &str, embedder: Arc<dyn Embed>) -> Result<Self> {
+        finish_install(index_path)?;
+        let tmp_dir = index_dir(index_path).join(TMP_DIR_NAME);
+        if tmp_dir.exists() {
+            warn!("removing stale {}", tmp_dir.display());
+            fs::remove_dir_all(&tmp_dir)?;
+        }
+        fs::create_dir_all(&tmp_dir)?;
+
+        let file_name = Path::new(index_path)
+            .file_name()
+            .ok_or_else(|| anyhow!("index path has no file name: {index_path}"))?;
+        let tmp_index = tmp_dir.join(file_name);
+        let index = cfg
+            .open(&tmp_index.to_string_lossy())
+            .with_context(|| format!("open staging index at {}", tmp_index.display()))?;
+        Ok(Self {
+            tmp_dir,
+            embedder,
+            index,
+            vector_ids: HashSet::new(),
+        })
+    }
+
+    /// embed ทีละ batch แล้วเพิ่มลง index; `progress(done, total)` หลังแต่ละ batch
+    pub fn add_rows(
+        &mut self,
+        rows: &[StoredReview],
+        progress: impl FnMut(usize, usize),
+    ) -> Result<()> {
+        embed_into(&*self.embedder, &mut *self.index, &vector_texts(rows), progress)?;
+        self.vector_ids.extend(rows.iter().flat_map(StoredReview::vector_ids));
+        Ok(())
+    }
+
+    /// ตามเก็บการเปลี่ยนแปลงที่เกิดระหว่าง rebuild (ต้องเรียกตอนที่หยุดการเขียนแล้ว)
+    pub fn catch_up(&mut self, live: &[StoredReview]) -> Result<()> {
+        let live_ids: HashSet<usize> = live.iter().flat_map(StoredReview::vector_ids).collect();
+        let gone: Vec<i64> = self
+            .vector_ids
+            .difference(&live_ids)
+            .map(|&v| v as i64)
+            .collect();
+        if !gone.is_empty() {
+            self.index.delete(&gone)?;
+            for v in &gone {
+                self.vector_ids.remove(&(*v as usize));
+            }
+        }
+
+        let missing: Vec<StoredReview> = live
+            .iter()
+            .filter(|r| !self.vector_ids.contains(&r.vector_id))
+            .cloned()
+            .collect();
+        if !missing.is_empty() || !gone.is_empty() {
+            info!(
+                "rebuild catch-up: +{} / -{} vector(s) changed during rebuild",
+                missing.len(),
+                gone.len()
+            );
+        }
+        self.add_rows(&missing, |_, _| {})
+    }
+
+    /// จำนวนเวกเตอร์ (chunk) ที่อยู่ใน index ใหม่
+    pub fn vector_count(&self) -> usize {
+        self.vector_ids.len()
+    }
+
+    /// save + fsync ไฟล์ใน staging, เขียน commit marker (รายการไฟล์ที่จะย้าย และไฟล์ index เดิม
+    /// ที่ staging ไม่มี — เช่น `.del` เก่า — ซึ่งต้องลบ) แล้วติดตั้งตาม marker และเปิด index
+    /// จากตำแหน่งจริง. crash หลังเขียน marker => `finish_install` ทำต่อตอนเปิดครั้งถัดไป
+    /// จึงไม่มีทางเหลือไฟล์เก่าปนไฟล์ใหม่
+    pub fn install(self, cfg: &IndexConfig, index_path: &str) -> Result<Box<dyn VectorIndex>> {
+        let marker = self.commit(cfg, index_path)?;
+        apply_install(&index_dir(index_path), &marker)?;
+        Ok(cfg.open(index_path)?)
+    }
+
+    /// ขั้นแรกของ `install`: หลังจากนี้ index ใหม่ถูกติดตั้งแน่นอน (ตอนนี้ หรือตอนเปิดครั้งถัดไป)
+    fn commit(mut self, cfg: &IndexConfig, index_path: &str) -> Result<InstallMarker> {
+        self.index.save()?;
+        drop(self.index);
+
+        let mut install = Vec::new();
+        for entry in fs::read_dir(&self.tmp_dir)? {
+            let entry = entry?;
+            File::open(entry.path())?.sync_all()?;
+            install.push(entry.file_name().to_string_lossy().into_owned());
+        }
+        let remove = cfg
+            .files(index_path)
+            .iter()
+            .filter_map(|p| p.file_name())
+            .map(|n| n.to_string_lossy().into_owned())
+            .filter(|n| !install.contains(n))
+            .collect();
+        let marker = InstallMarker { install, remove };
+        let marker_path = self.tmp_dir.join(INSTALL_MARKER);
+        let tmp = marker_path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec(&marker)?)?;
+        File::open(&tmp)?.sync_all()?;
+        fs::rename(&tmp, &marker_path)?;
+        File::open(&self.tmp_dir)?.sync_all()?;
+        Ok(marker)
+    }
+}
+
+/// ติดตั้ง index ที่ rebuild เสร็จแล้วให้จบ ถ้ามี commit marker ค้างใน `.rebuild/`
+/// (idempotent: ไฟล์ที่ย้าย / ลบไปแล้วถูกข้าม). เรียกก่อนเปิด index ทุกครั้ง;
+/// ไม่มี marker = ไม่ทำอะไร (staging ที่ไม่สมบูรณ์ถูกลบตอน rebuild ครั้งถัดไป)
+pub fn finish_install(index_path: &str) -> Result<()> {
+    let dest_dir = index_dir(index_path);
+    let tmp_dir = dest_dir.join(TMP_DIR_NAME);
+    let marker_path = tmp_dir.join(INSTALL_MARKER);
+    let bytes = match fs::read(&marker_path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    if read_only() {
+        return Err(anyhow!(
+            "{}: a rebuilt index was not fully installed; start once without READ_ONLY to finish it",
+            marker_path.display()
+        ));
+    }
+    let marker: InstallMarker = serde_json::from_slice(&bytes)
+        .with_context(|| format!("parse {}", marker_path.display()))?;
+    warn!("finishing the install of a rebuilt index from {}", tmp_dir.display());
+    apply_install(&dest_dir, &marker)
+}
+
+/// ย้ายไฟล์ตาม marker, ลบไฟล์เดิมที่ index ใหม่ไม่มี แล้วลบ marker + `.rebuild/`
+fn apply_install(dest_dir: &Path, marker: &InstallMarker) -> Result<()> {
+    let tmp_dir = dest_dir.join(TMP_DIR_NAME);
+    for name in &marker.install {
+        let (from, to) = (tmp_dir.join(name), dest_dir.join(name));
+        if from.exists() {
+            fs::rename(&from, &to)
+                .with_context(|| format!("move {} -> {}", from.display(), to.display()))?;
+        }
+    }
+    for name in &marker.remove {
+        match fs::remove_file(dest_dir.join(name)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+    File::open(dest_dir)?.sync_all()?;
+    fs::remove_file(tmp_dir.join(INSTALL_MARKER))?;
+    fs::remove_dir_all(&tmp_dir)?;
+    Ok(())
+}
+
+/// rebuild ขณะ server รันอยู่: สร้างใน staging, หยุดการเขียนชั่วคราว,
+/// catch-up แล้วสลับเข้า `AppState.index`; คืนจำนวนเวกเตอร์ใน index ใหม่
+pub fn rebuild_live(state: &AppState) -> Result<usize> {
+    let cfg = IndexConfig::from_env()?;
+    let paths: Paths = state
+        .paths
+        .read()
+        .map_err(|_| anyhow!("paths lock poisoned"))?
+        .clone();
+    let deleted = state
+        .deleted
+        .read()
+        .map_err(|_| anyhow!("deleted lock poisoned"))?
+        .clone();
+
+    let rows = live_rows(load_all_reviews(&paths.jsonl_path)?, &deleted);
+    set_progress(state, |s| s.total = rows.iter().map(StoredReview::vector_count).sum());
+
+    let mut staged = StagedIndex::create(&cfg, &paths.index_path, state.embedder.clone())?;
+    staged.add_rows(&rows, |done, total| {
+        set_progress(state, |s| {
+            s.embedded = done;
+            s.total = total;
+        });
+    })?;
+
+    // หยุดการเขียนทั้งหมดระหว่าง catch-up + swap
+    let _quiesce = state
+        .write_gate
+        .write()
+        .map_err(|_| anyhow!("write gate poisoned"))?;
+    let current_index = state
+        .paths
+        .read()
+        .map_err(|_| anyhow!("paths lock poisoned"))?
+        .index_path
+        .clone();
+    if current_index != paths.index_path {
+        return Err(anyhow!("index path changed during rebuild; not installing"));
+    }
+    let deleted = state
+        .deleted
+        .read()
+        .map_err(|_| anyhow!("deleted lock poisoned"))?
+        .clone();
+    let live = live_rows(load_all_reviews(&paths.jsonl_path)?, &deleted);
+    staged.catch_up(&live)?;
+    let vectors = staged.vector_count();
+
+    let mut idx = state
+        .index
+        .write()
+        .map_err(|_| anyhow!("index lock poisoned"))?;
+    *idx = staged.install(&cfg, &paths.index_path)?;
+    if let Ok(mut unsaved) = state.unsaved_vectors.write() {
+        *unsaved = 0;
+    }
+    Ok(vectors)
+}
+
+fn set_progress(state: &AppState, f: impl FnOnce(&mut RebuildStatus)) {
+    if let Ok(mut s) = state.rebuild.write() {
+        f(&mut s);
+    }
+}
+
+/// `backend rebuild`: rebuild แบบ offline (server ต้องไม่รันอยู่)
+pub fn run_cli(paths: &Paths) -> Result<()> {
+    let cfg = IndexConfig::from_env()?;
+    let deleted = load_deleted_vector_ids(&paths.jsonl_path)?;
+    let rows = live_rows(load_all_reviews(&paths.jsonl_path)?, &deleted);
+    info!(
+        "rebuilding {:?} index at {} from {} review(s)",
+        cfg.backend,
+        paths.index_path,
+        rows.len()
+    );
+
+    let started = Utc::now();
+    let mut staged = StagedIndex::create(&cfg, &paths.index_path, embedder::from_env()?)?;
+    staged.add_rows(&rows, |done, total| info!("embedded {done}/{total}"))?;
+    let vectors = staged.vector_count();
+    staged.install(&cfg, &paths.index_path)?;
+
+    info!(
+        "rebuild done: {vectors} vector(s) from {} review(s) in {}s",
+        rows.len(),
+        (Utc::now() - started).num_seconds()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::embedder::{HashEmbedder, Prefixes};
+    use crate::hnsw::HnswParams;
+    use crate::index::IndexBackend;
+    use tempfile::TempDir;
+
+    const DIM: usize = 8;
+
+    fn config() -> IndexConfig {
+        IndexConfig {
+            backend: IndexBackend::Hnsw,
+            model: "hash".into(),
+            dim: DIM,
+            document_prefix: String::new(),
+            preprocess: "none".into(),
+            spfresh_params: String::new(),
+            hnsw: HnswParams::default(),
+        }
+    }
+
+    fn vector(seed: usize) -> Vec<f32> {
+        (0..DIM)
+            .map(|i| ((seed * 7 + i) % 5) as f32 + 1.0)
+            .collect()
+    }
+
+    /// index เดิมที่มี id 0..3 และลบ id 1 แล้ว (มีไฟล์ `.hnsw.vec.del`)
+    fn old_index(cfg: &IndexConfig, index_path: &str) {
+        let mut index = cfg.open(index_path).unwrap();
+        let flat: Vec<f32> = (0..3).flat_map(vector).collect();
+        index.add_batch(&flat, Some(&[0, 1, 2])).unwrap();
+        index.delete(&[1]).unwrap();
+        index.save().unwrap();
+    }
+
+    fn staged(cfg: &IndexConfig, index_path: &str) -> StagedIndex {
+        let embedder = Arc::new(HashEmbedder::new(DIM, Prefixes::default()));
+        let mut staged = StagedIndex::create(cfg, index_path, embedder).unwrap();
+        let flat: Vec<f32> = [10, 11].into_iter().flat_map(vector).collect();
+        staged.index.add_batch(&flat, Some(&[10, 11])).unwrap();
+        staged
+    }
+
+    fn sorted_ids(index: &dyn VectorIndex) -> Vec<i64> {
+        let mut ids = index.ids().unwrap();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn install_replaces_every_file_and_drops_stale_ones() {
+        let dir = TempDir::new().unwrap();
+        let cfg = config();
+        let index_path = dir
+            .path()
+            .join("reviews.index")
+            .to_string_lossy()
+            .into_owned();
+        old_index(&cfg, &index_path);
+        let del = dir.path().join("reviews.hnsw.vec.del");
+        assert!(del.exists());
+
+        // staging ไม่มี `.del` (แบบ SPFresh ที่สร้างตอนลบครั้งแรก): ไฟล์เดิมต้องถูกลบ
+        // ไม่งั้น tombstone เก่าจะไปลบ id ใน index ใหม่
+        let staged = staged(&cfg, &index_path);
+        fs::remove_file(staged.tmp_dir.join("reviews.hnsw.vec.del")).unwrap();
+        let index = staged.install(&cfg, &index_path).unwrap();
+        assert_eq!(sorted_ids(&*index), vec![10, 11]);
+        assert!(!del.exists() || fs::metadata(&del).unwrap().len() == 0);
+        assert!(!dir.path().join(TMP_DIR_NAME).exists());
+    }
+
+    #[test]
+    fn interrupted_install_is_rolled_forward() {
+        let dir = TempDir::new().unwrap();
+        let cfg = config();
+        let index_path = dir
+            .path()
+            .join("reviews.index")
+            .to_string_lossy()
+            .into_owned();
+        old_index(&cfg, &index_path);
+
+        // crash หลังย้ายไปได้ไฟล์เดียว: กราฟใหม่ แต่เวกเตอร์ยังเป็นของเดิม
+        staged(&cfg, &index_path).commit(&cfg, &index_path).unwrap();
+        let tmp_dir = dir.path().join(TMP_DIR_NAME);
+        fs::rename(
+            tmp_dir.join("reviews.hnsw"),
+            dir.path().join("reviews.hnsw"),
+        )
+        .unwrap();
+
+        finish_install(&index_path).unwrap();
+        assert!(!tmp_dir.exists());
+        let del = dir.path().join("reviews.hnsw.vec.del");
+        assert_eq!(fs::metadata(&del).map(|m| m.len()).unwrap_or(0), 0);
+        assert_eq!(sorted_ids(&*cfg.open(&index_path).unwrap()), vec![10, 11]);
+        // ไม่มี marker แล้ว: ไม่มีอะไรให้ทำ
+        finish_install(&index_path).unwrap();
+    }
+
+    #[test]
+    fn unfinished_staging_without_marker_keeps_the_old_index() {
+        let dir = TempDir::new().unwrap();
+        let cfg = config();
+        let index_path = dir
+            .path()
+            .join("reviews.index")
+            .to_string_lossy()
+            .into_owned();
+        old_index(&cfg, &index_path);
+        let staged = staged(&cfg, &index_path);
+        drop(staged);
+
+        finish_install(&index_path).unwrap();
+        assert_eq!(sorted_ids(&*cfg.open(&index_path).unwrap()), vec![0, 2]);
+    }
+}
@@ -0,0 +1,142 @@
+use anyhow::{anyhow, Result};
+use serde::de::IgnoredAny;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::env;
+
+use crate::storage::{load_all_reviews, load_reviews_checked, ReviewOffsets, RowLocation};
+use crate::types::{ReviewId, StoredReview};
+
+/// เก็บแถวรีวิวไว้ที่ไหน (`METADATA_STORE`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StoreMode {
+    /// ทุกแถวอยู่ในหน่วยความจำ (default)
+    Memory,
+    /// เก็บแค่ตำแหน่ง (segment, offset, len) แล้ว seek อ่านจาก segment — สำหรับชุดข้อมูลที่ใหญ่เกิน RAM
+    Offsets,
+}
+
+impl StoreMode {
+    pub fn from_env() -> Result<Self> {
+        match env::var("METADATA_STORE")
+            .unwrap_or_else(|_| "memory".into())
+            .to_ascii_lowercase()
+            .as_str()
+        {
+            "memory" => Ok(Self::Memory),
+            "offsets" => Ok(Self::Offsets),
+            other => Err(anyhow!(
+                "unknown METADATA_STORE: {other} (expected memory|offsets)"
+            )),
+        }
+    }
+}
+
+enum Rows {
+    Memory(HashMap<usize, StoredReview>),
+    Offsets(ReviewOffsets),
+}
+
+#[derive(Deserialize)]
+struct RowKey {
+    id: ReviewId,
+    vector_id: usize,
+    #[serde(default)]
+    chunks: Vec<IgnoredAny>,
+}
+
+/// vector_id → แถวล่าสุดที่ใช้ vector นั้น และ review id → vector_id ของแถวล่าสุด.
+/// โหลดครั้งเดียวตอนเปิดชุดข้อมูล แล้วอัปเดตทุกครั้งที่ append แถวใหม่
+/// (search ไม่ต้องอ่าน reviews.jsonl ทั้งไฟล์อีก)
+pub struct ReviewStore {
+    rows: Rows,
+    current: HashMap<ReviewId, usize>,
+    /// vector_id ของ chunk ที่ 1.. → vector_id แรกของแถว (แถวเก็บไว้ที่ id แรกที่เดียว)
+    chunk_parent: HashMap<usize, usize>,
+}
+
+impl ReviewStore {
+    pub fn load(jsonl_path: &str) -> Result<Self> {
+        // ทำให้ sidecar `<segment>.idx` ครบก่อนมีการ append ใหม่ (ทุกโหมด)
+        let offsets = ReviewOffsets::open(jsonl_path)?;
+        match StoreMode::from_env()? {
+            StoreMode::Memory => {
+                let mut by_vec = HashMap::new();
+                let mut current = HashMap::new();
+                let mut chunk_parent = HashMap::new();
+                for r in load_all_reviews(jsonl_path)? {
+                    current.insert(r.id.clone(), r.vector_id);
+                    chunk_parent.extend(r.vector_ids().skip(1).map(|v| (v, r.vector_id)));
+                    by_vec.insert(r.vector_id, r);
+                }
+                Ok(Self {
+                    rows: Rows::Memory(by_vec),
+                    current,
+                    chunk_parent,
+                })
+            }
+            StoreMode::Offsets => {
+                let (keys, _) = load_reviews_checked::<RowKey>(jsonl_path)?;
+                let mut current = HashMap::new();
+                let mut chunk_parent = HashMap::new();
+                for k in keys {
+                    chunk_parent.extend((1..k.chunks.len()).map(|i| (k.vector_id + i, k.vector_id)));
+                    current.insert(k.id, k.vector_id);
+                }
+                Ok(Self {
+                    rows: Rows::Offsets(offsets),
+                    current,
+                    chunk_parent,
+                })
+            }
+        }
+    }
+
+    /// เรียกหลัง `append_review_line` สำเร็จ (ลำดับเดียวกับไฟล์)
+    pub fn insert(&mut self, review: StoredReview, loc: RowLocation) {
+        self.current.insert(review.id.clone(), review.vector_id);
+        self.chunk_parent
+            .extend(review.vector_ids().skip(1).map(|v| (v, review.vector_id)));
+        match &mut self.rows {
+            Rows::Memory(by_vec) => {
+                by_vec.insert(review.vector_id, review);
+            }
+            Rows::Offsets(offsets) => offsets.record(review.vector_id, loc),
+        }
+    }
+
+    /// vector_id แรกของแถวที่เป็นเจ้าของ `vector_id` (ตัวมันเองถ้าไม่ใช่ chunk ที่ 1..)
+    fn owner(&self, vector_id: usize) -> usize {
+        self.chunk_parent.get(&vector_id).copied().unwrap_or(vector_id)
+    }
+
+    /// แถวที่เป็นเจ้าของ `vector_id` (vector ใดก็ได้ของแถว)
+    pub fn get(&self, vector_id: usize) -> Result<Option<StoredReview>> {
+        let vector_id = self.owner(vector_id);
+        match &self.rows {
+            Rows::Memory(by_vec) => Ok(by_vec.get(&vector_id).cloned()),
+            Rows::Offsets(offsets) => offsets.get_review_by_vector_id(vector_id),
+        }
+    }
+
+    /// ผลลัพธ์เรียงตาม `vector_ids` (id ของ chunk ได้แถวเจ้าของ)
+    pub fn get_many(&self, vector_ids: &[usize]) -> Result<Vec<Option<StoredReview>>> {
+        let owners: Vec<usize> = vector_ids.iter().map(|&v| self.owner(v)).collect();
+        match &self.rows {
+            Rows::Memory(by_vec) => Ok(owners.iter().map(|v| by_vec.get(v).cloned()).collect()),
+            Rows::Offsets(offsets) => offsets.get_reviews(&owners),
+        }
+    }
+
+    /// แถวล่าสุดของ `review_id` (อาจเป็น vector ที่ถูกลบแล้ว — ผู้เรียกตรวจเอง)
+    pub fn current(&self, review_id: &str) -> Result<Option<StoredReview>> {
+        let Some(&vid) = self.current.get(review_id) else {
+            return Ok(None);
+        };
+        Ok(self.get(vid)?.filter(|r| r.id == review_id))
+    }
+
+    pub fn len(&self) -> usize {
+        self.current.len()
+    }
+}
@@ -1,5 +1,5 @@
 use axum::{
-    routing::{get, post},
+    routing::{delete, get, post},
     Router,
 };
 use tower_http::cors::{Any, CorsLayer};
@@ -7,11 +7,21 @@ use tower_http::cors::{Any, CorsLayer};
 use crate::handlers::{
     AppState,
     bulk_insert_handler,
+    clear_query_cache_handler,
+    delete_review_handler,
     get_paths_handler,
     health_handler,
     insert_review_handler,
+    patch_review_handler,
+    put_review_handler,
+    query_cache_stats_handler,
+    rebuild_status_handler,
+    repair_handler,
     search_handler,
     set_paths_handler,
+    snapshot_handler,
+    start_rebuild_handler,
+    verify_handler,
 };
 
 pub fn register_routes(state: AppState) -> Router<AppState> {
@@ -25,7 +35,24 @@ pub fn register_routes(state: AppState) -> Router<AppState> {
         .route("/config/paths", get(get_paths_handler).post(set_paths_handler))
         .route("/reviews", post(insert_review_handler))
         .route("/reviews/bulk", post(bulk_insert_handler))
+        .route(
+            "/reviews/:id",
+            delete(delete_review_handler)
+                .put(put_review_handler)
+                .patch(patch_review_handler),
+        )
         .route("/search", post(search_handler))
+        .route(
+            "/admin/rebuild",
+            get(rebuild_status_handler).post(start_rebuild_handler),
+        )
+        .route("/admin/verify", get(verify_handler))
+        .route("/admin/repair", post(repair_handler))
+        .route("/admin/snapshot", post(snapshot_handler))
+        .route(
+            "/admin/query-cache",
+            get(query_cache_stats_handler).delete(clear_query_cache_handler),
+        )
         .layer(cors)
         .with_state(state)
 }
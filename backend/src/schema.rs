@@ -0,0 +1,331 @@
+use anyhow::{bail, Result};
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+use std::collections::BTreeSet;
+use std::io::BufRead;
+
+use crate::handlers::Paths;
+use crate::segments::{SegmentFile, SegmentLayout};
+use crate::storage::{recover_data_files, rewrite_segment, segment_paths, ReviewOffsets};
+use crate::types::{MigrationReport, SegmentMigration, SCHEMA_VERSION};
+
+/// อัปเกรดแถว reviews หนึ่งขั้น (`from` → `to`) บน JSON object ดิบ
+pub struct Migration {
+    pub from: &'static str,
+    pub to: &'static str,
+    pub description: &'static str,
+    apply: fn(&mut Map<String, Value>),
+}
+
+/// ทุก migration เรียงตามเวอร์ชัน; สายต่อกันต้องจบที่ `SCHEMA_VERSION` (ยังว่าง: มีแค่ v1).
+/// เพิ่ม field ใหม่ = bump `SCHEMA_VERSION` + เพิ่มขั้นที่นี่ เช่น
+/// `Migration { from: "v1", to: "v2", description: "add foo (null)", apply: v1_to_v2 }`
+/// กับ `fn v1_to_v2(row: &mut Map<String, Value>)` ที่เติม field ใหม่ลง object ดิบ
+pub const MIGRATIONS: &[Migration] = &[];
+
+/// ชุด migration กับเวอร์ชันปลายทาง — ของจริงคือ `CURRENT`; test ใช้ registry ของตัวเอง
+struct Schema {
+    migrations: &'static [Migration],
+    target: &'static str,
+}
+
+const CURRENT: Schema = Schema {
+    migrations: MIGRATIONS,
+    target: SCHEMA_VERSION,
+};
+
+/// เวอร์ชันของแถว (แถวที่ไม่มี `schema_version` = v1)
+fn version_of(row: &Map<String, Value>) -> &str {
+    row.get("schema_version")
+        .and_then(Value::as_str)
+        .unwrap_or("v1")
+}
+
+/// อัปเกรดแถวให้เป็น `SCHEMA_VERSION`; คืน true ถ้ามีการเปลี่ยน.
+/// แถวที่ไม่ใช่ object ถูกปล่อยไว้ (deserialize จะล้มเป็นบรรทัดเสียเอง);
+/// เวอร์ชันที่ไม่รู้จัก (เช่นเขียนโดย build ที่ใหม่กว่า) = error
+pub fn upgrade(row: &mut Value) -> Result<bool> {
+    CURRENT.upgrade(row)
+}
+
+/// parse แถว reviews หนึ่งบรรทัด (อัปเกรดก่อน deserialize เป็น `T`)
+pub fn decode_row<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+    CURRENT.decode_row(bytes)
+}
+
+/// นับเวอร์ชันของทุกแถวในทุก segment และ (ถ้าไม่ใช่ `dry_run`) เขียน segment ที่มีแถวเก่าใหม่
+/// ให้เป็น `SCHEMA_VERSION` — segment ที่บีบอัดแล้วถูกบีบอัดใหม่, sidecar offset ถูกสร้างใหม่.
+/// บรรทัดที่ parse ไม่ได้ถูกคงไว้ตามเดิม
+pub fn migrate(paths: &Paths, dry_run: bool) -> Result<MigrationReport> {
+    CURRENT.migrate(paths, dry_run)
+}
+
+impl Schema {
+    fn migration_from(&self, version: &str) -> Result<&'static Migration> {
+        match self.migrations.iter().find(|m| m.from == version) {
+            Some(m) => Ok(m),
+            None => bail!("no migration from schema {version} (this build writes {})", self.target),
+        }
+    }
+
+    fn upgrade(&self, row: &mut Value) -> Result<bool> {
+        let Some(obj) = row.as_object_mut() else {
+            return Ok(false);
+        };
+        let mut changed = false;
+        while version_of(obj) != self.target {
+            let m = self.migration_from(version_of(obj))?;
+            (m.apply)(obj);
+            obj.insert("schema_version".into(), Value::String(m.to.into()));
+            changed = true;
+        }
+        Ok(changed)
+    }
+
+    fn decode_row<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        let mut row: Value = serde_json::from_slice(bytes)?;
+        self.upgrade(&mut row)?;
+        Ok(serde_json::from_value(row)?)
+    }
+
+    /// `from -> to: description` ของทุกขั้นจาก `version` ถึงเวอร์ชันปลายทาง
+    fn steps_from(&self, version: &str) -> Result<Vec<String>> {
+        let mut out = Vec::new();
+        let mut v = version.to_string();
+        while v != self.target {
+            let m = self.migration_from(&v)?;
+            out.push(format!("{} -> {}: {}", m.from, m.to, m.description));
+            v = m.to.to_string();
+        }
+        Ok(out)
+    }
+
+    fn migrate(&self, paths: &Paths, dry_run: bool) -> Result<MigrationReport> {
+        if !dry_run {
+            recover_data_files(&paths.jsonl_path, &paths.map_path)?;
+        }
+        let layout = SegmentLayout::from_path(&paths.jsonl_path);
+        let mut report = MigrationReport {
+            target_version: self.target.to_string(),
+            dry_run,
+            ..Default::default()
+        };
+        let mut seen = BTreeSet::new();
+        let mut stale = Vec::new();
+
+        for (seq, seg_path) in segment_paths(&paths.jsonl_path)? {
+            let mut seg = SegmentMigration {
+                file: seg_path.to_string_lossy().into_owned(),
+                ..Default::default()
+            };
+            if let Some(file) = SegmentFile::open(&layout, seq)? {
+                for line in file.reader_from(0)?.split(b'\n') {
+                    let line = line?;
+                    if line.iter().all(u8::is_ascii_whitespace) {
+                        continue;
+                    }
+                    let Ok(Value::Object(row)) = serde_json::from_slice::<Value>(&line) else {
+                        seg.malformed_lines += 1;
+                        continue;
+                    };
+                    let version = version_of(&row).to_string();
+                    seg.rows += 1;
+                    if version != self.target {
+                        seg.to_upgrade += 1;
+                    }
+                    *seg.by_version.entry(version.clone()).or_default() += 1;
+                    seen.insert(version);
+                }
+            }
+            report.rows += seg.rows;
+            report.rows_to_upgrade += seg.to_upgrade;
+            if seg.to_upgrade > 0 {
+                stale.push(seq);
+            }
+            report.segments.push(seg);
+        }
+
+        for version in &seen {
+            for step in self.steps_from(version)? {
+                if !report.steps.contains(&step) {
+                    report.steps.push(step);
+                }
+            }
+        }
+        if dry_run || stale.is_empty() {
+            return Ok(report);
+        }
+
+        for seq in stale {
+            rewrite_segment(&paths.jsonl_path, seq, |line| {
+                let Ok(mut row) = serde_json::from_slice::<Value>(line) else {
+                    return Ok(line.to_vec());
+                };
+                self.upgrade(&mut row)?;
+                Ok(serde_json::to_vec(&row)?)
+            })?;
+            report.rewritten_segments += 1;
+        }
+        // offset ของแถวเปลี่ยนหมด: สร้าง sidecar ของ segment ที่เขียนใหม่
+        ReviewOffsets::open(&paths.jsonl_path)?;
+        Ok(report)
+    }
+}
+
+/// `backend migrate [--dry-run]`
+pub fn run_cli(paths: &Paths, dry_run: bool) -> Result<()> {
+    let report = migrate(paths, dry_run)?;
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn add_rating(row: &mut Map<String, Value>) {
+        row.insert("rating".into(), Value::Null);
+    }
+
+    fn add_tags(row: &mut Map<String, Value>) {
+        row.entry("tags")
+            .or_insert_with(|| Value::Array(Vec::new()));
+    }
+
+    /// registry ทดสอบ: v1 → v2 → v3
+    const TEST: Schema = Schema {
+        migrations: &[
+            Migration {
+                from: "v1",
+                to: "v2",
+                description: "add rating (null)",
+                apply: add_rating,
+            },
+            Migration {
+                from: "v2",
+                to: "v3",
+                description: "add tags ([])",
+                apply: add_tags,
+            },
+        ],
+        target: "v3",
+    };
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct RowV3 {
+        schema_version: String,
+        id: String,
+        rating: Option<u8>,
+        tags: Vec<String>,
+    }
+
+    #[test]
+    fn upgrade_chains_every_step_to_the_target() {
+        let mut row = serde_json::json!({ "id": "a" });
+        assert!(TEST.upgrade(&mut row).unwrap());
+        assert_eq!(
+            row,
+            serde_json::json!({ "schema_version": "v3", "id": "a", "rating": null, "tags": [] })
+        );
+
+        // v2 ข้ามขั้นแรก; แถวที่เป็นเวอร์ชันล่าสุดแล้วหรือไม่ใช่ object ไม่เปลี่ยน
+        let mut row = serde_json::json!({ "schema_version": "v2", "id": "b", "rating": 4 });
+        assert!(TEST.upgrade(&mut row).unwrap());
+        assert_eq!(row["rating"], 4);
+        assert!(!TEST.upgrade(&mut row).unwrap());
+        assert!(!TEST.upgrade(&mut serde_json::json!([1, 2])).unwrap());
+
+        assert_eq!(
+            TEST.steps_from("v1").unwrap(),
+            vec!["v1 -> v2: add rating (null)", "v2 -> v3: add tags ([])"]
+        );
+        assert!(TEST.steps_from("v3").unwrap().is_empty());
+    }
+
+    #[test]
+    fn rows_are_upgraded_on_read() {
+        let row: RowV3 = TEST.decode_row(br#"{"id":"a"}"#).unwrap();
+        assert_eq!(
+            row,
+            RowV3 {
+                schema_version: "v3".into(),
+                id: "a".into(),
+                rating: None,
+                tags: vec![],
+            }
+        );
+    }
+
+    #[test]
+    fn a_newer_schema_version_is_an_error() {
+        let mut row = serde_json::json!({ "schema_version": "v9", "id": "a" });
+        let err = TEST.upgrade(&mut row).unwrap_err().to_string();
+        assert!(err.contains("no migration from schema v9"), "{err}");
+        assert!(TEST
+            .decode_row::<RowV3>(br#"{"schema_version":"v9","id":"a"}"#)
+            .is_err());
+        assert!(TEST.steps_from("v9").is_err());
+    }
+
+    #[test]
+    fn migrate_reports_on_dry_run_and_rewrites_old_rows() {
+        let dir = TempDir::new().unwrap();
+        let p = |name: &str| dir.path().join(name).to_string_lossy().into_owned();
+        let paths = Paths {
+            index_path: p("reviews.index"),
+            jsonl_path: p("reviews.jsonl"),
+            map_path: p("vector_map.jsonl"),
+        };
+        fs::write(
+            &paths.jsonl_path,
+            concat!(
+                r#"{"id":"a","vector_id":0}"#,
+                "\n",
+                r#"{"schema_version":"v2","id":"b","vector_id":1,"rating":5}"#,
+                "\n",
+                "not json\n",
+                r#"{"schema_version":"v3","id":"c","vector_id":2,"rating":null,"tags":["x"]}"#,
+                "\n",
+            ),
+        )
+        .unwrap();
+        let read_all = || -> String {
+            segment_paths(&paths.jsonl_path)
+                .unwrap()
+                .into_iter()
+                .map(|(_, path)| fs::read_to_string(path).unwrap())
+                .collect()
+        };
+        let before = read_all();
+
+        let report = TEST.migrate(&paths, true).unwrap();
+        assert!(report.dry_run);
+        assert_eq!(report.target_version, "v3");
+        assert_eq!((report.rows, report.rows_to_upgrade), (3, 2));
+        assert_eq!(report.segments[0].malformed_lines, 1);
+        assert_eq!(report.steps.len(), 2);
+        assert_eq!(report.rewritten_segments, 0);
+        assert_eq!(read_all(), before, "dry run must not write");
+
+        let report = TEST.migrate(&paths, false).unwrap();
+        assert_eq!(report.rewritten_segments, 1);
+        let after = read_all();
+        let lines: Vec<&str> = after.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[2], "not json", "malformed lines are kept as they are");
+        let rows: Vec<RowV3> = [lines[0], lines[1], lines[3]]
+            .iter()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert!(rows.iter().all(|r| r.schema_version == "v3"));
+        assert_eq!(rows[1].rating, Some(5));
+        assert_eq!(rows[2].tags, vec!["x".to_string()]);
+
+        // ครั้งที่สอง: ไม่มีอะไรต้องทำ
+        let report = TEST.migrate(&paths, false).unwrap();
+        assert_eq!((report.rows_to_upgrade, report.rewritten_segments), (0, 0));
+    }
+}
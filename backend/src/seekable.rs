@@ -0,0 +1,272 @@
+use anyhow::{bail, Result};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+// ---- zstd seekable format ----
+//
+// ไฟล์ = zstd frame อิสระต่อกัน ตามด้วย skippable frame ที่เป็น seek table:
+//   [magic 0x184D2A5E u32][size u32] entries `[compressed u32][decompressed u32]([checksum u32])`
+//   footer `[frames u32][descriptor u8][magic 0x8F92EAB1 u32]` (little-endian)
+// ตาม contrib/seekable_format ของ zstd — เครื่องมือ zstd ทั่วไปก็ยัง decompress ได้ทั้งไฟล์.
+// ที่นี่ตัด frame ที่ขอบบรรทัด ทำให้แต่ละแถวอยู่ใน frame เดียว (อ่านแถวหนึ่ง = decompress frame เดียว)
+
+const SKIPPABLE_MAGIC: u32 = 0x184D_2A5E;
+const SEEKABLE_MAGIC: u32 = 0x8F92_EAB1;
+const FOOTER_LEN: u64 = 9;
+const CHECKSUM_FLAG: u8 = 0x80;
+
+#[derive(Debug, Clone, Copy)]
+struct Frame {
+    c_offset: u64,
+    d_offset: u64,
+    c_size: u32,
+    d_size: u32,
+}
+
+/// บีบอัดไฟล์ JSONL `src` เป็น `dst` แบบ seekable; ปิด frame ที่ `\n` แรกหลังสะสมครบ `frame_size` byte
+pub fn compress_lines(src: &Path, dst: &Path, level: i32, frame_size: usize) -> Result<()> {
+    let mut reader = BufReader::new(File::open(src)?);
+    let mut out = File::create(dst)?;
+    let mut table: Vec<(u32, u32)> = Vec::new();
+    let mut buf = Vec::with_capacity(frame_size * 2);
+
+    let mut flush = |buf: &mut Vec<u8>, out: &mut File| -> Result<()> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let compressed = zstd::bulk::compress(buf, level)?;
+        out.write_all(&compressed)?;
+        table.push((compressed.len() as u32, buf.len() as u32));
+        buf.clear();
+        Ok(())
+    };
+    loop {
+        let n = reader.read_until(b'\n', &mut buf)?;
+        if n == 0 {
+            break;
+        }
+        if buf.len() >= frame_size {
+            flush(&mut buf, &mut out)?;
+        }
+    }
+    flush(&mut buf, &mut out)?;
+
+    let mut seek_table = Vec::with_capacity(8 + table.len() * 8 + FOOTER_LEN as usize);
+    seek_table.extend_from_slice(&SKIPPABLE_MAGIC.to_le_bytes());
+    seek_table.extend_from_slice(&((table.len() * 8) as u32 + FOOTER_LEN as u32).to_le_bytes());
+    for (c, d) in &table {
+        seek_table.extend_from_slice(&c.to_le_bytes());
+        seek_table.extend_from_slice(&d.to_le_bytes());
+    }
+    seek_table.extend_from_slice(&(table.len() as u32).to_le_bytes());
+    seek_table.push(0);
+    seek_table.extend_from_slice(&SEEKABLE_MAGIC.to_le_bytes());
+    out.write_all(&seek_table)?;
+    out.sync_all()?;
+    Ok(())
+}
+
+/// ไฟล์ seekable zstd ที่โหลด seek table แล้ว: อ่านช่วง byte ใดก็ได้ของข้อมูลที่ยังไม่บีบอัด
+#[derive(Debug)]
+pub struct SeekableFile {
+    file: File,
+    frames: Vec<Frame>,
+    len: u64,
+}
+
+impl SeekableFile {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = File::open(path)?;
+        let file_len = file.metadata()?.len();
+        if file_len < FOOTER_LEN + 8 {
+            bail!("{}: too short for a seekable zstd file", path.display());
+        }
+        let mut footer = [0u8; FOOTER_LEN as usize];
+        file.read_exact_at(&mut footer, file_len - FOOTER_LEN)?;
+        let n_frames = u32::from_le_bytes(footer[0..4].try_into().unwrap()) as u64;
+        let descriptor = footer[4];
+        if u32::from_le_bytes(footer[5..9].try_into().unwrap()) != SEEKABLE_MAGIC {
+            bail!("{}: missing seek table", path.display());
+        }
+        let entry_len: u64 = if descriptor & CHECKSUM_FLAG != 0 { 12 } else { 8 };
+        let table_len = 8 + n_frames * entry_len + FOOTER_LEN;
+        if table_len > file_len {
+            bail!("{}: seek table larger than file", path.display());
+        }
+        let mut table = vec![0u8; (table_len - FOOTER_LEN) as usize];
+        file.read_exact_at(&mut table, file_len - table_len)?;
+        if u32::from_le_bytes(table[0..4].try_into().unwrap()) != SKIPPABLE_MAGIC {
+            bail!("{}: bad seek table header", path.display());
+        }
+
+        let mut frames = Vec::with_capacity(n_frames as usize);
+        let (mut c_offset, mut d_offset) = (0u64, 0u64);
+        for e in table[8..].chunks_exact(entry_len as usize) {
+            let c_size = u32::from_le_bytes(e[0..4].try_into().unwrap());
+            let d_size = u32::from_le_bytes(e[4..8].try_into().unwrap());
+            frames.push(Frame {
+                c_offset,
+                d_offset,
+                c_size,
+                d_size,
+            });
+            c_offset += c_size as u64;
+            d_offset += d_size as u64;
+        }
+        if c_offset != file_len - table_len {
+            bail!("{}: seek table does not match file size", path.display());
+        }
+        Ok(Self {
+            file,
+            frames,
+            len: d_offset,
+        })
+    }
+
+    /// ขนาดข้อมูลหลัง decompress
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// เหมือน `FileExt::read_exact_at` แต่ `offset` เป็นตำแหน่งในข้อมูลที่ยังไม่บีบอัด
+    pub fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
+        let end = offset + buf.len() as u64;
+        if end > self.len {
+            bail!("read past end of seekable file ({end} > {})", self.len);
+        }
+        let mut i = self
+            .frames
+            .partition_point(|f| f.d_offset + f.d_size as u64 <= offset);
+        let mut filled = 0;
+        while filled < buf.len() {
+            let f = self.frames[i];
+            let mut compressed = vec![0u8; f.c_size as usize];
+            self.file.read_exact_at(&mut compressed, f.c_offset)?;
+            let data = zstd::bulk::decompress(&compressed, f.d_size as usize)?;
+            let start = (offset + filled as u64 - f.d_offset) as usize;
+            let take = (data.len() - start).min(buf.len() - filled);
+            buf[filled..filled + take].copy_from_slice(&data[start..start + take]);
+            filled += take;
+            i += 1;
+        }
+        Ok(())
+    }
+
+    /// อ่านข้อมูลที่ยังไม่บีบอัดตามลำดับตั้งแต่ `offset` (เริ่ม decompress จาก frame ที่มี `offset`;
+    /// decoder ข้าม skippable frame ของ seek table เอง)
+    pub fn reader_from(mut self, offset: u64) -> Result<Box<dyn BufRead + Send>> {
+        let i = self
+            .frames
+            .partition_point(|f| f.d_offset + f.d_size as u64 <= offset);
+        let Some(f) = self.frames.get(i).copied() else {
+            return Ok(Box::new(std::io::empty()));
+        };
+        self.file.seek(SeekFrom::Start(f.c_offset))?;
+        let mut reader = BufReader::new(zstd::stream::read::Decoder::new(self.file)?);
+        std::io::copy(&mut (&mut reader).take(offset - f.d_offset), &mut std::io::sink())?;
+        Ok(Box::new(reader))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    /// JSONL ความยาวบรรทัดไม่เท่ากัน; คืน (ไฟล์ต้นฉบับ, ตำแหน่งเริ่มของแต่ละบรรทัด)
+    fn write_lines(dir: &TempDir, n: usize) -> (Vec<u8>, Vec<usize>) {
+        let mut data = Vec::new();
+        let mut starts = Vec::new();
+        for i in 0..n {
+            starts.push(data.len());
+            let line = format!(
+                "{{\"vector_id\":{i},\"review\":\"{}\"}}\n",
+                "x".repeat(i % 37)
+            );
+            data.extend_from_slice(line.as_bytes());
+        }
+        std::fs::write(dir.path().join("seg.jsonl"), &data).unwrap();
+        (data, starts)
+    }
+
+    fn compress(dir: &TempDir, frame_size: usize) -> SeekableFile {
+        let (src, dst) = (
+            dir.path().join("seg.jsonl"),
+            dir.path().join("seg.jsonl.zst"),
+        );
+        compress_lines(&src, &dst, 3, frame_size).unwrap();
+        SeekableFile::open(&dst).unwrap()
+    }
+
+    #[test]
+    fn frames_end_on_line_boundaries() {
+        let dir = TempDir::new().unwrap();
+        let (data, starts) = write_lines(&dir, 500);
+        let file = compress(&dir, 256);
+        assert_eq!(file.len(), data.len() as u64);
+        assert!(file.frames.len() > 10);
+        for f in &file.frames {
+            let end = (f.d_offset + f.d_size as u64) as usize;
+            assert!(end == data.len() || starts.binary_search(&end).is_ok());
+        }
+    }
+
+    #[test]
+    fn random_reads_match_the_plain_file() {
+        let dir = TempDir::new().unwrap();
+        let (data, starts) = write_lines(&dir, 500);
+        let file = compress(&dir, 256);
+
+        // ทีละแถว (แบบที่ ReviewOffsets อ่าน)
+        for (i, &start) in starts.iter().enumerate().rev() {
+            let end = starts.get(i + 1).copied().unwrap_or(data.len());
+            let mut buf = vec![0u8; end - start];
+            file.read_exact_at(&mut buf, start as u64).unwrap();
+            assert_eq!(buf, &data[start..end]);
+        }
+
+        // ช่วงที่คร่อมหลาย frame
+        let mut seed = 7u64;
+        for _ in 0..200 {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            let start = (seed >> 33) as usize % data.len();
+            let len = 1 + (seed >> 13) as usize % 2000.min(data.len() - start);
+            let mut buf = vec![0u8; len];
+            file.read_exact_at(&mut buf, start as u64).unwrap();
+            assert_eq!(buf, &data[start..start + len]);
+        }
+
+        let mut past_end = [0u8; 2];
+        assert!(file
+            .read_exact_at(&mut past_end, data.len() as u64 - 1)
+            .is_err());
+    }
+
+    #[test]
+    fn reader_from_streams_the_rest() {
+        let dir = TempDir::new().unwrap();
+        let (data, starts) = write_lines(&dir, 300);
+        for offset in [0, starts[123], starts[123] + 5, data.len()] {
+            let mut rest = Vec::new();
+            compress(&dir, 256)
+                .reader_from(offset as u64)
+                .unwrap()
+                .read_to_end(&mut rest)
+                .unwrap();
+            assert_eq!(rest, &data[offset..]);
+        }
+    }
+
+    #[test]
+    fn plain_zstd_decodes_the_whole_file() {
+        let dir = TempDir::new().unwrap();
+        let (data, _) = write_lines(&dir, 300);
+        compress(&dir, 256);
+        let raw = std::fs::read(dir.path().join("seg.jsonl.zst")).unwrap();
+        assert_eq!(zstd::decode_all(&raw[..]).unwrap(), data);
+    }
+}
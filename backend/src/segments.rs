@@ -0,0 +1,440 @@
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+use crate::lock::read_only;
+use crate::seekable::SeekableFile;
+
+const MANIFEST_VERSION: u32 = 1;
+
+/// ขนาดสูงสุดของ segment ก่อนเปิดไฟล์ใหม่ (`SEGMENT_MAX_BYTES`, default 64 MiB)
+pub fn max_segment_bytes() -> u64 {
+    env::var("SEGMENT_MAX_BYTES")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(64 * 1024 * 1024)
+        .max(1)
+}
+
+/// ระดับ zstd สำหรับบีบอัด segment ที่ปิดแล้ว (`SEGMENT_COMPRESSION=zstd|none`, default zstd;
+/// `SEGMENT_ZSTD_LEVEL`, default 3); `None` = ไม่บีบอัด
+pub fn segment_compression() -> Result<Option<i32>> {
+    match env::var("SEGMENT_COMPRESSION")
+        .unwrap_or_else(|_| "zstd".into())
+        .to_ascii_lowercase()
+        .as_str()
+    {
+        "zstd" => Ok(Some(
+            env::var("SEGMENT_ZSTD_LEVEL")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(3),
+        )),
+        "none" => Ok(None),
+        other => bail!("unknown SEGMENT_COMPRESSION: {other} (expected zstd|none)"),
+    }
+}
+
+/// ตำแหน่งไฟล์ของ segment จาก `Paths.jsonl_path`:
+/// `data/reviews.jsonl` หรือ `data/reviews` → `data/reviews-000001.jsonl`, ...;
+/// ไดเรกทอรี (`data/segments/`) → `data/segments/reviews-000001.jsonl`, ...
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SegmentLayout {
+    dir: PathBuf,
+    stem: String,
+}
+
+impl SegmentLayout {
+    pub fn from_path(jsonl_path: &str) -> Self {
+        let p = Path::new(jsonl_path);
+        if jsonl_path.ends_with('/') || p.is_dir() {
+            return Self {
+                dir: p.to_path_buf(),
+                stem: "reviews".into(),
+            };
+        }
+        let dir = match p.parent() {
+            Some(d) if !d.as_os_str().is_empty() => d.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        let stem = if p.extension().is_some_and(|e| e == "jsonl") {
+            p.file_stem()
+        } else {
+            p.file_name()
+        }
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "reviews".into());
+        Self { dir, stem }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn stem(&self) -> &str {
+        &self.stem
+    }
+
+    pub fn segment_file_name(&self, seq: u32) -> String {
+        format!("{}-{seq:06}.jsonl", self.stem)
+    }
+
+    pub fn segment_path(&self, seq: u32) -> PathBuf {
+        self.dir.join(self.segment_file_name(seq))
+    }
+
+    /// segment ที่บีบอัดแล้ว (seekable zstd)
+    pub fn compressed_file_name(&self, seq: u32) -> String {
+        format!("{}.zst", self.segment_file_name(seq))
+    }
+
+    pub fn compressed_path(&self, seq: u32) -> PathBuf {
+        self.dir.join(self.compressed_file_name(seq))
+    }
+
+    pub fn manifest_path(&self) -> PathBuf {
+        self.dir.join(format!("{}.manifest.json", self.stem))
+    }
+
+    /// ไฟล์เดียวแบบเดิม (`reviews.jsonl`) ก่อนมี segment
+    fn legacy_path(&self) -> PathBuf {
+        self.dir.join(format!("{}.jsonl", self.stem))
+    }
+
+    /// เลข segment จากชื่อไฟล์ `<stem>-NNNNNN.jsonl` หรือ `<stem>-NNNNNN.jsonl.zst`
+    fn parse_seq(&self, file_name: &str) -> Option<u32> {
+        let name = file_name.strip_suffix(".zst").unwrap_or(file_name);
+        name.strip_prefix(&self.stem)?
+            .strip_prefix('-')?
+            .strip_suffix(".jsonl")
+            .filter(|n| n.len() >= 6 && n.bytes().all(|b| b.is_ascii_digit()))?
+            .parse()
+            .ok()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SegmentInfo {
+    pub seq: u32,
+    pub file: String,
+    pub rows: usize,
+    pub bytes: u64,
+    pub min_vector_id: Option<usize>,
+    pub max_vector_id: Option<usize>,
+    /// segment ที่ปิดแล้ว (ไม่มีการ append อีก); มีแค่ segment สุดท้ายที่ยังเปิด
+    pub sealed: bool,
+    /// ปิดแล้วและถูกบีบอัดเป็น `<file>.zst` (offset ใน sidecar ยังเป็นตำแหน่งก่อนบีบอัด)
+    #[serde(default)]
+    pub compressed: bool,
+}
+
+impl SegmentInfo {
+    fn empty(layout: &SegmentLayout, seq: u32) -> Self {
+        Self {
+            seq,
+            file: layout.segment_file_name(seq),
+            rows: 0,
+            bytes: 0,
+            min_vector_id: None,
+            max_vector_id: None,
+            sealed: false,
+            compressed: false,
+        }
+    }
+
+    fn note_row(&mut self, vector_id: usize) {
+        self.rows += 1;
+        self.min_vector_id = Some(self.min_vector_id.map_or(vector_id, |m| m.min(vector_id)));
+        self.max_vector_id = Some(self.max_vector_id.map_or(vector_id, |m| m.max(vector_id)));
+    }
+}
+
+/// `<stem>.manifest.json`: รายการ segment ตามลำดับการเขียน
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Manifest {
+    pub version: u32,
+    pub segments: Vec<SegmentInfo>,
+}
+
+#[derive(Deserialize)]
+struct VectorIdOnly {
+    vector_id: usize,
+}
+
+/// ไฟล์ของ segment หนึ่ง: JSONL ปกติ หรือ seekable zstd (offset เป็นตำแหน่งก่อนบีบอัดทั้งคู่)
+pub enum SegmentFile {
+    Plain(File),
+    Zstd(SeekableFile),
+}
+
+impl SegmentFile {
+    /// เปิด `<segment>.jsonl` ถ้ายังอยู่ ไม่งั้น `<segment>.jsonl.zst`; ไม่มีทั้งคู่ = `None`
+    pub fn open(layout: &SegmentLayout, seq: u32) -> Result<Option<Self>> {
+        match File::open(layout.segment_path(seq)) {
+            Ok(f) => return Ok(Some(Self::Plain(f))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        let zst = layout.compressed_path(seq);
+        if !zst.exists() {
+            return Ok(None);
+        }
+        Ok(Some(Self::Zstd(SeekableFile::open(&zst)?)))
+    }
+
+    /// ขนาดข้อมูล (ก่อนบีบอัด)
+    pub fn len(&self) -> Result<u64> {
+        match self {
+            Self::Plain(f) => Ok(f.metadata()?.len()),
+            Self::Zstd(z) => Ok(z.len()),
+        }
+    }
+
+    /// อ่านทีละบรรทัดตั้งแต่ `offset`
+    pub fn reader_from(self, offset: u64) -> Result<Box<dyn BufRead + Send>> {
+        match self {
+            Self::Plain(mut f) => {
+                f.seek(SeekFrom::Start(offset))?;
+                Ok(Box::new(BufReader::new(f)))
+            }
+            Self::Zstd(z) => z.reader_from(offset),
+        }
+    }
+}
+
+/// นับแถว / ขนาด / ช่วง vector_id ของ segment จากไฟล์จริง
+fn scan_segment(layout: &SegmentLayout, seq: u32) -> Result<SegmentInfo> {
+    let mut info = SegmentInfo::empty(layout, seq);
+    let Some(file) = SegmentFile::open(layout, seq)? else {
+        return Ok(info);
+    };
+    let len = file.len()?;
+    if matches!(file, SegmentFile::Zstd(_)) {
+        info.compressed = true;
+        info.file = layout.compressed_file_name(seq);
+    }
+    for line in file.reader_from(0)?.split(b'\n') {
+        let line = line?;
+        info.bytes += line.len() as u64 + 1;
+        if let Ok(row) = serde_json::from_slice::<VectorIdOnly>(&line) {
+            info.note_row(row.vector_id);
+        }
+    }
+    // ไฟล์ที่ไม่มี '\n' ปิดท้าย นับเกินไป 1 byte
+    info.bytes = info.bytes.min(len);
+    Ok(info)
+}
+
+/// ทำสถานะการบีบอัดของ segment ให้ตรงกับไฟล์: `.zst` ถูก rename เข้าที่หลังเขียน + fsync ครบแล้ว
+/// จึงเชื่อได้เสมอ — ไฟล์ `.jsonl` ที่ค้างอยู่ (crash ก่อนลบ) ถูกลบทิ้ง
+fn settle_compression(layout: &SegmentLayout, seg: &mut SegmentInfo) -> Result<()> {
+    let plain = layout.segment_path(seg.seq);
+    let zst = layout.compressed_path(seg.seq);
+    if !read_only() {
+        let _ = fs::remove_file(format!("{}.tmp", zst.display()));
+    }
+    if zst.exists() {
+        if plain.exists() && !read_only() {
+            warn!("{}: already compressed; removing leftover", plain.display());
+            fs::remove_file(&plain)?;
+        }
+        seg.compressed = true;
+        seg.file = layout.compressed_file_name(seg.seq);
+    } else {
+        seg.compressed = false;
+        seg.file = layout.segment_file_name(seg.seq);
+    }
+    Ok(())
+}
+
+/// ชุด segment ของ reviews ที่เปิดอยู่ + manifest
+#[derive(Debug)]
+pub struct Segments {
+    layout: SegmentLayout,
+    manifest: Manifest,
+}
+
+impl Segments {
+    /// โหลด manifest (หรือสร้างจากไฟล์ที่มี / ย้าย `reviews.jsonl` เดิมเป็น segment แรก)
+    /// แล้วนับ segment ที่ยังเปิดอยู่ใหม่จากไฟล์จริง
+    pub fn open(jsonl_path: &str) -> Result<Self> {
+        let layout = SegmentLayout::from_path(jsonl_path);
+        let manifest_path = layout.manifest_path();
+        let stored: Option<Manifest> = match fs::read(&manifest_path) {
+            Ok(bytes) => Some(
+                serde_json::from_slice(&bytes)
+                    .with_context(|| format!("parse {}", manifest_path.display()))?,
+            ),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        if let Some(m) = &stored {
+            if m.version != MANIFEST_VERSION {
+                bail!(
+                    "{}: unsupported manifest version {}",
+                    manifest_path.display(),
+                    m.version
+                );
+            }
+        }
+
+        let mut on_disk: Vec<u32> = match fs::read_dir(&layout.dir) {
+            Ok(rd) => rd
+                .filter_map(|e| e.ok())
+                .filter_map(|e| layout.parse_seq(&e.file_name().to_string_lossy()))
+                .collect(),
+            Err(_) => vec![],
+        };
+        on_disk.sort_unstable();
+
+        // ยังไม่เคยแบ่ง segment: ย้ายไฟล์เดิม (+ sidecar) เป็น segment แรก
+        let legacy = layout.legacy_path();
+        if stored.is_none() && on_disk.is_empty() && legacy.is_file() {
+            if read_only() {
+                bail!(
+                    "{}: not split into segments yet; start once without READ_ONLY first",
+                    legacy.display()
+                );
+            }
+            let first = layout.segment_path(1);
+            fs::rename(&legacy, &first)?;
+            let legacy_idx = PathBuf::from(format!("{}.idx", legacy.display()));
+            if legacy_idx.exists() {
+                fs::rename(&legacy_idx, format!("{}.idx", first.display()))?;
+            }
+            info!("{}: moved to {}", legacy.display(), first.display());
+            on_disk.push(1);
+        }
+
+        let mut segments = stored.clone().map(|m| m.segments).unwrap_or_default();
+        // segment ที่มีไฟล์แต่ manifest ยังไม่รู้จัก (ไม่มี manifest / เขียน manifest ไม่ทัน)
+        let known_max = segments.last().map_or(0, |s| s.seq);
+        for seq in on_disk.into_iter().filter(|&s| s > known_max) {
+            segments.push(SegmentInfo::empty(&layout, seq));
+        }
+        if segments.is_empty() {
+            segments.push(SegmentInfo::empty(&layout, 1));
+        }
+        let last = segments.len() - 1;
+        for (i, seg) in segments.iter_mut().enumerate() {
+            settle_compression(&layout, seg)?;
+            if i < last && seg.sealed {
+                continue;
+            }
+            let mut fresh = scan_segment(&layout, seg.seq)?;
+            fresh.sealed = i < last;
+            *seg = fresh;
+        }
+
+        let manifest = Manifest {
+            version: MANIFEST_VERSION,
+            segments,
+        };
+        let out = Self { layout, manifest };
+        if stored.as_ref() != Some(&out.manifest) {
+            out.save()?;
+        }
+        Ok(out)
+    }
+
+    pub fn layout(&self) -> &SegmentLayout {
+        &self.layout
+    }
+
+    /// path จริงของทุก segment ตามลำดับ (เก่า → ใหม่; `.jsonl.zst` ถ้าบีบอัดแล้ว)
+    pub fn paths(&self) -> Vec<(u32, PathBuf)> {
+        self.manifest
+            .segments
+            .iter()
+            .map(|s| (s.seq, self.layout.dir.join(&s.file)))
+            .collect()
+    }
+
+    /// segment ที่ปิดแล้วแต่ยังไม่ถูกบีบอัด
+    pub fn pending_compression(&self) -> Vec<u32> {
+        self.manifest
+            .segments
+            .iter()
+            .filter(|s| s.sealed && !s.compressed)
+            .map(|s| s.seq)
+            .collect()
+    }
+
+    /// นับ segment `seq` ใหม่จากไฟล์ (หลังถูกเขียนใหม่ทั้งไฟล์) แล้วบันทึก manifest
+    pub fn rescan(&mut self, seq: u32) -> Result<()> {
+        if let Some(seg) = self.manifest.segments.iter_mut().find(|s| s.seq == seq) {
+            let sealed = seg.sealed;
+            *seg = scan_segment(&self.layout, seq)?;
+            seg.sealed = sealed;
+        }
+        self.save()
+    }
+
+    /// บันทึกว่า segment `seq` ถูกบีบอัดแล้ว (เรียกหลัง rename `.zst` เข้าที่)
+    pub fn mark_compressed(&mut self, seq: u32) -> Result<()> {
+        let file = self.layout.compressed_file_name(seq);
+        if let Some(seg) = self.manifest.segments.iter_mut().find(|s| s.seq == seq) {
+            seg.compressed = true;
+            seg.file = file;
+        }
+        self.save()
+    }
+
+    fn active(&mut self) -> &mut SegmentInfo {
+        self.manifest
+            .segments
+            .last_mut()
+            .expect("manifest always has an active segment")
+    }
+
+    /// segment ที่จะ append บรรทัดยาว `line_len` byte: ถ้าเกิน `max_bytes`
+    /// ปิด segment ปัจจุบันแล้วเปิด segment ใหม่ (บันทึก manifest ก่อนเขียนไฟล์ใหม่).
+    /// คืน seq ของ segment ที่เพิ่งปิดด้วย (ถ้ามี) ให้ผู้เรียกบีบอัดต่อ
+    pub fn reserve(&mut self, line_len: u64, max_bytes: u64) -> Result<(u32, PathBuf, Option<u32>)> {
+        let layout = &self.layout;
+        let active = self
+            .manifest
+            .segments
+            .last_mut()
+            .expect("manifest always has an active segment");
+        let mut sealed = None;
+        if active.bytes > 0 && active.bytes + line_len > max_bytes {
+            active.sealed = true;
+            sealed = Some(active.seq);
+            let next = SegmentInfo::empty(layout, active.seq + 1);
+            info!("sealed segment {}; rotating to {}", active.file, next.file);
+            self.manifest.segments.push(next);
+            self.save()?;
+        }
+        let seq = self.active().seq;
+        Ok((seq, self.layout.segment_path(seq), sealed))
+    }
+
+    /// บันทึกแถวที่เพิ่ง append; `end` = ขนาดไฟล์หลังเขียน
+    pub fn note_append(&mut self, vector_id: usize, end: u64) {
+        let active = self.active();
+        active.note_row(vector_id);
+        active.bytes = end;
+    }
+
+    /// เขียน manifest ใหม่ทั้งไฟล์ (tmp + fsync + rename); read-only = เก็บไว้ในหน่วยความจำเท่านั้น
+    pub fn save(&self) -> Result<()> {
+        if read_only() {
+            return Ok(());
+        }
+        fs::create_dir_all(&self.layout.dir)?;
+        let path = self.layout.manifest_path();
+        let tmp = PathBuf::from(format!("{}.tmp", path.display()));
+        {
+            let mut file = File::create(&tmp)?;
+            file.write_all(&serde_json::to_vec_pretty(&self.manifest)?)?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,460 @@
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::Utc;
+use sha2::{Digest, Sha256};
+use std::env;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+use crate::handlers::{AppState, Paths};
+use crate::index::{IndexBackend, IndexConfig};
+use crate::lock::{read_only, DataDirLock};
+use crate::review_store::ReviewStore;
+use crate::segments::SegmentLayout;
+use crate::storage::{deletions_path, load_deleted_vector_ids, segment_paths, wal_path};
+use crate::types::{
+    RestoreReport, SnapshotCounts, SnapshotFile, SnapshotManifest, SnapshotPaths, SnapshotReport,
+    VerifyReport, SCHEMA_VERSION,
+};
+use crate::verify;
+
+const FORMAT_VERSION: u32 = 1;
+/// entry สุดท้ายของ archive (checksum คำนวณระหว่างเขียนไฟล์อื่น)
+const MANIFEST_NAME: &str = "snapshot.json";
+const ARCHIVE_ZSTD_LEVEL: i32 = 3;
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
+/// โฟลเดอร์แตก archive ใน target (rename เข้าที่เมื่อตรวจผ่านแล้ว)
+const STAGING_DIR_NAME: &str = ".restore";
+
+/// ไฟล์ที่เปิดไว้ตอนหยุดการเขียน + ขนาด ณ ตอนนั้น. ไฟล์ข้อมูลเป็น append-only หรือถูกแทนด้วย
+/// rename เท่านั้น ดังนั้นอ่าน `len` byte แรกจาก fd นี้ทีหลังได้ภาพเดิมแม้จะมีการเขียนต่อแล้ว
+struct Captured {
+    name: String,
+    role: &'static str,
+    file: File,
+    len: u64,
+}
+
+/// `SNAPSHOT_DIR` หรือ `snapshots/` ในไดเรกทอรีเดียวกับ segment ของ reviews
+fn snapshot_dir(paths: &Paths) -> PathBuf {
+    env::var("SNAPSHOT_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| {
+            SegmentLayout::from_path(&paths.jsonl_path)
+                .dir()
+                .join("snapshots")
+        })
+}
+
+fn file_name(path: &Path) -> Result<String> {
+    path.file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .ok_or_else(|| anyhow!("{}: no file name", path.display()))
+}
+
+/// เปิด `path` เก็บไว้ใน `out`; คืน false ถ้าไม่มีไฟล์
+fn capture(out: &mut Vec<Captured>, path: &Path, role: &'static str) -> Result<bool> {
+    let file = match File::open(path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(anyhow!(e).context(format!("open {}", path.display()))),
+    };
+    let name = file_name(path)?;
+    // restore วางทุกไฟล์ในไดเรกทอรีเดียว
+    if name == MANIFEST_NAME || out.iter().any(|c| c.name == name) {
+        bail!(
+            "cannot snapshot two files named {name} (index and data directories overlap by name)"
+        );
+    }
+    let len = file.metadata()?.len();
+    out.push(Captured {
+        name,
+        role,
+        file,
+        len,
+    });
+    Ok(true)
+}
+
+/// POST /admin/snapshot: หยุดการเขียนชั่วคราว, save index + checkpoint WAL แล้วเปิดทุกไฟล์ไว้;
+/// เขียน tar (+zstd) หลังปล่อย lock แล้ว. ไม่ทำใน READ_ONLY: process ที่ถือ lock ยังเขียนต่อได้
+/// (หยุดไม่ได้จากที่นี่) และจำนวนใน memory ของ process นี้อาจไม่ตรงกับไฟล์
+pub fn snapshot_live(state: &AppState, compress: bool) -> Result<SnapshotReport> {
+    if read_only() {
+        bail!("snapshots need the data directory lock (READ_ONLY=1)");
+    }
+    let (paths, captured, mut manifest) = {
+        let _quiesce = state
+            .write_gate
+            .write()
+            .map_err(|_| anyhow!("write gate poisoned"))?;
+        let paths = state
+            .paths
+            .read()
+            .map_err(|_| anyhow!("paths lock poisoned"))?
+            .clone();
+        let mut idx = state
+            .index
+            .write()
+            .map_err(|_| anyhow!("index lock poisoned"))?;
+        idx.save()?;
+        if let Ok(mut unsaved) = state.unsaved_vectors.write() {
+            *unsaved = 0;
+        }
+        state
+            .wal
+            .lock()
+            .map_err(|_| anyhow!("wal lock poisoned"))?
+            .checkpoint()?;
+
+        let cfg = IndexConfig::from_env()?;
+        let layout = SegmentLayout::from_path(&paths.jsonl_path);
+        let mut files = Vec::new();
+        for path in cfg.files(&paths.index_path) {
+            capture(&mut files, &path, "index")?;
+        }
+        capture(&mut files, &layout.manifest_path(), "segment_manifest")?;
+        for (seq, path) in segment_paths(&paths.jsonl_path)? {
+            // segment อาจเพิ่งถูกบีบอัด (ไฟล์ .jsonl ถูกลบหลังสร้าง .zst เสร็จ)
+            if !capture(&mut files, &path, "segment")?
+                && !capture(&mut files, &layout.compressed_path(seq), "segment")?
+            {
+                bail!("segment {seq} is missing ({})", path.display());
+            }
+        }
+        capture(&mut files, Path::new(&paths.map_path), "vector_map")?;
+        capture(
+            &mut files,
+            Path::new(&deletions_path(&paths.jsonl_path)),
+            "deletions",
+        )?;
+        capture(&mut files, Path::new(&wal_path(&paths.jsonl_path)), "wal")?;
+        files.retain(|c| c.role != "wal" || c.len > 0);
+
+        let manifest = SnapshotManifest {
+            format: FORMAT_VERSION,
+            created_at: Utc::now(),
+            schema_version: SCHEMA_VERSION.to_string(),
+            index_backend: cfg.backend.as_str().to_string(),
+            embed_model: cfg.model.clone(),
+            dim: idx.dim(),
+            paths: SnapshotPaths {
+                index_file: file_name(Path::new(&paths.index_path))?,
+                metadata_file: format!("{}.jsonl", layout.stem()),
+                map_file: file_name(Path::new(&paths.map_path))?,
+            },
+            next_vector_id: *state
+                .next_vector_id
+                .read()
+                .map_err(|_| anyhow!("id lock poisoned"))?,
+            counts: SnapshotCounts {
+                reviews: state
+                    .reviews
+                    .read()
+                    .map_err(|_| anyhow!("reviews lock poisoned"))?
+                    .len(),
+                index_vectors: idx.ids()?.len(),
+                deleted_vectors: state
+                    .deleted
+                    .read()
+                    .map_err(|_| anyhow!("deleted lock poisoned"))?
+                    .len(),
+            },
+            files: Vec::new(),
+        };
+        (paths, files, manifest)
+    };
+
+    let dir = snapshot_dir(&paths);
+    fs::create_dir_all(&dir)?;
+    let name = format!(
+        "snapshot-{}.tar{}",
+        manifest.created_at.format("%Y%m%dT%H%M%S%.3fZ"),
+        if compress { ".zst" } else { "" }
+    );
+    let dest = dir.join(name);
+    let tmp = PathBuf::from(format!("{}.tmp", dest.display()));
+    let written = write_archive(&tmp, captured, &mut manifest, compress);
+    if let Err(e) = written {
+        let _ = fs::remove_file(&tmp);
+        return Err(e);
+    }
+    fs::rename(&tmp, &dest)?;
+    let archive_bytes = fs::metadata(&dest)?.len();
+    info!(
+        "snapshot written: {} ({archive_bytes} bytes)",
+        dest.display()
+    );
+
+    Ok(SnapshotReport {
+        archive: dest.to_string_lossy().into_owned(),
+        archive_bytes,
+        compressed: compress,
+        manifest,
+    })
+}
+
+fn write_archive(
+    tmp: &Path,
+    files: Vec<Captured>,
+    manifest: &mut SnapshotManifest,
+    compress: bool,
+) -> Result<()> {
+    let out = BufWriter::new(File::create(tmp)?);
+    let out = if compress {
+        let enc = zstd::stream::write::Encoder::new(out, ARCHIVE_ZSTD_LEVEL)?;
+        write_tar(enc, files, manifest)?.finish()?
+    } else {
+        write_tar(out, files, manifest)?
+    };
+    out.into_inner()
+        .map_err(|e| anyhow!("flush {}: {}", tmp.display(), e.error()))?
+        .sync_all()?;
+    Ok(())
+}
+
+fn write_tar<W: Write>(w: W, files: Vec<Captured>, manifest: &mut SnapshotManifest) -> Result<W> {
+    let mtime = manifest.created_at.timestamp().max(0) as u64;
+    let header = |size: u64| {
+        let mut h = tar::Header::new_gnu();
+        h.set_size(size);
+        h.set_mode(0o644);
+        h.set_mtime(mtime);
+        h
+    };
+    let mut tar = tar::Builder::new(w);
+    for c in files {
+        let mut reader = Checksum::new(c.file.take(c.len));
+        tar.append_data(&mut header(c.len), &c.name, &mut reader)?;
+        if reader.bytes != c.len {
+            bail!(
+                "{} shrank while archiving ({} of {} bytes)",
+                c.name,
+                reader.bytes,
+                c.len
+            );
+        }
+        let jsonl = c.name.ends_with(".jsonl");
+        manifest.files.push(SnapshotFile {
+            lines: jsonl.then_some(reader.lines),
+            sha256: reader.hex(),
+            name: c.name,
+            role: c.role.to_string(),
+            bytes: c.len,
+        });
+    }
+    let body = serde_json::to_vec_pretty(manifest)?;
+    tar.append_data(
+        &mut header(body.len() as u64),
+        MANIFEST_NAME,
+        body.as_slice(),
+    )?;
+    Ok(tar.into_inner()?)
+}
+
+/// sha256 + จำนวน byte / บรรทัดของทุกอย่างที่อ่านผ่าน
+struct Checksum<R> {
+    inner: R,
+    hasher: Sha256,
+    bytes: u64,
+    lines: u64,
+}
+
+impl<R: Read> Checksum<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+            bytes: 0,
+            lines: 0,
+        }
+    }
+
+    fn hex(self) -> String {
+        format!("{:x}", self.hasher.finalize())
+    }
+}
+
+impl<R: Read> Read for Checksum<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.bytes += n as u64;
+        self.lines += buf[..n].iter().filter(|&&b| b == b'\n').count() as u64;
+        Ok(n)
+    }
+}
+
+/// แตก archive (tar หรือ tar.zst) ลง `dir`; รับเฉพาะไฟล์ธรรมดาชื่อไม่มีไดเรกทอรี
+fn unpack(archive: &Path, dir: &Path) -> Result<()> {
+    let mut file = File::open(archive).with_context(|| format!("open {}", archive.display()))?;
+    let mut magic = [0u8; 4];
+    let zstd = file.read_exact(&mut magic).is_ok() && magic == ZSTD_MAGIC;
+    file.seek(SeekFrom::Start(0))?;
+    let reader: Box<dyn Read> = if zstd {
+        Box::new(zstd::stream::read::Decoder::new(file)?)
+    } else {
+        Box::new(BufReader::new(file))
+    };
+    let mut tar = tar::Archive::new(reader);
+    for entry in tar.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.to_string_lossy().into_owned();
+        if !entry.header().entry_type().is_file()
+            || name.is_empty()
+            || name.contains('/')
+            || name.starts_with('.')
+        {
+            bail!("unexpected archive entry {name:?}");
+        }
+        entry.unpack(dir.join(&name))?;
+    }
+    Ok(())
+}
+
+fn checksum_file(path: &Path) -> Result<(u64, String)> {
+    let mut reader = Checksum::new(BufReader::new(File::open(path)?));
+    std::io::copy(&mut reader, &mut std::io::sink())?;
+    Ok((reader.bytes, reader.hex()))
+}
+
+fn paths_in(dir: &Path, names: &SnapshotPaths) -> Paths {
+    let at = |n: &str| dir.join(n).to_string_lossy().into_owned();
+    Paths {
+        index_path: at(&names.index_file),
+        jsonl_path: at(&names.metadata_file),
+        map_path: at(&names.map_file),
+    }
+}
+
+/// ตรวจไฟล์ที่แตกไว้ใน `staging` เทียบกับ `snapshot.json`: checksum ทุกไฟล์, ไม่มีไฟล์เกิน,
+/// แล้วเปิดชุดข้อมูลจริงเทียบจำนวนที่บันทึกไว้
+fn validate(staging: &Path) -> Result<(SnapshotManifest, VerifyReport)> {
+    let manifest: SnapshotManifest = serde_json::from_slice(
+        &fs::read(staging.join(MANIFEST_NAME)).context("archive has no snapshot.json")?,
+    )
+    .context("parse snapshot.json")?;
+    if manifest.format != FORMAT_VERSION {
+        bail!("unsupported snapshot format {}", manifest.format);
+    }
+    for f in &manifest.files {
+        let (bytes, sha256) = checksum_file(&staging.join(&f.name))
+            .with_context(|| format!("{}: listed in snapshot.json but unreadable", f.name))?;
+        if bytes != f.bytes || sha256 != f.sha256 {
+            bail!("{}: checksum mismatch (archive is corrupt)", f.name);
+        }
+    }
+    for entry in fs::read_dir(staging)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if name != MANIFEST_NAME && !manifest.files.iter().any(|f| f.name == name) {
+            bail!("{name}: in the archive but not in snapshot.json");
+        }
+    }
+
+    let paths = paths_in(staging, &manifest.paths);
+    let mut cfg = IndexConfig::from_env()?;
+    cfg.backend = IndexBackend::parse(&manifest.index_backend)?;
+    cfg.model = manifest.embed_model.clone();
+    cfg.dim = manifest.dim;
+    cfg.adopt_stamp(&paths.index_path)?;
+    let index = cfg.open(&paths.index_path)?;
+    let deleted = load_deleted_vector_ids(&paths.jsonl_path)?;
+    let counts = SnapshotCounts {
+        reviews: ReviewStore::load(&paths.jsonl_path)?.len(),
+        index_vectors: index.ids()?.len(),
+        deleted_vectors: deleted.len(),
+    };
+    if counts != manifest.counts {
+        bail!(
+            "restored data does not match snapshot.json: expected {:?}, found {:?}",
+            manifest.counts,
+            counts
+        );
+    }
+    let report = verify::verify(&paths, &*index, &deleted)?;
+    Ok((manifest, report))
+}
+
+/// แตก `archive` ลง `target` (ต้องว่างหรือยังไม่มี), ตรวจแล้วค่อยย้ายเข้าที่.
+/// ตรวจไม่ผ่าน => `target` ว่างเหมือนเดิม
+pub fn restore(archive: &Path, target: &Path) -> Result<RestoreReport> {
+    fs::create_dir_all(target)?;
+    if fs::read_dir(target)?.next().is_some() {
+        bail!(
+            "{} is not empty; restore into a fresh data directory",
+            target.display()
+        );
+    }
+    // ทุกไฟล์อยู่ใน `target` ไดเรกทอรีเดียว: ชื่อไฟล์ไม่มีผลต่อ lock
+    let in_target = target.join("reviews").to_string_lossy().into_owned();
+    let _lock = DataDirLock::acquire(
+        &Paths {
+            index_path: in_target.clone(),
+            jsonl_path: in_target.clone(),
+            map_path: in_target,
+        },
+        None,
+    )?;
+
+    let staging = target.join(STAGING_DIR_NAME);
+    fs::create_dir(&staging)?;
+    let checked = unpack(archive, &staging).and_then(|()| validate(&staging));
+    let (manifest, report) = match checked {
+        Ok(v) => v,
+        Err(e) => {
+            let _ = fs::remove_dir_all(&staging);
+            return Err(e);
+        }
+    };
+
+    fs::remove_file(staging.join(MANIFEST_NAME))?;
+    for entry in fs::read_dir(&staging)? {
+        let entry = entry?;
+        fs::rename(entry.path(), target.join(entry.file_name()))?;
+    }
+    fs::remove_dir(&staging)?;
+
+    if let Ok(cfg) = IndexConfig::from_env() {
+        if cfg.backend.as_str() != manifest.index_backend {
+            warn!(
+                "snapshot uses INDEX_BACKEND={}; start the server with it",
+                manifest.index_backend
+            );
+        }
+        if cfg.model != manifest.embed_model {
+            warn!(
+                "snapshot was embedded with EMBED_MODEL={}; start the server with it",
+                manifest.embed_model
+            );
+        }
+        let mut stored = cfg.clone();
+        if stored
+            .adopt_stamp(&paths_in(target, &manifest.paths).index_path)
+            .is_ok()
+            && (stored.document_prefix != cfg.document_prefix
+                || stored.preprocess != cfg.preprocess)
+        {
+            warn!(
+                "snapshot was embedded with EMBED_DOCUMENT_PREFIX={:?} and TEXT_PREPROCESS={}; start the server with them",
+                stored.document_prefix, stored.preprocess
+            );
+        }
+    }
+    if !report.consistent {
+        warn!("restored data has inconsistencies (present in the source); see `verify`");
+    }
+    Ok(RestoreReport {
+        archive: archive.to_string_lossy().into_owned(),
+        paths: paths_in(target, &manifest.paths),
+        manifest,
+        verify: report,
+    })
+}
+
+/// `backend restore <archive> <target-dir>` (ไม่แตะ data directory ปัจจุบัน)
+pub fn run_cli(archive: &str, target: &str) -> Result<()> {
+    let report = restore(Path::new(archive), Path::new(target))?;
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
@@ -3,6 +3,8 @@ use std::ffi::{CStr, CString};
 use std::ptr;
 use thiserror::Error;
 
+use crate::index::{check_batch, IndexError, VectorIndex};
+
 #[repr(C)]
 struct SPFreshStatus {
     code: i32,
@@ -32,8 +34,14 @@ extern "C" {
         out_ids: *mut c_longlong,
         out_scores: *mut f32,
     ) -> SPFreshStatus;
+    fn spfresh_delete(handle: Handle, ids: *const c_longlong, n: size_t) -> SPFreshStatus;
+    fn spfresh_ids(
+        handle: Handle,
+        out_ids: *mut c_longlong,
+        cap: size_t,
+        out_n: *mut size_t,
+    ) -> SPFreshStatus;
 
-    #[allow(dead_code)] // 👈 ปิดเตือนถ้ายังไม่เรียกใช้งาน
     fn spfresh_save(handle: Handle) -> SPFreshStatus;
 }
 
@@ -79,35 +87,30 @@ impl Spfresh {
         into_result(st)?;
         Ok(Self { h, dim })
     }
+}
 
-    pub fn add_batch(&self, vectors: &[f32], ids: Option<&[i64]>) -> Result<(), SpfreshError> {
-        if self.dim == 0 {
-            return Err(SpfreshError::InvalidParam("self.dim == 0"));
-        }
-        let n = vectors.len() / self.dim;
-        if vectors.len() != n * self.dim {
-            return Err(SpfreshError::InvalidParam("vectors buffer must be contiguous [n*dim]"));
-        }
-        if let Some(ids_slice) = ids {
-            if ids_slice.len() != n {
-                return Err(SpfreshError::InvalidParam("ids length != number of vectors"));
-            }
-        }
+impl VectorIndex for Spfresh {
+    fn dim(&self) -> usize {
+        self.dim
+    }
+
+    fn add_batch(&mut self, vectors: &[f32], ids: Option<&[i64]>) -> Result<(), IndexError> {
+        let n = check_batch(self.dim, vectors, ids)?;
         if n == 0 {
             return Ok(());
         }
 
         let ids_ptr = ids.map(|v| v.as_ptr()).unwrap_or(ptr::null());
         let st = unsafe { spfresh_add(self.h, vectors.as_ptr(), n as size_t, ids_ptr) };
-        into_result(st)
+        Ok(into_result(st)?)
     }
 
-    pub fn search(&self, query: &[f32], topk: usize) -> Result<(Vec<i64>, Vec<f32>), SpfreshError> {
+    fn search(&self, query: &[f32], topk: usize) -> Result<(Vec<i64>, Vec<f32>), IndexError> {
         if self.dim == 0 {
-            return Err(SpfreshError::InvalidParam("self.dim == 0"));
+            return Err(IndexError::InvalidParam("self.dim == 0"));
         }
         if query.len() != self.dim {
-            return Err(SpfreshError::InvalidParam("query dim mismatch"));
+            return Err(IndexError::InvalidParam("query dim mismatch"));
         }
         if topk == 0 {
             return Ok((Vec::new(), Vec::new()));
@@ -128,10 +131,28 @@ impl Spfresh {
         Ok((ids, scores))
     }
 
-    #[allow(dead_code)] // 👈 ปิดเตือนถ้ายังไม่เรียกใช้งาน
-    pub fn save(&self) -> Result<(), SpfreshError> {
+    fn delete(&mut self, ids: &[i64]) -> Result<(), IndexError> {
+        if ids.is_empty() {
+            return Ok(());
+        }
+        let st = unsafe { spfresh_delete(self.h, ids.as_ptr(), ids.len() as size_t) };
+        Ok(into_result(st)?)
+    }
+
+    fn ids(&self) -> Result<Vec<i64>, IndexError> {
+        // เรียกครั้งแรกเพื่อขอจำนวน แล้วค่อยจอง buffer
+        let mut n: size_t = 0;
+        into_result(unsafe { spfresh_ids(self.h, ptr::null_mut(), 0, &mut n) })?;
+        let mut ids = vec![0i64; n];
+        let st = unsafe { spfresh_ids(self.h, ids.as_mut_ptr(), n, &mut n) };
+        into_result(st)?;
+        ids.truncate(n);
+        Ok(ids)
+    }
+
+    fn save(&mut self) -> Result<(), IndexError> {
         let st = unsafe { spfresh_save(self.h) };
-        into_result(st)
+        Ok(into_result(st)?)
     }
 }
 
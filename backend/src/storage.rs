@@ -1,45 +1,767 @@
-use anyhow::Result;
-use serde_json::Deserializer;
-use std::fs::{File, OpenOptions};
-use std::io::{BufReader, Write};
-use std::path::Path;
+use anyhow::{anyhow, Result};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use tracing::{info, warn};
 
-use crate::types::StoredReview;
+use crate::lock::read_only;
+use crate::schema;
+use crate::seekable::{compress_lines, SeekableFile};
+use crate::segments::{max_segment_bytes, segment_compression, SegmentFile, SegmentLayout, Segments};
+use crate::types::{StoredReview, Tombstone, VectorMapEntry};
 
-/// เขียน 1 บรรทัดของรีวิวลงไฟล์ JSONL
-pub fn append_review_line(path: &str, review: &StoredReview) -> Result<()> {
-    let mut file = OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(path)?;
-    let line = serde_json::to_string(review)? + "\n";
-    file.write_all(line.as_bytes())?;
+/// ตำแหน่งของแถวใน segment ของ reviews (`len` ไม่รวม `\n`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RowLocation {
+    pub segment: u32,
+    pub offset: u64,
+    pub len: u32,
+}
+
+/// segment ที่เปิดอยู่ของแต่ละชุดข้อมูล; lock นี้ยังทำให้ append ทีละบรรทัด
+/// เพื่อให้ offset ที่อ่านจากขนาดไฟล์ถูกต้อง
+static SEGMENTS: Mutex<Vec<Segments>> = Mutex::new(Vec::new());
+
+fn with_segments<R>(jsonl_path: &str, f: impl FnOnce(&mut Segments) -> Result<R>) -> Result<R> {
+    let layout = SegmentLayout::from_path(jsonl_path);
+    let mut open = SEGMENTS.lock().map_err(|_| anyhow!("segments lock poisoned"))?;
+    let i = match open.iter().position(|s| s.layout() == &layout) {
+        Some(i) => i,
+        None => {
+            open.push(Segments::open(jsonl_path)?);
+            open.len() - 1
+        }
+    };
+    f(&mut open[i])
+}
+
+/// ทิ้ง segment ที่ cache ไว้ (ไฟล์ถูกแก้จากนอก append เช่นตอนกู้ท้ายไฟล์) ให้เปิดใหม่ครั้งถัดไป
+fn forget_segments(jsonl_path: &str) -> Result<()> {
+    let layout = SegmentLayout::from_path(jsonl_path);
+    SEGMENTS
+        .lock()
+        .map_err(|_| anyhow!("segments lock poisoned"))?
+        .retain(|s| s.layout() != &layout);
     Ok(())
 }
 
-/// โหลดรีวิวทั้งหมดจากไฟล์ JSONL
-pub fn load_all_reviews(path: &str) -> Result<Vec<StoredReview>> {
-    if !Path::new(path).exists() {
+/// ไฟล์ที่ append ไปแล้วแต่ยังไม่ fsync (segment, sidecar `.idx`, vector_map) —
+/// `sync_appended` fsync ให้ก่อน WAL checkpoint
+static UNSYNCED: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+fn note_unsynced(path: &Path) -> Result<()> {
+    let mut unsynced = UNSYNCED.lock().map_err(|_| anyhow!("unsynced lock poisoned"))?;
+    if !unsynced.iter().any(|p| p == path) {
+        unsynced.push(path.to_path_buf());
+    }
+    Ok(())
+}
+
+/// fsync ทุกไฟล์ที่ append ไปตั้งแต่รอบก่อน (ไฟล์ที่หายไปแล้ว เช่น segment ที่ถูกบีบอัด
+/// เป็น `.zst` ซึ่ง fsync เองอยู่แล้ว ถูกข้าม). ต้องเรียกก่อนล้าง WAL ไม่งั้นแถวที่ตอบ client
+/// ไปแล้วอาจหายเมื่อไฟดับ ทั้งที่เวกเตอร์อยู่ใน index แล้ว
+pub fn sync_appended() -> Result<()> {
+    let mut unsynced = UNSYNCED.lock().map_err(|_| anyhow!("unsynced lock poisoned"))?;
+    while let Some(path) = unsynced.last() {
+        match File::open(path) {
+            Ok(file) => file.sync_data()?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        unsynced.pop();
+    }
+    Ok(())
+}
+
+/// path จริงของทุก segment ของ reviews ตามลำดับ (เก่า → ใหม่)
+pub fn segment_paths(jsonl_path: &str) -> Result<Vec<(u32, PathBuf)>> {
+    with_segments(jsonl_path, |s| Ok(s.paths()))
+}
+
+/// เขียน 1 บรรทัดของรีวิวลง segment ปัจจุบัน (ขึ้น segment ใหม่เมื่อเกิน `SEGMENT_MAX_BYTES`)
+/// และบันทึกตำแหน่งลง sidecar `<segment>.idx`
+pub fn append_review_line(path: &str, review: &StoredReview) -> Result<RowLocation> {
+    let mut line = serde_json::to_string(review)?;
+    let len = line.len() as u32;
+    line.push('\n');
+    let (loc, sealed) = with_segments(path, |segs| {
+        let (segment, seg_path, sealed) = segs.reserve(line.len() as u64, max_segment_bytes())?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&seg_path)?;
+        let offset = file.metadata()?.len();
+        file.write_all(line.as_bytes())?;
+        note_unsynced(&seg_path)?;
+        let loc = RowLocation {
+            segment,
+            offset,
+            len,
+        };
+
+        // sidecar หาย (แต่ไฟล์มีข้อมูลแล้ว) => ไม่เขียนเศษ sidecar; ReviewOffsets::open จะสร้างใหม่ทั้งไฟล์
+        let idx_path = offsets_path(&seg_path);
+        if offset == 0 || fs::metadata(&idx_path).is_ok_and(|m| m.len() > 0) {
+            append_offset_entries(&idx_path, &[(review.vector_id, loc)])?;
+            note_unsynced(&idx_path)?;
+        }
+        segs.note_append(review.vector_id, offset + line.len() as u64);
+        Ok((loc, sealed))
+    })?;
+    if let (Some(seq), Some(level)) = (sealed, segment_compression()?) {
+        queue_compression(path, seq, level)?;
+    }
+    Ok(loc)
+}
+
+/// segment ที่ปิดแล้วรอบีบอัด: (jsonl_path, seq, level)
+type CompressJob = (String, u32, i32);
+
+/// worker บีบอัดตัวเดียวของทั้ง process (เริ่มเมื่อมี segment แรกถูกปิด) —
+/// segment ถูกบีบอัดทีละไฟล์ตามลำดับที่ปิด
+static COMPRESSOR: Mutex<Option<(Sender<CompressJob>, JoinHandle<()>)>> = Mutex::new(None);
+
+fn queue_compression(jsonl_path: &str, seq: u32, level: i32) -> Result<()> {
+    let mut worker = COMPRESSOR.lock().map_err(|_| anyhow!("compressor lock poisoned"))?;
+    if worker.is_none() {
+        let (tx, rx) = mpsc::channel::<CompressJob>();
+        let handle = std::thread::Builder::new()
+            .name("compress".into())
+            .spawn(move || {
+                for (path, seq, level) in rx {
+                    if let Err(e) = compress_segment(&path, seq, level) {
+                        warn!("compress segment {seq} failed: {e:#}");
+                    }
+                }
+            })?;
+        *worker = Some((tx, handle));
+    }
+    if let Some((tx, _)) = worker.as_ref() {
+        // worker ตาย (panic) => segment ค้างแบบไม่บีบอัด; recover_data_files บีบอัดให้ตอนเปิดครั้งถัดไป
+        if tx.send((jsonl_path.to_string(), seq, level)).is_err() {
+            warn!("compress segment {seq}: compressor has stopped");
+        }
+    }
+    Ok(())
+}
+
+/// ตอน shutdown: บีบอัด segment ที่อยู่ในคิวให้เสร็จแล้วรอ worker จบ
+pub fn finish_compression() {
+    let worker = match COMPRESSOR.lock() {
+        Ok(mut w) => w.take(),
+        Err(_) => return,
+    };
+    if let Some((tx, handle)) = worker {
+        drop(tx);
+        if handle.join().is_err() {
+            warn!("compressor thread panicked");
+        }
+    }
+}
+
+/// ขนาด frame (ก่อนบีบอัด) ของ segment ที่บีบอัดแล้ว: อ่านแถวหนึ่ง = decompress ไม่เกินราว ๆ นี้
+const SEEKABLE_FRAME_BYTES: usize = 64 * 1024;
+
+/// บีบอัด segment ที่ปิดแล้วเป็น `<segment>.jsonl.zst` (seekable) แล้วลบไฟล์เดิม.
+/// ผู้อ่านเปิด `.jsonl` ก่อนเสมอ จึงอ่านได้ตลอดระหว่างบีบอัด; sidecar `.idx` ใช้ต่อได้เลย
+pub fn compress_segment(jsonl_path: &str, seq: u32, level: i32) -> Result<()> {
+    let layout = SegmentLayout::from_path(jsonl_path);
+    let src = layout.segment_path(seq);
+    if !src.exists() {
+        return Ok(());
+    }
+    let dst = layout.compressed_path(seq);
+    let tmp = PathBuf::from(format!("{}.tmp", dst.display()));
+    compress_lines(&src, &tmp, level, SEEKABLE_FRAME_BYTES)?;
+    let plain_len = fs::metadata(&src)?.len();
+    if SeekableFile::open(&tmp)?.len() != plain_len {
+        let _ = fs::remove_file(&tmp);
+        return Err(anyhow!("{}: compressed size check failed", src.display()));
+    }
+    fs::rename(&tmp, &dst)?;
+    with_segments(jsonl_path, |segs| segs.mark_compressed(seq))?;
+    fs::remove_file(&src)?;
+    info!(
+        "{}: compressed {plain_len} -> {} bytes",
+        dst.display(),
+        fs::metadata(&dst)?.len()
+    );
+    Ok(())
+}
+
+// ---- Sidecar: vector_id → (offset, len) ใน segment ----
+//
+// `<segment>.idx` = "RVOF" | u32 version | entries `[vector_id u64][offset u64][len u32]`
+// (little-endian, ตามลำดับการ append; vector_id ซ้ำ = entry หลังสุดชนะ)
+
+const OFFSETS_MAGIC: &[u8; 4] = b"RVOF";
+const OFFSETS_VERSION: u32 = 1;
+const OFFSETS_HEADER_LEN: usize = 8;
+const OFFSETS_ENTRY_LEN: usize = 20;
+
+pub fn offsets_path(segment_path: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.idx", segment_path.display()))
+}
+
+fn encode_offset_entry(buf: &mut Vec<u8>, vector_id: usize, loc: RowLocation) {
+    buf.extend_from_slice(&(vector_id as u64).to_le_bytes());
+    buf.extend_from_slice(&loc.offset.to_le_bytes());
+    buf.extend_from_slice(&loc.len.to_le_bytes());
+}
+
+fn append_offset_entries(idx_path: &Path, entries: &[(usize, RowLocation)]) -> Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(idx_path)?;
+    let mut buf = Vec::with_capacity(OFFSETS_HEADER_LEN + entries.len() * OFFSETS_ENTRY_LEN);
+    if file.metadata()?.len() == 0 {
+        buf.extend_from_slice(OFFSETS_MAGIC);
+        buf.extend_from_slice(&OFFSETS_VERSION.to_le_bytes());
+    }
+    for &(vid, loc) in entries {
+        encode_offset_entry(&mut buf, vid, loc);
+    }
+    file.write_all(&buf)?;
+    Ok(())
+}
+
+/// อ่าน sidecar; header เสีย = ไม่มี (None) ให้สร้างใหม่, entry ท้ายที่ไม่ครบถูกตัดทิ้ง
+fn read_offset_entries(idx_path: &Path, segment: u32) -> Result<Option<Vec<(usize, RowLocation)>>> {
+    let bytes = match fs::read(idx_path) {
+        Ok(b) => b,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    if bytes.len() < OFFSETS_HEADER_LEN
+        || &bytes[0..4] != OFFSETS_MAGIC
+        || u32::from_le_bytes(bytes[4..8].try_into().unwrap()) != OFFSETS_VERSION
+    {
+        warn!("{}: bad header; rebuilding", idx_path.display());
+        return Ok(None);
+    }
+    Ok(Some(
+        bytes[OFFSETS_HEADER_LEN..]
+            .chunks_exact(OFFSETS_ENTRY_LEN)
+            .map(|c| {
+                let vid = u64::from_le_bytes(c[0..8].try_into().unwrap()) as usize;
+                let offset = u64::from_le_bytes(c[8..16].try_into().unwrap());
+                let len = u32::from_le_bytes(c[16..20].try_into().unwrap());
+                (vid, RowLocation { segment, offset, len })
+            })
+            .collect(),
+    ))
+}
+
+#[derive(Deserialize)]
+struct VectorIdOnly {
+    vector_id: usize,
+}
+
+/// โหลด sidecar ของ segment หนึ่งแล้วทำให้ตรงกับไฟล์: ตัด entry ที่ชี้เกินท้ายไฟล์,
+/// สแกนแถวที่ sidecar ยังไม่ครอบคลุม (crash หลัง append) และสร้างใหม่ทั้งไฟล์ถ้าหายหรือเสีย
+fn reconcile_offsets(layout: &SegmentLayout, segment: u32) -> Result<Vec<(usize, RowLocation)>> {
+    let seg_path = layout.segment_path(segment);
+    let idx_path = offsets_path(&seg_path);
+    let file = SegmentFile::open(layout, segment)?;
+    let file_len = match &file {
+        Some(f) => f.len()?,
+        None => 0,
+    };
+
+    let stored = read_offset_entries(&idx_path, segment)?;
+    let mut rewrite = stored.is_none();
+    let mut entries = stored.unwrap_or_default();
+    let before = entries.len();
+    entries.retain(|(_, loc)| loc.offset + (loc.len as u64) < file_len);
+    if entries.len() != before {
+        warn!(
+            "{}: dropping {} entries past end of {}",
+            idx_path.display(),
+            before - entries.len(),
+            seg_path.display()
+        );
+        rewrite = true;
+    }
+    let covered = entries
+        .iter()
+        .map(|(_, loc)| loc.offset + loc.len as u64 + 1)
+        .max()
+        .unwrap_or(0);
+
+    // แถวหลัง `covered` ที่ยังไม่มีใน sidecar
+    let mut missing = Vec::new();
+    if let Some(file) = file.filter(|_| covered < file_len) {
+        let mut reader = file.reader_from(covered)?;
+        let mut offset = covered;
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            let n = reader.read_until(b'\n', &mut line)?;
+            if n == 0 || line.last() != Some(&b'\n') {
+                break;
+            }
+            let body = &line[..n - 1];
+            if let Ok(row) = serde_json::from_slice::<VectorIdOnly>(body) {
+                missing.push((
+                    row.vector_id,
+                    RowLocation {
+                        segment,
+                        offset,
+                        len: body.len() as u32,
+                    },
+                ));
+            }
+            offset += n as u64;
+        }
+    }
+
+    if read_only() {
+        // ไม่แก้ sidecar: ใช้ตำแหน่งที่คำนวณได้ในหน่วยความจำเท่านั้น
+        entries.extend(missing);
+        return Ok(entries);
+    }
+    if file_len == 0 {
+        // segment ว่าง (ยังไม่มีไฟล์) — sidecar จะถูกสร้างใหม่ตอน append แรก
+        if idx_path.exists() {
+            fs::remove_file(&idx_path)?;
+        }
         return Ok(vec![]);
     }
-    let file = File::open(path)?;
-    let reader = BufReader::new(file);
-    let stream = Deserializer::from_reader(reader).into_iter::<StoredReview>();
+    if rewrite {
+        let tmp = PathBuf::from(format!("{}.tmp", idx_path.display()));
+        let _ = fs::remove_file(&tmp);
+        let all: Vec<_> = entries.iter().chain(&missing).copied().collect();
+        append_offset_entries(&tmp, &all)?;
+        File::open(&tmp)?.sync_all()?;
+        fs::rename(&tmp, &idx_path)?;
+        info!("{}: rebuilt ({} rows)", idx_path.display(), all.len());
+    } else if !missing.is_empty() {
+        append_offset_entries(&idx_path, &missing)?;
+        info!("{}: indexed {} new row(s)", idx_path.display(), missing.len());
+    }
+    entries.extend(missing);
+    Ok(entries)
+}
+
+/// เขียน segment `seq` ใหม่ทั้งไฟล์โดยแปลงทีละบรรทัด (เช่น migrate schema): เขียนไฟล์ tmp
+/// (บีบอัดใหม่ถ้าเดิมบีบอัดอยู่) แล้ว rename ทับ และลบ sidecar `.idx` ทิ้งให้สร้างใหม่
+/// เพราะ offset เปลี่ยนหมด. ใช้แบบ offline เท่านั้น
+pub fn rewrite_segment(
+    jsonl_path: &str,
+    seq: u32,
+    mut convert: impl FnMut(&[u8]) -> Result<Vec<u8>>,
+) -> Result<()> {
+    let layout = SegmentLayout::from_path(jsonl_path);
+    let Some(file) = SegmentFile::open(&layout, seq)? else {
+        return Ok(());
+    };
+    let compressed = matches!(file, SegmentFile::Zstd(_));
+    let plain = layout.segment_path(seq);
+    let tmp = PathBuf::from(format!("{}.rewrite.tmp", plain.display()));
+    {
+        let mut out = std::io::BufWriter::new(File::create(&tmp)?);
+        for line in file.reader_from(0)?.split(b'\n') {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+            out.write_all(&convert(&line)?)?;
+            out.write_all(b"\n")?;
+        }
+        out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    }
+
+    with_segments(jsonl_path, |segs| {
+        if compressed {
+            let zst = layout.compressed_path(seq);
+            let zst_tmp = PathBuf::from(format!("{}.tmp", zst.display()));
+            compress_lines(&tmp, &zst_tmp, segment_compression()?.unwrap_or(3), SEEKABLE_FRAME_BYTES)?;
+            fs::rename(&zst_tmp, &zst)?;
+            fs::remove_file(&tmp)?;
+        } else {
+            fs::rename(&tmp, &plain)?;
+        }
+        let _ = fs::remove_file(offsets_path(&plain));
+        segs.rescan(seq)
+    })
+}
+
+/// ตำแหน่งของทุกแถวในทุก segment (จาก sidecar `<segment>.idx`) สำหรับอ่านแบบ seek
+pub struct ReviewOffsets {
+    layout: SegmentLayout,
+    /// index = vector_id
+    locations: Vec<Option<RowLocation>>,
+    /// seek table ของ segment ที่บีบอัดแล้ว (โหลดครั้งแรกที่อ่าน)
+    compressed: Mutex<HashMap<u32, Arc<SeekableFile>>>,
+}
+
+impl ReviewOffsets {
+    /// ทำ sidecar ของทุก segment ให้ตรงกับไฟล์ แล้วโหลดตำแหน่งทั้งหมด
+    pub fn open(jsonl_path: &str) -> Result<Self> {
+        let mut out = Self {
+            layout: SegmentLayout::from_path(jsonl_path),
+            locations: Vec::new(),
+            compressed: Mutex::new(HashMap::new()),
+        };
+        for (seq, _) in segment_paths(jsonl_path)? {
+            for (vid, loc) in reconcile_offsets(&out.layout, seq)? {
+                out.record(vid, loc);
+            }
+        }
+        Ok(out)
+    }
+
+    /// จำตำแหน่งแถวใหม่ (หลัง `append_review_line`)
+    pub fn record(&mut self, vector_id: usize, loc: RowLocation) {
+        if vector_id >= self.locations.len() {
+            self.locations.resize(vector_id + 1, None);
+        }
+        self.locations[vector_id] = Some(loc);
+    }
+
+    fn location(&self, vector_id: usize) -> Option<RowLocation> {
+        self.locations.get(vector_id).copied().flatten()
+    }
+
+    fn read_row(&self, loc: RowLocation) -> Result<StoredReview> {
+        let mut buf = vec![0u8; loc.len as usize];
+        // segment อาจถูกบีบอัดระหว่างที่เปิดอยู่: ลอง `.jsonl` ก่อนแล้วค่อย `.jsonl.zst`
+        match File::open(self.layout.segment_path(loc.segment)) {
+            Ok(file) => file.read_exact_at(&mut buf, loc.offset)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let zst = {
+                    let mut cache = self
+                        .compressed
+                        .lock()
+                        .map_err(|_| anyhow!("segment cache poisoned"))?;
+                    match cache.get(&loc.segment) {
+                        Some(z) => z.clone(),
+                        None => {
+                            let z = Arc::new(SeekableFile::open(
+                                &self.layout.compressed_path(loc.segment),
+                            )?);
+                            cache.insert(loc.segment, z.clone());
+                            z
+                        }
+                    }
+                };
+                zst.read_exact_at(&mut buf, loc.offset)?;
+            }
+            Err(e) => return Err(e.into()),
+        }
+        schema::decode_row(&buf)
+    }
+
+    pub fn get_review_by_vector_id(&self, vector_id: usize) -> Result<Option<StoredReview>> {
+        self.location(vector_id)
+            .map(|loc| self.read_row(loc))
+            .transpose()
+    }
+
+    /// อ่านหลายแถว (เรียงตาม segment + offset เพื่ออ่านไปข้างหน้า); ผลลัพธ์เรียงตาม `vector_ids`
+    pub fn get_reviews(&self, vector_ids: &[usize]) -> Result<Vec<Option<StoredReview>>> {
+        let mut order: Vec<(usize, RowLocation)> = vector_ids
+            .iter()
+            .enumerate()
+            .filter_map(|(i, &vid)| self.location(vid).map(|loc| (i, loc)))
+            .collect();
+        order.sort_by_key(|(_, loc)| (loc.segment, loc.offset));
+        let mut out = vec![None; vector_ids.len()];
+        for (i, loc) in order {
+            out[i] = Some(self.read_row(loc)?);
+        }
+        Ok(out)
+    }
+}
+
+/// อ่านทุก record จากไฟล์ JSONL (ไม่มีไฟล์ = ว่าง)
+/// บรรทัดที่ parse ไม่ได้ถูกข้ามและคืนเป็นเลขบรรทัด (เริ่มที่ 1) แทนที่จะทำให้ทั้งไฟล์อ่านไม่ได้
+pub fn load_jsonl_checked<T: DeserializeOwned>(path: &str) -> Result<(Vec<T>, Vec<usize>)> {
+    if !Path::new(path).exists() {
+        return Ok((vec![], vec![]));
+    }
+    parse_jsonl_lines(BufReader::new(File::open(path)?), false)
+}
+
+/// `review_rows`: อัปเกรดแต่ละแถวตาม `schema::MIGRATIONS` ก่อน deserialize
+/// (แถวที่อัปเกรดไม่ได้ เช่น schema ที่ไม่รู้จัก นับเป็นบรรทัดเสียพร้อม warning — ไม่ล้มทั้งไฟล์)
+fn parse_jsonl_lines<T: DeserializeOwned>(
+    reader: impl BufRead,
+    review_rows: bool,
+) -> Result<(Vec<T>, Vec<usize>)> {
     let mut out = Vec::new();
-    for item in stream {
-        out.push(item?);
+    let mut malformed = Vec::new();
+    for (i, line) in reader.split(b'\n').enumerate() {
+        let line = line?;
+        if line.iter().all(u8::is_ascii_whitespace) {
+            continue;
+        }
+        let parsed = if review_rows {
+            match serde_json::from_slice::<serde_json::Value>(&line) {
+                Ok(mut row) => match schema::upgrade(&mut row) {
+                    Ok(_) => serde_json::from_value::<T>(row).ok(),
+                    Err(e) => {
+                        warn!("line {}: {e:#}", i + 1);
+                        None
+                    }
+                },
+                Err(_) => None,
+            }
+        } else {
+            serde_json::from_slice::<T>(&line).ok()
+        };
+        match parsed {
+            Some(item) => out.push(item),
+            None => malformed.push(i + 1),
+        }
+    }
+    Ok((out, malformed))
+}
+
+/// เหมือน `load_jsonl_checked` แต่ log บรรทัดเสียเป็น warning
+fn load_jsonl<T: DeserializeOwned>(path: &str) -> Result<Vec<T>> {
+    let (out, malformed) = load_jsonl_checked(path)?;
+    if !malformed.is_empty() {
+        warn!("{path}: skipped malformed line(s) {malformed:?}");
     }
     Ok(out)
 }
 
-/// เขียน mapping (vector_id → review_id) 1 บรรทัด
-pub fn append_vector_map_line(path: &str, vector_id: usize, review_id: &str) -> Result<()> {
+/// กู้ไฟล์ JSONL หลัง crash กลางการ append: ถ้าท้ายไฟล์มีเศษ record ที่ไม่มี `\n` ปิดท้าย
+/// และ parse ไม่ได้ ย้ายเศษนั้นไป `<path>.quarantine` แล้วตัดไฟล์ถึงบรรทัดสุดท้ายที่สมบูรณ์.
+/// ถ้าเศษนั้นเป็น JSON ที่ครบแล้ว (ขาดแค่ `\n`) เติม `\n` ให้. คืนจำนวน byte ที่ถูกกักไว้
+fn recover_jsonl_tail(path: &str) -> Result<usize> {
+    if !Path::new(path).exists() {
+        return Ok(0);
+    }
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    let len = file.metadata()?.len();
+
+    // หา '\n' ตัวสุดท้าย โดยอ่านย้อนจากท้ายไฟล์ทีละก้อน
+    const BLOCK: u64 = 64 * 1024;
+    let mut good_len = 0;
+    let mut end = len;
+    let mut buf = Vec::new();
+    while end > 0 {
+        let start = end.saturating_sub(BLOCK);
+        buf.resize((end - start) as usize, 0);
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(&mut buf)?;
+        if let Some(pos) = buf.iter().rposition(|&b| b == b'\n') {
+            good_len = start + pos as u64 + 1;
+            break;
+        }
+        end = start;
+    }
+    if good_len == len {
+        return Ok(0);
+    }
+
+    let mut tail = Vec::with_capacity((len - good_len) as usize);
+    file.seek(SeekFrom::Start(good_len))?;
+    file.read_to_end(&mut tail)?;
+
+    if serde_json::from_slice::<serde_json::Value>(&tail).is_ok() {
+        warn!("{path}: last record had no trailing newline; adding one");
+        file.seek(SeekFrom::End(0))?;
+        file.write_all(b"\n")?;
+        file.sync_data()?;
+        return Ok(0);
+    }
+
+    let quarantine = format!("{path}.quarantine");
+    let mut q = OpenOptions::new().create(true).append(true).open(&quarantine)?;
+    q.write_all(&tail)?;
+    q.write_all(b"\n")?;
+    q.sync_data()?;
+
+    file.set_len(good_len)?;
+    file.sync_data()?;
+    warn!(
+        "{path}: moved a partial trailing record ({} bytes at offset {good_len}) to {quarantine}",
+        tail.len()
+    );
+    Ok(tail.len())
+}
+
+/// `recover_jsonl_tail` กับทุกไฟล์ JSONL ของชุดข้อมูล (ทุก segment ของ reviews, map, deletions)
+/// ก่อนเริ่มเขียนต่อท้าย
+pub fn recover_data_files(jsonl_path: &str, map_path: &str) -> Result<()> {
+    forget_segments(jsonl_path)?;
+    remove_stale_tmp_files(jsonl_path)?;
+    // segment ที่บีบอัดแล้วเขียนเสร็จก่อน rename เสมอ — กู้เฉพาะ `.jsonl`
+    for (_, seg_path) in Segments::open(jsonl_path)?.paths() {
+        if seg_path.extension().is_some_and(|e| e == "jsonl") {
+            recover_jsonl_tail(&seg_path.to_string_lossy())?;
+        }
+    }
+    for path in [map_path, &deletions_path(jsonl_path)] {
+        recover_jsonl_tail(path)?;
+    }
+    // ขนาด segment อาจเปลี่ยนหลังตัดท้ายไฟล์: เปิด manifest ใหม่
+    forget_segments(jsonl_path)?;
+    // ให้ sidecar ตามทันแถวที่เขียนก่อน crash ก่อนจะมี append ใหม่ (เช่น WAL replay)
+    ReviewOffsets::open(jsonl_path)?;
+    // segment ที่ปิดแล้วแต่ยังไม่ถูกบีบอัด (crash ระหว่างบีบอัด / เพิ่งเปิด SEGMENT_COMPRESSION)
+    if let Some(level) = segment_compression()? {
+        for seq in with_segments(jsonl_path, |s| Ok(s.pending_compression()))? {
+            compress_segment(jsonl_path, seq, level)?;
+        }
+    }
+    Ok(())
+}
+
+/// ลบ `<segment>.jsonl.zst.tmp` ที่ค้างจากการบีบอัดที่ crash กลางทาง (ยังไม่ถูก rename จึงไม่มีใครอ่าน)
+fn remove_stale_tmp_files(jsonl_path: &str) -> Result<()> {
+    let layout = SegmentLayout::from_path(jsonl_path);
+    let prefix = format!("{}-", layout.stem());
+    let entries = match fs::read_dir(layout.dir()) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    for entry in entries {
+        let path = entry?.path();
+        let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+            continue;
+        };
+        if name.starts_with(&prefix) && name.ends_with(".jsonl.zst.tmp") {
+            fs::remove_file(&path)?;
+            warn!("{}: removed leftover of an interrupted compression", path.display());
+        }
+    }
+    Ok(())
+}
+
+/// อ่านทุก segment ของ reviews ตามลำดับ; บรรทัดที่ parse ไม่ได้คืนเป็น `path:line`
+pub fn load_reviews_checked<T: DeserializeOwned>(jsonl_path: &str) -> Result<(Vec<T>, Vec<String>)> {
+    let mut out = Vec::new();
+    let mut malformed = Vec::new();
+    let layout = SegmentLayout::from_path(jsonl_path);
+    for (seq, seg_path) in segment_paths(jsonl_path)? {
+        let Some(file) = SegmentFile::open(&layout, seq)? else {
+            continue;
+        };
+        let seg_path = seg_path.to_string_lossy();
+        let (rows, bad) = parse_jsonl_lines::<T>(file.reader_from(0)?, true)?;
+        out.extend(rows);
+        malformed.extend(bad.into_iter().map(|n| format!("{seg_path}:{n}")));
+    }
+    Ok((out, malformed))
+}
+
+/// โหลดรีวิวทั้งหมดจากทุก segment
+pub fn load_all_reviews(path: &str) -> Result<Vec<StoredReview>> {
+    let (rows, malformed) = load_reviews_checked(path)?;
+    if !malformed.is_empty() {
+        warn!("skipped malformed review line(s) {malformed:?}");
+    }
+    Ok(rows)
+}
+
+/// เขียน mapping (vector_id → review_id) ของทุก vector ในแถว
+pub fn append_vector_map_lines(path: &str, review: &StoredReview) -> Result<()> {
     let mut file = OpenOptions::new()
         .create(true)
         .append(true)
         .open(path)?;
-    let line =
-        serde_json::json!({ "vector_id": vector_id, "review_id": review_id }).to_string() + "\n";
+    // หนึ่งบรรทัดต่อ vector ของแถว (รีวิวที่แบ่ง chunk มีหลายบรรทัด) — เขียนครั้งเดียว
+    let mut buf = String::new();
+    for (chunk, vector_id) in review.vector_ids().enumerate() {
+        let entry = VectorMapEntry {
+            vector_id,
+            review_id: review.id.clone(),
+            chunk,
+        };
+        buf.push_str(&serde_json::to_string(&entry)?);
+        buf.push('\n');
+    }
+    file.write_all(buf.as_bytes())?;
+    note_unsynced(Path::new(path))?;
+    Ok(())
+}
+
+/// โหลด vector_map.jsonl ทั้งไฟล์
+pub fn load_vector_map(path: &str) -> Result<Vec<VectorMapEntry>> {
+    load_jsonl(path)
+}
+
+/// เขียน vector_map.jsonl ใหม่ทั้งไฟล์ (tmp + fsync + rename) — ใช้ตอน repair เท่านั้น
+pub fn rewrite_vector_map(path: &str, entries: &[VectorMapEntry]) -> Result<()> {
+    let tmp = format!("{path}.tmp");
+    {
+        let mut file = File::create(&tmp)?;
+        let mut buf = String::new();
+        for e in entries {
+            buf.push_str(&serde_json::to_string(e)?);
+            buf.push('\n');
+        }
+        file.write_all(buf.as_bytes())?;
+        file.sync_all()?;
+    }
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// path ของ log การลบ: `deletions.jsonl` ในไดเรกทอรีเดียวกับ segment ของ reviews
+pub fn deletions_path(jsonl_path: &str) -> String {
+    SegmentLayout::from_path(jsonl_path)
+        .dir()
+        .join("deletions.jsonl")
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// path ของ write-ahead log: `wal.jsonl` ในไดเรกทอรีเดียวกับ segment ของ reviews
+pub fn wal_path(jsonl_path: &str) -> String {
+    SegmentLayout::from_path(jsonl_path)
+        .dir()
+        .join("wal.jsonl")
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// เขียน tombstone 1 บรรทัดลง deletions.jsonl
+pub fn append_tombstone_line(path: &str, tombstone: &Tombstone) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    let line = serde_json::to_string(tombstone)? + "\n";
     file.write_all(line.as_bytes())?;
     Ok(())
 }
+
+/// vector_id ทั้งหมดที่ถูกลบแล้ว (จาก deletions.jsonl ข้าง ๆ `jsonl_path`)
+pub fn load_deleted_vector_ids(jsonl_path: &str) -> Result<HashSet<usize>> {
+    let tombstones: Vec<Tombstone> = load_jsonl(&deletions_path(jsonl_path))?;
+    Ok(tombstones.into_iter().map(|t| t.vector_id).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::SCHEMA_VERSION;
+
+    #[test]
+    fn unknown_schema_version_is_a_malformed_line() {
+        let data = concat!(
+            r#"{"id":"a","review":"first","rating":3,"schema_version":"v1","vector_id":0}"#,
+            "\n",
+            r#"{"id":"b","review":"from a newer build","rating":4,"schema_version":"v9","vector_id":1}"#,
+            "\n",
+            r#"{"id":"c","review":"current","rating":5,"schema_version":"v1","vector_id":2}"#,
+            "\n",
+        );
+        let (rows, malformed) = parse_jsonl_lines::<StoredReview>(data.as_bytes(), true).unwrap();
+        let ids: Vec<&str> = rows.iter().map(|r| r.id.as_str()).collect();
+        assert_eq!(ids, ["a", "c"]);
+        assert_eq!(rows[0].schema_version, SCHEMA_VERSION);
+        assert_eq!(malformed, [2]);
+    }
+}
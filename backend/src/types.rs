@@ -1,9 +1,15 @@
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::ops::Range;
 use uuid::Uuid;
 
+use crate::handlers::Paths;
+
 pub type ReviewId = String;
 
-/// Central schema version for metadata evolution.
+/// Central schema version for metadata evolution. Older rows are upgraded on
+/// read through the migrations in `schema.rs`; `backend migrate` rewrites them.
 pub const SCHEMA_VERSION: &str = "v1";
 
 /// Input struct (single review). Extendable: add new optional fields here.
@@ -55,13 +61,82 @@ pub struct StoredReview {
     #[serde(default)]
     pub category: Option<String>,
     pub schema_version: String,
+    /// First (or only) vector of this row; a chunked row owns
+    /// `vector_id .. vector_id + chunks.len()`.
     pub vector_id: usize,
+    /// Byte ranges `[start, end)` of `review`, one vector each, for reviews longer
+    /// than `CHUNK_WORDS`; empty when the whole text is a single vector.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chunks: Vec<[usize; 2]>,
+}
+
+/// Partial update for `PATCH /reviews/:id`; absent (or null) fields keep
+/// their current value. Use `PUT` to clear `category`.
+#[derive(Debug, Deserialize, Clone)]
+pub struct ReviewPatch {
+    #[serde(default)]
+    pub review: Option<String>,
+    #[serde(default)]
+    pub rating: Option<i32>,
+    #[serde(default)]
+    pub category: Option<String>,
+}
+
+impl ReviewPatch {
+    /// Merge onto the current stored row; the result still needs `validate()`.
+    pub fn apply_to(self, current: &StoredReview) -> ReviewInput {
+        ReviewInput {
+            review: self.review.unwrap_or_else(|| current.review.clone()),
+            rating: self.rating.unwrap_or(current.rating),
+            category: self.category.or_else(|| current.category.clone()),
+        }
+    }
 }
 
 impl StoredReview {
     pub fn from_input(input: ReviewInput, vector_id: usize) -> Self {
+        Self::with_id(Uuid::new_v4().to_string(), input, vector_id)
+    }
+
+    /// New version of an existing review: same `id`, appended as a new line.
+    pub fn revision_of(current: &StoredReview, input: ReviewInput, vector_id: usize) -> Self {
+        Self::with_id(current.id.clone(), input, vector_id)
+    }
+
+    /// Chunk spans from `Chunker::spans` for this row's text (set before the ids are used).
+    pub fn with_chunks(mut self, chunks: Vec<[usize; 2]>) -> Self {
+        self.chunks = chunks;
+        self
+    }
+
+    /// Number of vectors (and vector_ids) this row owns.
+    pub fn vector_count(&self) -> usize {
+        self.chunks.len().max(1)
+    }
+
+    pub fn vector_ids(&self) -> Range<usize> {
+        self.vector_id..self.vector_id + self.vector_count()
+    }
+
+    /// `(vector_id, text)` for every vector of this row, in id order.
+    pub fn vector_texts(&self) -> Vec<(usize, String)> {
+        self.vector_ids()
+            .zip(crate::chunk::texts(&self.review, &self.chunks))
+            .collect()
+    }
+
+    /// The editable fields of this row, e.g. to append it again under a new `vector_id`.
+    pub fn to_input(&self) -> ReviewInput {
+        ReviewInput {
+            review: self.review.clone(),
+            rating: self.rating,
+            category: self.category.clone(),
+        }
+    }
+
+    fn with_id(id: ReviewId, input: ReviewInput, vector_id: usize) -> Self {
         Self {
-            id: Uuid::new_v4().to_string(),
+            id,
             review: input.review,
             rating: input.rating,
             // normalize category to trimmed Some(...) or None
@@ -71,10 +146,39 @@ impl StoredReview {
             }),
             schema_version: SCHEMA_VERSION.to_string(),
             vector_id,
+            chunks: Vec::new(),
         }
     }
 }
 
+/// One line of `vector_map.jsonl`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct VectorMapEntry {
+    pub vector_id: usize,
+    pub review_id: ReviewId,
+    /// Position of this vector among the review's chunks (absent = 0).
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub chunk: usize,
+}
+
+fn is_zero(n: &usize) -> bool {
+    *n == 0
+}
+
+/// Deletion record, one line per retired vector in `deletions.jsonl`
+/// (next to `reviews.jsonl`). The vector is tombstoned in the index and
+/// search skips `vector_id`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Tombstone {
+    pub review_id: ReviewId,
+    pub vector_id: usize,
+    pub deleted_at: DateTime<Utc>,
+    /// Set when the vector was replaced by `PUT`/`PATCH` (the new `vector_id`);
+    /// `None` for a real delete.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub superseded_by: Option<usize>,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct BulkReviews(pub Vec<ReviewInput>);
 
@@ -82,15 +186,220 @@ pub struct BulkReviews(pub Vec<ReviewInput>);
 pub struct SearchRequest {
     pub query: String,
     pub top_k: Option<usize>,
+    /// Optional HNSW candidate list size for this query (overrides `HNSW_EF_SEARCH`).
+    /// Ignored by engines without an `ef` knob.
+    #[serde(default)]
+    pub ef: Option<usize>,
+    /// How chunk scores combine into a review score (default `SEARCH_CHUNK_AGG`, `max`).
+    #[serde(default)]
+    pub aggregate: Option<ChunkAggregate>,
+}
+
+/// Score of a chunked review from its chunks that the index returned.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChunkAggregate {
+    /// best chunk
+    Max,
+    /// average of the returned chunks
+    Mean,
 }
 
 #[derive(Debug, Serialize)]
 pub struct SearchHit {
     pub review: StoredReview,
     pub score: f32,
+    /// Best-scoring chunk, for reviews split into chunks.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chunk: Option<ChunkMatch>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChunkMatch {
+    /// 0-based position in `review.chunks`
+    pub index: usize,
+    /// chunks of the review
+    pub count: usize,
+    /// chunks of this review among the index results
+    pub matched: usize,
+    pub score: f32,
+    pub text: String,
 }
 
 #[derive(Debug, Serialize)]
 pub struct SearchResponse {
     pub hits: Vec<SearchHit>,
-}
\ No newline at end of file
+}
+
+/// Progress of the last (or running) index rebuild, returned by
+/// `GET`/`POST /admin/rebuild`.
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct RebuildStatus {
+    pub running: bool,
+    /// live vectors to embed (last row per `vector_id`, deletions excluded)
+    pub total: usize,
+    pub embedded: usize,
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Result of cross-checking `reviews.jsonl`, `vector_map.jsonl`,
+/// `deletions.jsonl` and the vector index (`GET /admin/verify`, `backend verify`).
+/// A review's *current* row is its last line; it is live unless its
+/// `vector_id` is in `deletions.jsonl`.
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct VerifyReport {
+    pub review_rows: usize,
+    pub live_reviews: usize,
+    pub map_lines: usize,
+    pub index_vectors: usize,
+    /// vector_ids used by a review row but absent from `vector_map.jsonl`
+    pub missing_from_map: Vec<usize>,
+    /// `vector_map.jsonl` entries with no review row
+    pub orphaned_in_map: Vec<usize>,
+    /// vector_ids mapped more than once, or mapped to a different review id than the row
+    pub duplicate_in_map: Vec<usize>,
+    /// vector_ids that are the current vector of more than one review
+    pub conflicting_vector_ids: Vec<usize>,
+    /// vectors of superseded rows that were never tombstoned (still searchable)
+    pub stale_vectors: Vec<usize>,
+    /// live vector_ids the index does not contain
+    pub missing_from_index: Vec<usize>,
+    /// index ids that are not a live review's vector
+    pub orphaned_in_index: Vec<usize>,
+    /// unparseable lines (`path:line`, 1-based); skipped on read, left in place for manual fixing
+    pub malformed_lines: Vec<String>,
+    /// smallest vector_id not used by any file or the index
+    pub next_vector_id: usize,
+    /// the server's id counter, when it is below `next_vector_id` (ids would be reused)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub counter_behind: Option<usize>,
+    pub consistent: bool,
+}
+
+/// What `POST /admin/repair` / `backend repair` changed.
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct RepairReport {
+    pub before: VerifyReport,
+    /// reviews appended again under a fresh vector_id (conflicting ids)
+    pub reassigned: usize,
+    /// tombstones appended to `deletions.jsonl`
+    pub tombstoned: usize,
+    pub map_rewritten: bool,
+    pub removed_from_index: usize,
+    pub added_to_index: usize,
+    pub after: VerifyReport,
+}
+
+/// Per-segment counts in a [`MigrationReport`].
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct SegmentMigration {
+    pub file: String,
+    pub rows: usize,
+    /// rows per stored `schema_version` (before upgrading)
+    pub by_version: BTreeMap<String, usize>,
+    pub to_upgrade: usize,
+    pub malformed_lines: usize,
+}
+
+/// What `backend migrate` found and, unless `dry_run`, rewrote.
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct MigrationReport {
+    pub target_version: String,
+    pub dry_run: bool,
+    /// migration steps needed by the rows found (`from -> to: description`)
+    pub steps: Vec<String>,
+    pub segments: Vec<SegmentMigration>,
+    pub rows: usize,
+    pub rows_to_upgrade: usize,
+    /// segments rewritten to `target_version` (0 on a dry run)
+    pub rewritten_segments: usize,
+}
+
+/// Body of `POST /admin/snapshot` (optional).
+#[derive(Debug, Deserialize, Default)]
+pub struct SnapshotRequest {
+    /// zstd-compress the archive (default `true`)
+    pub compress: Option<bool>,
+}
+
+/// File names (no directory) of a snapshot's [`Paths`];
+/// `restore` puts them all in the target directory.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SnapshotPaths {
+    pub index_file: String,
+    pub metadata_file: String,
+    pub map_file: String,
+}
+
+/// Counts taken while writes were paused; `restore` re-checks them.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct SnapshotCounts {
+    /// distinct review ids (including deleted ones)
+    pub reviews: usize,
+    pub index_vectors: usize,
+    pub deleted_vectors: usize,
+}
+
+/// One archived file.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SnapshotFile {
+    pub name: String,
+    /// `index` | `segment_manifest` | `segment` | `vector_map` | `deletions` | `wal`
+    pub role: String,
+    pub bytes: u64,
+    pub sha256: String,
+    /// newline count, for uncompressed JSONL files
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lines: Option<u64>,
+}
+
+/// `snapshot.json`, the last entry of a snapshot archive.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SnapshotManifest {
+    pub format: u32,
+    pub created_at: DateTime<Utc>,
+    pub schema_version: String,
+    pub index_backend: String,
+    /// `EMBED_MODEL` the vectors came from
+    pub embed_model: String,
+    pub dim: usize,
+    pub paths: SnapshotPaths,
+    pub next_vector_id: usize,
+    pub counts: SnapshotCounts,
+    pub files: Vec<SnapshotFile>,
+}
+
+/// Result of `POST /admin/snapshot`.
+#[derive(Debug, Serialize, Clone)]
+pub struct SnapshotReport {
+    pub archive: String,
+    pub archive_bytes: u64,
+    pub compressed: bool,
+    pub manifest: SnapshotManifest,
+}
+
+/// Result of `backend restore`.
+#[derive(Debug, Serialize, Clone)]
+pub struct RestoreReport {
+    pub archive: String,
+    /// paths to start the server with (`INDEX_FILE`, `METADATA_FILE`, `MAP_FILE`)
+    pub paths: Paths,
+    pub manifest: SnapshotManifest,
+    /// cross-check of the restored files (inconsistencies were already in the source)
+    pub verify: VerifyReport,
+}
+
+/// `GET /admin/query-cache`: LRU of search query embeddings (`QUERY_CACHE_SIZE`).
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct QueryCacheStats {
+    /// model the cached vectors belong to (the cache is emptied when it changes)
+    pub model: String,
+    /// 0 = cache disabled
+    pub capacity: usize,
+    pub entries: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
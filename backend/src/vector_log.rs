@@ -0,0 +1,346 @@
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use tracing::warn;
+
+use crate::index::IndexError;
+use crate::lock::read_only;
+
+const MAGIC: &[u8; 4] = b"RVFL";
+const VERSION: u32 = 1;
+/// magic + version + dim
+const HEADER_LEN: u64 = 12;
+
+/// Append-only vector file shared by the Rust index engines.
+///
+/// Layout: a 12-byte header followed by `[id: i64][vector: dim * f32]`
+/// records (little-endian). A partial trailing record (crash mid-append)
+/// is truncated away on open. Deleted ids are appended as raw `i64`s to a
+/// `<path>.del` sidecar.
+///
+/// With `READ_ONLY=1` the files are only read: nothing is created, a partial
+/// record is ignored in memory instead of truncated, and writes fail.
+pub struct VectorLog {
+    path: PathBuf,
+    /// `None` = read-only
+    writer: Option<BufWriter<File>>,
+    del_file: Option<File>,
+}
+
+/// Everything read back by [`VectorLog::open`], in append order.
+pub struct LoadedVectors {
+    pub ids: Vec<i64>,
+    /// raw (not normalized) vectors, `ids.len() * dim`
+    pub vectors: Vec<f32>,
+    /// tombstoned ids; their records are still present in `ids`/`vectors`
+    pub deleted: HashSet<i64>,
+}
+
+impl VectorLog {
+    /// Open (or create) the log and return every stored record plus tombstones.
+    pub fn open(path: &Path, dim: usize) -> Result<(Self, LoadedVectors), IndexError> {
+        Self::open_as(path, dim, !read_only())
+    }
+
+    fn open_as(
+        path: &Path,
+        dim: usize,
+        writable: bool,
+    ) -> Result<(Self, LoadedVectors), IndexError> {
+        if dim == 0 {
+            return Err(IndexError::InvalidParam("dim == 0"));
+        }
+        let mut file = match OpenOptions::new()
+            .read(true)
+            .write(writable)
+            .create(writable)
+            .truncate(false)
+            .open(path)
+        {
+            Ok(file) => file,
+            // read-only และยังไม่มี log = index ว่าง
+            Err(e) if !writable && e.kind() == ErrorKind::NotFound => {
+                let (_, deleted) = open_tombstones(&del_path(path), false)?;
+                let log = Self {
+                    path: path.to_path_buf(),
+                    writer: None,
+                    del_file: None,
+                };
+                return Ok((
+                    log,
+                    LoadedVectors {
+                        ids: Vec::new(),
+                        vectors: Vec::new(),
+                        deleted,
+                    },
+                ));
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let len = file.metadata()?.len();
+        let mut ids = Vec::new();
+        let mut vectors = Vec::new();
+
+        if len == 0 && !writable {
+            // ยังไม่มี header: อ่านได้ว่าเป็น log ว่าง
+        } else if len == 0 {
+            file.write_all(MAGIC)?;
+            file.write_all(&VERSION.to_le_bytes())?;
+            file.write_all(&(dim as u32).to_le_bytes())?;
+            file.sync_data()?;
+        } else {
+            let mut reader = BufReader::new(&mut file);
+            let mut header = [0u8; HEADER_LEN as usize];
+            reader
+                .read_exact(&mut header)
+                .map_err(|_| IndexError::Corrupt(format!("{}: short header", path.display())))?;
+            if &header[0..4] != MAGIC {
+                return Err(IndexError::Corrupt(format!("{}: bad magic", path.display())));
+            }
+            let version = u32::from_le_bytes(header[4..8].try_into().unwrap());
+            if version != VERSION {
+                return Err(IndexError::Corrupt(format!(
+                    "{}: unsupported version {version}",
+                    path.display()
+                )));
+            }
+            let file_dim = u32::from_le_bytes(header[8..12].try_into().unwrap()) as usize;
+            if file_dim != dim {
+                return Err(IndexError::Corrupt(format!(
+                    "{}: dim {file_dim} != configured {dim}",
+                    path.display()
+                )));
+            }
+
+            let record_len = 8 + dim * 4;
+            let body = len - HEADER_LEN;
+            let n = (body / record_len as u64) as usize;
+            ids.reserve(n);
+            vectors.reserve(n * dim);
+
+            let mut buf = vec![0u8; record_len];
+            for _ in 0..n {
+                reader.read_exact(&mut buf)?;
+                ids.push(i64::from_le_bytes(buf[0..8].try_into().unwrap()));
+                vectors.extend(
+                    buf[8..]
+                        .chunks_exact(4)
+                        .map(|c| f32::from_le_bytes(c.try_into().unwrap())),
+                );
+            }
+            drop(reader);
+
+            // ตัดเศษ record ที่เขียนไม่ครบ (crash กลางการ append); read-only แค่ไม่อ่านส่วนนั้น
+            let good_len = HEADER_LEN + (n * record_len) as u64;
+            if good_len != len {
+                warn!(
+                    "{}: {} {} trailing bytes of a partial record",
+                    path.display(),
+                    if writable { "dropping" } else { "ignoring" },
+                    len - good_len
+                );
+                if writable {
+                    file.set_len(good_len)?;
+                }
+            }
+        }
+
+        let (del_file, deleted) = open_tombstones(&del_path(path), writable)?;
+        let writer = if writable {
+            file.seek(SeekFrom::End(0))?;
+            Some(BufWriter::new(file))
+        } else {
+            None
+        };
+        let log = Self {
+            path: path.to_path_buf(),
+            writer,
+            del_file,
+        };
+        Ok((
+            log,
+            LoadedVectors {
+                ids,
+                vectors,
+                deleted,
+            },
+        ))
+    }
+
+    fn read_only_error(&self) -> IndexError {
+        IndexError::Io(io::Error::new(
+            ErrorKind::PermissionDenied,
+            format!("{}: opened read-only", self.path.display()),
+        ))
+    }
+
+    /// Record tombstones for `ids` (written straight to the OS, fsync on `sync`).
+    pub fn delete(&mut self, ids: &[i64]) -> Result<(), IndexError> {
+        let Some(del_file) = &mut self.del_file else {
+            return Err(self.read_only_error());
+        };
+        let buf: Vec<u8> = ids.iter().flat_map(|id| id.to_le_bytes()).collect();
+        del_file.write_all(&buf)?;
+        Ok(())
+    }
+
+    pub fn append(&mut self, id: i64, vector: &[f32]) -> Result<(), IndexError> {
+        let Some(writer) = &mut self.writer else {
+            return Err(self.read_only_error());
+        };
+        writer.write_all(&id.to_le_bytes())?;
+        for x in vector {
+            writer.write_all(&x.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Hand buffered records to the OS (no fsync). No-op when read-only.
+    pub fn flush(&mut self) -> Result<(), IndexError> {
+        match &mut self.writer {
+            Some(writer) => Ok(writer.flush()?),
+            None => Ok(()),
+        }
+    }
+
+    /// Flush and fsync. No-op when read-only.
+    pub fn sync(&mut self) -> Result<(), IndexError> {
+        let (Some(writer), Some(del_file)) = (&mut self.writer, &self.del_file) else {
+            return Ok(());
+        };
+        writer.flush()?;
+        writer
+            .get_ref()
+            .sync_data()
+            .and_then(|_| del_file.sync_data())
+            .map_err(|e| {
+                warn!("fsync {} failed: {e}", self.path.display());
+                IndexError::Io(e)
+            })
+    }
+}
+
+fn del_path(path: &Path) -> PathBuf {
+    let mut p = path.as_os_str().to_owned();
+    p.push(".del");
+    PathBuf::from(p)
+}
+
+/// Read `<log>.del` (dropping a torn trailing id) and, when `writable`, reopen
+/// it for appending. Read-only: a missing file is empty and a torn id is ignored.
+fn open_tombstones(
+    path: &Path,
+    writable: bool,
+) -> Result<(Option<File>, HashSet<i64>), IndexError> {
+    let mut file = match OpenOptions::new()
+        .read(true)
+        .append(writable)
+        .create(writable)
+        .open(path)
+    {
+        Ok(file) => file,
+        Err(e) if !writable && e.kind() == ErrorKind::NotFound => {
+            return Ok((None, HashSet::new()))
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let mut buf = Vec::new();
+    file.read_to_end(&mut buf)?;
+    let whole = buf.len() - buf.len() % 8;
+    if whole != buf.len() {
+        if writable {
+            warn!("{}: dropping a partial tombstone", path.display());
+            file.set_len(whole as u64)?;
+        } else {
+            warn!("{}: ignoring a partial tombstone", path.display());
+        }
+    }
+    let deleted = buf[..whole]
+        .chunks_exact(8)
+        .map(|c| i64::from_le_bytes(c.try_into().unwrap()))
+        .collect();
+    Ok((writable.then_some(file), deleted))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    const DIM: usize = 4;
+
+    fn write_log(path: &Path) {
+        let (mut log, _) = VectorLog::open_as(path, DIM, true).unwrap();
+        for id in 0..3 {
+            log.append(id, &[id as f32; DIM]).unwrap();
+        }
+        log.delete(&[1]).unwrap();
+        log.sync().unwrap();
+    }
+
+    fn append_bytes(path: &Path, bytes: &[u8]) {
+        OpenOptions::new()
+            .append(true)
+            .open(path)
+            .unwrap()
+            .write_all(bytes)
+            .unwrap();
+    }
+
+    #[test]
+    fn writable_open_truncates_torn_tails() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("v.log");
+        write_log(&path);
+        let (len, del_len) = (
+            path.metadata().unwrap().len(),
+            del_path(&path).metadata().unwrap().len(),
+        );
+        append_bytes(&path, &[7; 10]);
+        append_bytes(&del_path(&path), &[7; 3]);
+
+        let (_, loaded) = VectorLog::open_as(&path, DIM, true).unwrap();
+        assert_eq!(loaded.ids, [0, 1, 2]);
+        assert_eq!(loaded.deleted, HashSet::from([1]));
+        assert_eq!(path.metadata().unwrap().len(), len);
+        assert_eq!(del_path(&path).metadata().unwrap().len(), del_len);
+    }
+
+    #[test]
+    fn read_only_open_ignores_torn_tails_without_writing() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("v.log");
+        write_log(&path);
+        append_bytes(&path, &[7; 10]);
+        append_bytes(&del_path(&path), &[7; 3]);
+        let (len, del_len) = (
+            path.metadata().unwrap().len(),
+            del_path(&path).metadata().unwrap().len(),
+        );
+
+        let (mut log, loaded) = VectorLog::open_as(&path, DIM, false).unwrap();
+        assert_eq!(loaded.ids, [0, 1, 2]);
+        assert_eq!(loaded.vectors[2 * DIM..], [2.0; DIM]);
+        assert_eq!(loaded.deleted, HashSet::from([1]));
+        assert_eq!(path.metadata().unwrap().len(), len);
+        assert_eq!(del_path(&path).metadata().unwrap().len(), del_len);
+
+        assert!(log.append(3, &[3.0; DIM]).is_err());
+        assert!(log.delete(&[0]).is_err());
+        log.sync().unwrap();
+        assert_eq!(path.metadata().unwrap().len(), len);
+    }
+
+    #[test]
+    fn read_only_open_of_a_missing_log_creates_nothing() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("v.log");
+        let (_, loaded) = VectorLog::open_as(&path, DIM, false).unwrap();
+        assert!(loaded.ids.is_empty() && loaded.deleted.is_empty());
+        assert!(!path.exists());
+        assert!(!del_path(&path).exists());
+    }
+}
@@ -0,0 +1,421 @@
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use tracing::{info, warn};
+
+use crate::handlers::{AppState, Paths};
+use crate::index::{IndexConfig, VectorIndex};
+use crate::embedder::{self, Embed};
+use crate::rebuild::embed_into;
+use crate::review_store::ReviewStore;
+use crate::storage::{
+    append_review_line, append_tombstone_line, append_vector_map_lines, deletions_path,
+    load_all_reviews, load_deleted_vector_ids, load_jsonl_checked, load_reviews_checked,
+    load_vector_map, recover_data_files, rewrite_vector_map,
+};
+use crate::types::{RepairReport, StoredReview, Tombstone, VectorMapEntry, VerifyReport};
+
+/// ตรวจความสอดคล้องของ segment ของ reviews / vector_map.jsonl / deletions / index
+pub fn verify(
+    paths: &Paths,
+    index: &dyn VectorIndex,
+    deleted: &HashSet<usize>,
+) -> Result<VerifyReport> {
+    let (rows, bad_rows) = load_reviews_checked::<StoredReview>(&paths.jsonl_path)?;
+    let (map, bad_map) = load_jsonl_checked::<VectorMapEntry>(&paths.map_path)?;
+    let del_path = deletions_path(&paths.jsonl_path);
+    let (_, bad_del) = load_jsonl_checked::<Tombstone>(&del_path)?;
+    let index_ids = index.ids()?;
+
+    let mut report = check(&rows, &map, deleted, &index_ids);
+    report.malformed_lines = bad_rows;
+    for (path, lines) in [
+        (&paths.map_path, bad_map),
+        (&del_path, bad_del),
+    ] {
+        report
+            .malformed_lines
+            .extend(lines.into_iter().map(|n| format!("{path}:{n}")));
+    }
+    report.consistent = is_consistent(&report);
+    Ok(report)
+}
+
+/// vector_id ถัดไปที่ปลอดภัย: มากกว่าทุก id ที่เคยใช้ในไฟล์ใด ๆ หรือใน index
+/// (แทนการนับบรรทัดของ vector_map.jsonl ซึ่งผิดเมื่อเขียนไม่ครบ)
+pub fn next_vector_id(paths: &Paths, index: &dyn VectorIndex) -> Result<usize> {
+    let deleted = load_deleted_vector_ids(&paths.jsonl_path)?;
+    Ok(verify(paths, index, &deleted)?.next_vector_id)
+}
+
+fn check(
+    rows: &[StoredReview],
+    map: &[VectorMapEntry],
+    deleted: &HashSet<usize>,
+    index_ids: &[i64],
+) -> VerifyReport {
+    // vector_id (ทุก chunk) -> review id ของแถวล่าสุดที่ใช้ vector นั้น
+    let mut row_owner: HashMap<usize, &str> = HashMap::new();
+    // review id -> แถวล่าสุด (current row)
+    let mut current: HashMap<&str, &StoredReview> = HashMap::new();
+    for r in rows {
+        for vid in r.vector_ids() {
+            row_owner.insert(vid, &r.id);
+        }
+        current.insert(&r.id, r);
+    }
+
+    let mut claims: BTreeMap<usize, usize> = BTreeMap::new();
+    for r in current.values().filter(|r| !deleted.contains(&r.vector_id)) {
+        for vid in r.vector_ids() {
+            *claims.entry(vid).or_default() += 1;
+        }
+    }
+    let live_vids: BTreeSet<usize> = claims.keys().copied().collect();
+
+    let mut map_count: BTreeMap<usize, usize> = BTreeMap::new();
+    let mut map_mismatch: BTreeSet<usize> = BTreeSet::new();
+    for e in map {
+        *map_count.entry(e.vector_id).or_default() += 1;
+        if row_owner.get(&e.vector_id).is_some_and(|owner| *owner != e.review_id) {
+            map_mismatch.insert(e.vector_id);
+        }
+    }
+
+    let index_set: BTreeSet<usize> = index_ids
+        .iter()
+        .filter_map(|&id| usize::try_from(id).ok())
+        .collect();
+
+    let mut sorted_row_vids: Vec<usize> = row_owner.keys().copied().collect();
+    sorted_row_vids.sort_unstable();
+
+    let mut report = VerifyReport {
+        review_rows: rows.len(),
+        live_reviews: current
+            .values()
+            .filter(|r| !deleted.contains(&r.vector_id))
+            .count(),
+        map_lines: map.len(),
+        index_vectors: index_ids.len(),
+        missing_from_map: sorted_row_vids
+            .iter()
+            .copied()
+            .filter(|v| !map_count.contains_key(v))
+            .collect(),
+        orphaned_in_map: map_count
+            .keys()
+            .copied()
+            .filter(|v| !row_owner.contains_key(v))
+            .collect(),
+        duplicate_in_map: map_count
+            .iter()
+            .filter(|(v, &n)| n > 1 || map_mismatch.contains(v))
+            .map(|(&v, _)| v)
+            .collect(),
+        conflicting_vector_ids: claims
+            .iter()
+            .filter(|(_, &n)| n > 1)
+            .map(|(&v, _)| v)
+            .collect(),
+        stale_vectors: sorted_row_vids
+            .iter()
+            .copied()
+            .filter(|v| !deleted.contains(v) && !live_vids.contains(v))
+            .collect(),
+        missing_from_index: live_vids.difference(&index_set).copied().collect(),
+        orphaned_in_index: index_set.difference(&live_vids).copied().collect(),
+        malformed_lines: Vec::new(),
+        next_vector_id: 0,
+        counter_behind: None,
+        consistent: false,
+    };
+
+    report.next_vector_id = row_owner
+        .keys()
+        .chain(map_count.keys())
+        .chain(deleted.iter())
+        .chain(index_set.iter())
+        .max()
+        .map_or(0, |m| m + 1);
+    report.consistent = is_consistent(&report);
+    report
+}
+
+fn is_consistent(r: &VerifyReport) -> bool {
+    r.missing_from_map.is_empty()
+        && r.orphaned_in_map.is_empty()
+        && r.duplicate_in_map.is_empty()
+        && r.conflicting_vector_ids.is_empty()
+        && r.stale_vectors.is_empty()
+        && r.missing_from_index.is_empty()
+        && r.orphaned_in_index.is_empty()
+        && r.malformed_lines.is_empty()
+        && r.counter_behind.is_none()
+}
+
+/// ซ่อมให้ไฟล์ทั้งสามกับ index ตรงกัน (ต้องไม่มีใครเขียนอยู่ระหว่างนี้)
+///
+/// - vector_id ที่หลายรีวิวใช้ร่วมกัน: ทุกรีวิวได้ vector_id ใหม่ (append แถวใหม่) แล้ว id เดิมถูก tombstone
+/// - vector ของแถวเก่าที่ไม่ได้ tombstone และ id ใน index ที่ไม่มีเจ้าของ: tombstone + ลบจาก index
+/// - vector_map.jsonl: เขียนใหม่จาก reviews.jsonl ถ้ามีบรรทัดขาด/เกิน/ซ้ำ
+/// - รีวิวที่ live แต่ไม่อยู่ใน index: embed แล้วเพิ่ม
+pub fn repair(
+    paths: &Paths,
+    embedder: &dyn Embed,
+    index: &mut dyn VectorIndex,
+    deleted: &mut HashSet<usize>,
+    next_id: &mut usize,
+) -> Result<RepairReport> {
+    let mut before = verify(paths, index, deleted)?;
+    if *next_id < before.next_vector_id {
+        before.counter_behind = Some(*next_id);
+        before.consistent = false;
+    }
+    let mut out = RepairReport {
+        before: before.clone(),
+        ..Default::default()
+    };
+    if before.consistent {
+        out.after = before;
+        return Ok(out);
+    }
+    let mut next = (*next_id).max(before.next_vector_id);
+
+    let rows = load_all_reviews(&paths.jsonl_path)?;
+    let mut current: HashMap<&str, &StoredReview> = HashMap::new();
+    let mut row_owner: HashMap<usize, &str> = HashMap::new();
+    for r in &rows {
+        current.insert(&r.id, r);
+        for vid in r.vector_ids() {
+            row_owner.insert(vid, &r.id);
+        }
+    }
+    let map_owner: HashMap<usize, String> = load_vector_map(&paths.map_path)?
+        .into_iter()
+        .map(|e| (e.vector_id, e.review_id))
+        .collect();
+
+    let mut tombstones: BTreeMap<usize, Tombstone> = BTreeMap::new();
+    let mut tombstone = |review_id: &str, vector_id: usize, superseded_by: Option<usize>| {
+        tombstones.entry(vector_id).or_insert_with(|| Tombstone {
+            review_id: review_id.to_string(),
+            vector_id,
+            deleted_at: Utc::now(),
+            superseded_by,
+        });
+    };
+
+    // 1) vector_id ชนกัน: ให้ทุกรีวิวที่ใช้ id นั้นได้ id ใหม่ (ทุก chunk ของแถว)
+    let mut to_embed: BTreeMap<usize, String> = BTreeMap::new();
+    let mut moved_ids: HashSet<&str> = HashSet::new();
+    for &vid in &before.conflicting_vector_ids {
+        let mut claimants: Vec<&StoredReview> = current
+            .values()
+            .copied()
+            .filter(|r| r.vector_ids().contains(&vid) && !moved_ids.contains(r.id.as_str()))
+            .collect();
+        claimants.sort_by(|a, b| a.id.cmp(&b.id));
+        for r in claimants {
+            let moved =
+                StoredReview::revision_of(r, r.to_input(), next).with_chunks(r.chunks.clone());
+            next += moved.vector_count();
+            append_review_line(&paths.jsonl_path, &moved)?;
+            append_vector_map_lines(&paths.map_path, &moved)?;
+            warn!("repair: review {} moved from vector {vid} to {}", r.id, moved.vector_id);
+            to_embed.extend(moved.vector_texts());
+            // id อื่นของแถวเดิมจะไม่มีเจ้าของแล้ว
+            for old in r.vector_ids().filter(|&v| v != vid) {
+                tombstone(&r.id, old, Some(moved.vector_id));
+            }
+            moved_ids.insert(&r.id);
+            out.reassigned += 1;
+        }
+        tombstone(row_owner.get(&vid).copied().unwrap_or_default(), vid, None);
+    }
+
+    // 2) vector ของแถวเก่าที่ค้างอยู่ (crash ระหว่าง update)
+    for &vid in &before.stale_vectors {
+        let owner = row_owner.get(&vid).copied().unwrap_or_default();
+        let superseded_by = current
+            .get(owner)
+            .map(|r| r.vector_id)
+            .filter(|v| !deleted.contains(v));
+        tombstone(owner, vid, superseded_by);
+    }
+
+    // 3) id ใน index ที่ไม่ใช่ vector ของรีวิวที่ live: ลบออก และ tombstone
+    //    (กันไม่ให้ id นี้ถูกใช้ซ้ำ — engine จำ tombstone ของ index ไว้ตาม id)
+    for &vid in &before.orphaned_in_index {
+        if !deleted.contains(&vid) {
+            let owner = row_owner
+                .get(&vid)
+                .map(|s| s.to_string())
+                .or_else(|| map_owner.get(&vid).cloned())
+                .unwrap_or_default();
+            tombstone(&owner, vid, None);
+        }
+    }
+
+    let del_path = deletions_path(&paths.jsonl_path);
+    for t in tombstones.values() {
+        append_tombstone_line(&del_path, t)?;
+        deleted.insert(t.vector_id);
+    }
+    out.tombstoned = tombstones.len();
+
+    let in_index: HashSet<usize> = index
+        .ids()?
+        .into_iter()
+        .filter_map(|id| usize::try_from(id).ok())
+        .collect();
+    let remove: Vec<i64> = tombstones
+        .keys()
+        .chain(&before.orphaned_in_index)
+        .filter(|v| in_index.contains(v))
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .map(|&v| v as i64)
+        .collect();
+    index.delete(&remove)?;
+    out.removed_from_index = remove.len();
+
+    // 4) vector_map.jsonl
+    if !before.missing_from_map.is_empty()
+        || !before.orphaned_in_map.is_empty()
+        || !before.duplicate_in_map.is_empty()
+    {
+        let rows = load_all_reviews(&paths.jsonl_path)?;
+        let entries: Vec<VectorMapEntry> = rows
+            .iter()
+            .flat_map(|r| {
+                r.vector_ids()
+                    .enumerate()
+                    .map(|(chunk, vid)| (vid, (r.id.clone(), chunk)))
+            })
+            .collect::<BTreeMap<_, _>>()
+            .into_iter()
+            .map(|(vector_id, (review_id, chunk))| VectorMapEntry {
+                vector_id,
+                review_id,
+                chunk,
+            })
+            .collect();
+        rewrite_vector_map(&paths.map_path, &entries)?;
+        out.map_rewritten = true;
+    }
+
+    // 5) vector ที่ live แต่ไม่มีใน index (เฉพาะ chunk ที่ขาด) + แถวที่ได้ id ใหม่จากข้อ 1
+    let conflicting: HashSet<usize> = before.conflicting_vector_ids.iter().copied().collect();
+    for &vid in &before.missing_from_index {
+        if conflicting.contains(&vid) {
+            continue;
+        }
+        if let Some(r) = rows.iter().rev().find(|r| r.vector_ids().contains(&vid)) {
+            to_embed.extend(r.vector_texts().into_iter().filter(|&(v, _)| v == vid));
+        }
+    }
+    let to_embed: Vec<(usize, String)> = to_embed.into_iter().collect();
+    embed_into(embedder, index, &to_embed, |done, total| info!("repair: embedded {done}/{total}"))?;
+    out.added_to_index = to_embed.len();
+    index.save()?;
+
+    *next_id = next;
+    out.after = verify(paths, index, deleted)?;
+    Ok(out)
+}
+
+/// GET /admin/verify: หยุดการเขียนชั่วคราวเพื่อให้ได้ภาพที่ตรงกัน
+pub fn verify_live(state: &AppState) -> Result<VerifyReport> {
+    let _quiesce = state
+        .write_gate
+        .write()
+        .map_err(|_| anyhow!("write gate poisoned"))?;
+    let paths = state
+        .paths
+        .read()
+        .map_err(|_| anyhow!("paths lock poisoned"))?
+        .clone();
+    let idx = state
+        .index
+        .read()
+        .map_err(|_| anyhow!("index lock poisoned"))?;
+    let deleted = state
+        .deleted
+        .read()
+        .map_err(|_| anyhow!("deleted lock poisoned"))?;
+    let mut report = verify(&paths, &**idx, &deleted)?;
+
+    let counter = *state
+        .next_vector_id
+        .read()
+        .map_err(|_| anyhow!("id lock poisoned"))?;
+    if counter < report.next_vector_id {
+        report.counter_behind = Some(counter);
+        report.consistent = false;
+    }
+    Ok(report)
+}
+
+/// POST /admin/repair
+pub fn repair_live(state: &AppState) -> Result<RepairReport> {
+    let _quiesce = state
+        .write_gate
+        .write()
+        .map_err(|_| anyhow!("write gate poisoned"))?;
+    let paths = state
+        .paths
+        .read()
+        .map_err(|_| anyhow!("paths lock poisoned"))?
+        .clone();
+    let mut idx = state
+        .index
+        .write()
+        .map_err(|_| anyhow!("index lock poisoned"))?;
+    let mut deleted = state
+        .deleted
+        .write()
+        .map_err(|_| anyhow!("deleted lock poisoned"))?;
+    let mut next_id = state
+        .next_vector_id
+        .write()
+        .map_err(|_| anyhow!("id lock poisoned"))?;
+
+    let report = repair(&paths, &*state.embedder, &mut **idx, &mut deleted, &mut next_id)?;
+    if let Ok(mut unsaved) = state.unsaved_vectors.write() {
+        *unsaved = 0;
+    }
+    // repair อาจ append แถวใหม่ลง reviews.jsonl
+    if report.reassigned > 0 {
+        *state
+            .reviews
+            .write()
+            .map_err(|_| anyhow!("reviews lock poisoned"))? = ReviewStore::load(&paths.jsonl_path)?;
+    }
+    Ok(report)
+}
+
+/// `backend verify` / `backend repair` (server ต้องไม่รันอยู่)
+pub fn run_cli(paths: &Paths, repair_mode: bool) -> Result<()> {
+    let cfg = IndexConfig::from_env()?;
+    let mut index = cfg.open(&paths.index_path)?;
+    let mut deleted = load_deleted_vector_ids(&paths.jsonl_path)?;
+
+    let consistent = if repair_mode {
+        recover_data_files(&paths.jsonl_path, &paths.map_path)?;
+        let mut next_id = next_vector_id(paths, &*index)?;
+        let embedder = embedder::from_env()?;
+        let report = repair(paths, &*embedder, &mut *index, &mut deleted, &mut next_id)?;
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        report.after.consistent
+    } else {
+        let report = verify(paths, &*index, &deleted)?;
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        report.consistent
+    };
+
+    if !consistent {
+        return Err(anyhow!("data directory is not consistent"));
+    }
+    Ok(())
+}
@@ -0,0 +1,245 @@
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+use crate::handlers::Paths;
+use crate::index::VectorIndex;
+use crate::schema;
+use crate::storage::{
+    append_review_line, append_vector_map_lines, load_all_reviews, load_vector_map, sync_appended,
+};
+use crate::types::StoredReview;
+
+/// หนึ่งบรรทัดของ `wal.jsonl`: ทุกอย่างที่ insert หนึ่งครั้ง (หรือ bulk หนึ่ง batch) ต้องเขียน
+/// `vectors` เรียงตาม `vector_ids()` ของแต่ละแถวต่อกัน (แถวที่ไม่แบ่ง chunk = หนึ่งเวกเตอร์)
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WalRecord {
+    pub reviews: Vec<StoredReview>,
+    pub vectors: Vec<Vec<f32>>,
+}
+
+/// บรรทัด `{"abort": <vector_id>}`: record ที่มี vector_id แรกนี้ apply ไม่สำเร็จ
+/// (client ได้ error และส่วนที่เขียนไปแล้วถูกย้อน) — `open` ทิ้ง record นั้น ไม่ replay
+#[derive(Debug, Serialize, Deserialize)]
+struct AbortMarker {
+    abort: usize,
+}
+
+/// record อาจถูกเขียนโดย build ก่อนหน้า: อัปเกรดแถวรีวิวตาม schema ก่อน deserialize
+fn decode_record(body: &[u8]) -> Result<WalRecord> {
+    let mut value: serde_json::Value = serde_json::from_slice(body)?;
+    if let Some(rows) = value.get_mut("reviews").and_then(|r| r.as_array_mut()) {
+        for row in rows {
+            schema::upgrade(row)?;
+        }
+    }
+    Ok(serde_json::from_value(value)?)
+}
+
+/// Write-ahead log ของการ insert: record ถูก fsync ก่อนแตะ index / reviews.jsonl /
+/// vector_map.jsonl และถูกล้างทิ้ง (checkpoint) หลัง index save เมื่อไม่มี record ค้าง
+pub struct Wal {
+    path: PathBuf,
+    /// `None` = read-only (`READ_ONLY=1`): ไม่ replay และไม่เขียน
+    file: Option<File>,
+    /// record ที่เขียนลง WAL แล้วแต่ยังเขียนไฟล์อื่นไม่ครบ
+    pending: usize,
+}
+
+impl Wal {
+    /// เปิด (หรือสร้าง) WAL และคืน record ที่ค้างอยู่ทั้งหมด
+    /// (บรรทัดสุดท้ายที่เขียนไม่ครบ = insert ที่ยังไม่ได้ตอบ client ถูกตัดทิ้ง;
+    /// record ที่มี abort marker ตามมาก็ถูกข้าม)
+    pub fn open(path: &Path) -> Result<(Self, Vec<WalRecord>)> {
+        let mut records = Vec::new();
+        let mut aborted = HashSet::new();
+        let mut good_len: u64 = 0;
+        if path.exists() {
+            let mut reader = BufReader::new(File::open(path)?);
+            let mut line = Vec::new();
+            loop {
+                line.clear();
+                let n = reader.read_until(b'\n', &mut line)?;
+                if n == 0 {
+                    break;
+                }
+                // record ที่ไม่มี '\n' ปิดท้าย = เขียนไม่จบ (ยังไม่ได้ fsync / ตอบ client)
+                let body = match line.strip_suffix(b"\n") {
+                    Some(body) => body,
+                    None => {
+                        warn!("{}: dropping torn record (no trailing newline)", path.display());
+                        break;
+                    }
+                };
+                if let Ok(marker) = serde_json::from_slice::<AbortMarker>(body) {
+                    aborted.insert(marker.abort);
+                    good_len += n as u64;
+                    continue;
+                }
+                match decode_record(body) {
+                    Ok(rec) => {
+                        records.push(rec);
+                        good_len += n as u64;
+                    }
+                    Err(e) => {
+                        warn!("{}: dropping torn record ({e})", path.display());
+                        break;
+                    }
+                }
+            }
+        }
+
+        records.retain(|rec| {
+            rec.reviews
+                .first()
+                .is_none_or(|r| !aborted.contains(&r.vector_id))
+        });
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("open {}", path.display()))?;
+        if file.metadata()?.len() > good_len {
+            file.set_len(good_len)?;
+        }
+        Ok((
+            Self {
+                path: path.to_path_buf(),
+                file: Some(file),
+                pending: 0,
+            },
+            records,
+        ))
+    }
+
+    /// WAL ของชุดข้อมูลที่เปิดแบบ read-only: ไม่แตะไฟล์ (record ที่ค้างจะไม่ถูก replay)
+    pub fn detached(path: &Path) -> Self {
+        if std::fs::metadata(path).is_ok_and(|m| m.len() > 0) {
+            warn!(
+                "{}: has pending records; not replayed in read-only mode",
+                path.display()
+            );
+        }
+        Self {
+            path: path.to_path_buf(),
+            file: None,
+            pending: 0,
+        }
+    }
+
+    /// เขียน record + fsync; ต้องเรียก `applied()` เมื่อเขียนไฟล์อื่นครบแล้ว
+    pub fn append(&mut self, record: &WalRecord) -> Result<()> {
+        let Some(file) = &mut self.file else {
+            bail!("{}: read-only", self.path.display());
+        };
+        let line = serde_json::to_string(record)? + "\n";
+        file.write_all(line.as_bytes())?;
+        file.sync_data()?;
+        self.pending += 1;
+        Ok(())
+    }
+
+    pub fn applied(&mut self) {
+        self.pending = self.pending.saturating_sub(1);
+    }
+
+    /// record ที่ `append` แล้ว apply ไม่สำเร็จ: เขียน abort marker (fsync) ให้ replay ข้าม
+    /// แล้วนับว่า settle แล้ว (แม้เขียน marker ไม่ได้ — ไม่งั้น WAL จะไม่ถูก checkpoint อีกเลย)
+    pub fn abort(&mut self, record: &WalRecord) -> Result<()> {
+        self.pending = self.pending.saturating_sub(1);
+        let Some(first) = record.reviews.first() else {
+            return Ok(());
+        };
+        let Some(file) = &mut self.file else {
+            bail!("{}: read-only", self.path.display());
+        };
+        let line = serde_json::to_string(&AbortMarker {
+            abort: first.vector_id,
+        })? + "\n";
+        file.write_all(line.as_bytes())?;
+        file.sync_data()?;
+        Ok(())
+    }
+
+    /// เรียกหลัง index save: ถ้าไม่มี record ค้าง ทุก record อยู่ในไฟล์ปลายทางครบแล้ว จึงล้าง WAL ได้
+    /// — หลัง fsync segment / sidecar / vector_map ที่ append ไป (WAL คือสำเนาเดียวของแถวเหล่านั้น
+    /// จนกว่าจะถึงดิสก์)
+    pub fn checkpoint(&mut self) -> Result<()> {
+        let Some(file) = &self.file else {
+            return Ok(());
+        };
+        if self.pending > 0 {
+            return Ok(());
+        }
+        sync_appended()?;
+        file.set_len(0)?;
+        file.sync_data()?;
+        Ok(())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// ทำ record ที่ค้างใน WAL ให้ครบ: เพิ่มเวกเตอร์ที่ index ยังไม่มี และแถว/map ที่ยังไม่ถูกเขียน
+/// (idempotent — ส่วนที่เขียนไปแล้วถูกข้าม) จากนั้น save index แล้วล้าง WAL
+pub fn replay(
+    wal: &mut Wal,
+    records: Vec<WalRecord>,
+    paths: &Paths,
+    index: &mut dyn VectorIndex,
+    deleted: &HashSet<usize>,
+) -> Result<usize> {
+    if records.is_empty() {
+        return Ok(0);
+    }
+    let mut in_rows: HashSet<usize> = load_all_reviews(&paths.jsonl_path)?
+        .iter()
+        .map(|r| r.vector_id)
+        .collect();
+    let mut in_map: HashSet<usize> = load_vector_map(&paths.map_path)?
+        .iter()
+        .map(|e| e.vector_id)
+        .collect();
+    let mut in_index: HashSet<i64> = index.ids()?.into_iter().collect();
+
+    let mut repaired = 0;
+    for rec in &records {
+        let mut vectors = rec.vectors.iter();
+        for review in &rec.reviews {
+            let mut touched = false;
+            for (vid, vector) in review.vector_ids().zip(vectors.by_ref()) {
+                if !deleted.contains(&vid) && in_index.insert(vid as i64) {
+                    index.add_batch(vector, Some(&[vid as i64]))?;
+                    touched = true;
+                }
+            }
+            let vid = review.vector_id;
+            if in_rows.insert(vid) {
+                append_review_line(&paths.jsonl_path, review)?;
+                touched = true;
+            }
+            if in_map.insert(vid) {
+                append_vector_map_lines(&paths.map_path, review)?;
+                touched = true;
+            }
+            repaired += usize::from(touched);
+        }
+    }
+
+    index.save()?;
+    wal.checkpoint()?;
+    info!(
+        "{}: replayed {} record(s), {} review(s) needed repair",
+        wal.path().display(),
+        records.len(),
+        repaired
+    );
+    Ok(repaired)
+}